@@ -0,0 +1,331 @@
+pub mod clint;
+pub mod plic;
+pub mod ram;
+pub mod uart;
+
+use std::fmt;
+
+pub use crate::bus::clint::Clint;
+pub use crate::bus::plic::{InterruptLine, Plic};
+pub use crate::bus::ram::Ram;
+pub use crate::bus::uart::Uart;
+
+/// Faults raised by a bus access
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BusFault {
+    /// No device is mapped at the address
+    Unmapped(u64),
+    /// A device is mapped at the address but refused the access
+    Denied(u64),
+}
+
+/// A memory-mapped target that can service byte, half, word and double
+/// accesses
+///
+/// Addresses are relative to wherever the target is mapped. Multi-byte
+/// accesses are little-endian; the default implementations compose them from
+/// single-byte accesses, so simple devices only need `read8` and `write8`.
+pub trait Bus {
+    fn read8(&mut self, addr: u64) -> Result<u8, BusFault>;
+    fn write8(&mut self, addr: u64, value: u8) -> Result<(), BusFault>;
+
+    fn read16(&mut self, addr: u64) -> Result<u16, BusFault> {
+        Ok(read_bytes(self, addr, 2)? as u16)
+    }
+
+    fn read32(&mut self, addr: u64) -> Result<u32, BusFault> {
+        Ok(read_bytes(self, addr, 4)? as u32)
+    }
+
+    fn read64(&mut self, addr: u64) -> Result<u64, BusFault> {
+        read_bytes(self, addr, 8)
+    }
+
+    fn write16(&mut self, addr: u64, value: u16) -> Result<(), BusFault> {
+        write_bytes(self, addr, 2, value as u64)
+    }
+
+    fn write32(&mut self, addr: u64, value: u32) -> Result<(), BusFault> {
+        write_bytes(self, addr, 4, value as u64)
+    }
+
+    fn write64(&mut self, addr: u64, value: u64) -> Result<(), BusFault> {
+        write_bytes(self, addr, 8, value)
+    }
+
+    /// Copy `data` to consecutive addresses starting at `addr`
+    fn load(&mut self, addr: u64, data: &[u8]) -> Result<(), BusFault> {
+        for (i, byte) in data.iter().enumerate() {
+            self.write8(addr.wrapping_add(i as u64), *byte)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_bytes<B: Bus + ?Sized>(bus: &mut B, addr: u64, size: u64) -> Result<u64, BusFault> {
+    let mut value: u64 = 0;
+    for i in (0..size).rev() {
+        value = (value << 8) | bus.read8(addr.wrapping_add(i))? as u64;
+    }
+    Ok(value)
+}
+
+fn write_bytes<B: Bus + ?Sized>(
+    bus: &mut B,
+    addr: u64,
+    size: u64,
+    value: u64,
+) -> Result<(), BusFault> {
+    for i in 0..size {
+        bus.write8(addr.wrapping_add(i), (value >> (8 * i)) as u8)?;
+    }
+    Ok(())
+}
+
+/// A device that drives interrupt-pending bits of a hart's mip
+///
+/// Connected devices are polled by the hart before every instruction.
+pub trait InterruptSource {
+    /// Advance the device by one instruction of the hart
+    fn tick(&mut self) {}
+
+    /// mip bits the device currently asserts for hart `hart`
+    fn pending(&mut self, hart: u64) -> u64;
+
+    /// Real-time counter the device keeps, if any, which the hart reads
+    /// through the time CSR
+    fn time(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A device mapped into an `AddressMap`
+struct Region {
+    base: u64,
+    size: u64,
+    device: Box<dyn Bus>,
+}
+
+impl Region {
+    /// Offset of `addr` into the region if an access of `len` bytes fits
+    fn offset(&self, addr: u64, len: u64) -> Option<u64> {
+        let offset = addr.checked_sub(self.base)?;
+        if offset.checked_add(len)? <= self.size {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Address map routing physical address ranges to devices
+///
+/// Each device sees addresses relative to the base it was mapped at.
+/// Accesses that do not fall entirely inside one mapped range fault with
+/// `BusFault::Unmapped`.
+#[derive(Default)]
+pub struct AddressMap {
+    regions: Vec<Region>,
+}
+
+impl AddressMap {
+    pub fn new() -> AddressMap {
+        AddressMap {
+            regions: Vec::new(),
+        }
+    }
+
+    /// Map `device` at `[base, base + size)`
+    ///
+    /// Panics if the range is empty, wraps around, or overlaps an existing
+    /// mapping, as those are configuration errors.
+    pub fn map(&mut self, base: u64, size: u64, device: Box<dyn Bus>) {
+        let end = base
+            .checked_add(size)
+            .filter(|_| size > 0)
+            .unwrap_or_else(|| panic!("invalid mapping at {:#x} of size {:#x}", base, size));
+        if let Some(other) = self
+            .regions
+            .iter()
+            .find(|r| base < r.base + r.size && r.base < end)
+        {
+            panic!(
+                "mapping [{:#x}, {:#x}) overlaps [{:#x}, {:#x})",
+                base,
+                end,
+                other.base,
+                other.base + other.size
+            );
+        }
+        self.regions.push(Region { base, size, device });
+    }
+
+    /// Map `size` bytes of zeroed RAM at `base`
+    pub fn map_ram(&mut self, base: u64, size: u64) {
+        self.map(base, size, Box::new(Ram::new(size as usize)));
+    }
+
+    /// Whether an access of `len` bytes at `addr` falls inside one mapping
+    pub fn is_mapped(&self, addr: u64, len: u64) -> bool {
+        self.regions.iter().any(|r| r.offset(addr, len).is_some())
+    }
+
+    /// The `(base, size)` of every mapped range, in mapping order
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.regions.iter().map(|r| (r.base, r.size))
+    }
+
+    fn route(&mut self, addr: u64, len: u64) -> Result<(&mut (dyn Bus + 'static), u64), BusFault> {
+        self.regions
+            .iter_mut()
+            .find_map(|r| {
+                r.offset(addr, len)
+                    .map(|offset| (r.device.as_mut(), offset))
+            })
+            .ok_or(BusFault::Unmapped(addr))
+    }
+}
+
+impl Bus for AddressMap {
+    fn read8(&mut self, addr: u64) -> Result<u8, BusFault> {
+        let (device, offset) = self.route(addr, 1)?;
+        device.read8(offset).map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn write8(&mut self, addr: u64, value: u8) -> Result<(), BusFault> {
+        let (device, offset) = self.route(addr, 1)?;
+        device
+            .write8(offset, value)
+            .map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn read16(&mut self, addr: u64) -> Result<u16, BusFault> {
+        let (device, offset) = self.route(addr, 2)?;
+        device.read16(offset).map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn read32(&mut self, addr: u64) -> Result<u32, BusFault> {
+        let (device, offset) = self.route(addr, 4)?;
+        device.read32(offset).map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn read64(&mut self, addr: u64) -> Result<u64, BusFault> {
+        let (device, offset) = self.route(addr, 8)?;
+        device.read64(offset).map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn write16(&mut self, addr: u64, value: u16) -> Result<(), BusFault> {
+        let (device, offset) = self.route(addr, 2)?;
+        device
+            .write16(offset, value)
+            .map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn write32(&mut self, addr: u64, value: u32) -> Result<(), BusFault> {
+        let (device, offset) = self.route(addr, 4)?;
+        device
+            .write32(offset, value)
+            .map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn write64(&mut self, addr: u64, value: u64) -> Result<(), BusFault> {
+        let (device, offset) = self.route(addr, 8)?;
+        device
+            .write64(offset, value)
+            .map_err(|_| BusFault::Denied(addr))
+    }
+
+    fn load(&mut self, addr: u64, data: &[u8]) -> Result<(), BusFault> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let (device, offset) = self.route(addr, data.len() as u64)?;
+        device
+            .load(offset, data)
+            .map_err(|_| BusFault::Denied(addr))
+    }
+}
+
+impl fmt::Debug for AddressMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.ranges()
+                    .map(|(base, size)| format!("{:#x}..{:#x}", base, base + size)),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::bus::*;
+
+    /// Device that records the last write and refuses reads
+    struct WriteOnly {
+        last: Rc<Cell<Option<(u64, u8)>>>,
+    }
+
+    impl Bus for WriteOnly {
+        fn read8(&mut self, addr: u64) -> Result<u8, BusFault> {
+            Err(BusFault::Denied(addr))
+        }
+
+        fn write8(&mut self, addr: u64, value: u8) -> Result<(), BusFault> {
+            self.last.set(Some((addr, value)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_routes_to_ram() {
+        let mut map = AddressMap::new();
+        map.map_ram(0x8000_0000, 0x1000);
+        map.write64(0x8000_0ff8, 0x0102_0304_0506_0708).unwrap();
+        assert_eq!(map.read64(0x8000_0ff8), Ok(0x0102_0304_0506_0708));
+        assert_eq!(map.read32(0x8000_0ffc), Ok(0x0102_0304));
+        assert_eq!(map.read16(0x8000_0ff8), Ok(0x0708));
+        assert_eq!(map.read8(0x8000_0fff), Ok(0x01));
+    }
+
+    #[test]
+    fn test_unmapped_faults() {
+        let mut map = AddressMap::new();
+        map.map_ram(0x1000, 0x1000);
+        assert_eq!(map.read8(0xfff), Err(BusFault::Unmapped(0xfff)));
+        assert_eq!(map.write8(0x2000, 0), Err(BusFault::Unmapped(0x2000)));
+        // an access straddling the end of the region is not partially applied
+        assert_eq!(
+            map.write32(0x1ffe, u32::MAX),
+            Err(BusFault::Unmapped(0x1ffe))
+        );
+        assert_eq!(map.read16(0x1ffe), Ok(0));
+    }
+
+    #[test]
+    fn test_mmio_sees_offsets() {
+        let last = Rc::new(Cell::new(None));
+        let mut map = AddressMap::new();
+        map.map(
+            0x1000_0000,
+            0x100,
+            Box::new(WriteOnly { last: last.clone() }),
+        );
+        map.write8(0x1000_0005, 0x41).unwrap();
+        assert_eq!(last.get(), Some((0x5, 0x41)));
+        assert_eq!(map.read8(0x1000_0005), Err(BusFault::Denied(0x1000_0005)));
+        map.write16(0x1000_0010, 0xbeef).unwrap();
+        assert_eq!(last.get(), Some((0x11, 0xbe)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_overlapping_map_panics() {
+        let mut map = AddressMap::new();
+        map.map_ram(0x1000, 0x1000);
+        map.map_ram(0x1800, 0x1000);
+    }
+}
@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::bus::{Bus, BusFault, InterruptSource};
+use crate::cpu::csr::{MIP_MSIP, MIP_MTIP};
+
+/// Size of the CLINT register block
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+/// Base address of the CLINT on the QEMU `virt` machine
+pub const CLINT_BASE: u64 = 0x200_0000;
+
+/// Timebase frequency of the QEMU `virt` machine in Hz
+pub const DEFAULT_FREQUENCY: u64 = 10_000_000;
+
+/// Register offsets: one 32-bit msip per hart, one 64-bit mtimecmp per hart
+/// and the shared 64-bit mtime
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+/// What drives mtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timebase {
+    /// mtime advances one tick every `n` instructions, so runs are
+    /// deterministic
+    Instructions(u64),
+    /// mtime follows the host's monotonic clock at the given frequency in Hz
+    HostClock(u64),
+}
+
+struct State {
+    timebase: Timebase,
+    /// Instructions stepped, for `Timebase::Instructions`
+    steps: u64,
+    start: Instant,
+    /// mtime minus the ticks counted since reset, so that writes to mtime
+    /// take effect without disturbing the time source
+    offset: u64,
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+}
+
+impl State {
+    fn ticks(&self) -> u64 {
+        match self.timebase {
+            Timebase::Instructions(n) => self.steps / n.max(1),
+            Timebase::HostClock(hz) => {
+                (self.start.elapsed().as_nanos() * hz as u128 / 1_000_000_000) as u64
+            }
+        }
+    }
+
+    fn mtime(&self) -> u64 {
+        self.offset.wrapping_add(self.ticks())
+    }
+
+    /// Register containing `offset` as (value, offset of the register,
+    /// width in bytes)
+    fn register(&self, offset: u64) -> Option<(u64, u64, u64)> {
+        let harts = self.msip.len() as u64;
+        match offset {
+            MSIP..MTIMECMP if (offset - MSIP) / 4 < harts => {
+                let hart = (offset - MSIP) / 4;
+                Some((self.msip[hart as usize] as u64, MSIP + 4 * hart, 4))
+            }
+            MTIMECMP..MTIME if (offset - MTIMECMP) / 8 < harts => {
+                let hart = (offset - MTIMECMP) / 8;
+                Some((self.mtimecmp[hart as usize], MTIMECMP + 8 * hart, 8))
+            }
+            MTIME..CLINT_SIZE => Some((self.mtime(), MTIME, 8)),
+            _ => None,
+        }
+    }
+
+    /// Read `size` bytes at `offset`, which must lie inside one register
+    fn read(&self, offset: u64, size: u64) -> Result<u64, BusFault> {
+        let (value, base, width) = self.register(offset).ok_or(BusFault::Denied(offset))?;
+        if offset + size > base + width {
+            return Err(BusFault::Denied(offset));
+        }
+        let value = value >> (8 * (offset - base));
+        Ok(if size == 8 {
+            value
+        } else {
+            value & ((1 << (8 * size)) - 1)
+        })
+    }
+
+    /// Write the low `size` bytes of `value` at `offset`, which must lie
+    /// inside one register
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), BusFault> {
+        let (old, base, width) = self.register(offset).ok_or(BusFault::Denied(offset))?;
+        if offset + size > base + width {
+            return Err(BusFault::Denied(offset));
+        }
+        let shift = 8 * (offset - base);
+        let mask = if size == 8 {
+            u64::MAX
+        } else {
+            ((1 << (8 * size)) - 1) << shift
+        };
+        let new = (old & !mask) | ((value << shift) & mask);
+        match base {
+            MTIME => self.offset = new.wrapping_sub(self.ticks()),
+            MTIMECMP.. => self.mtimecmp[((base - MTIMECMP) / 8) as usize] = new,
+            // only bit 0 of msip is implemented
+            _ => self.msip[((base - MSIP) / 4) as usize] = new & 1 != 0,
+        }
+        Ok(())
+    }
+}
+
+/// SiFive-compatible Core-Local Interruptor: the machine timer and software
+/// interrupts of each hart
+///
+/// `Clint` is a handle to shared state: map one clone on the bus and
+/// connect another to the hart with `CPU::connect`, which ticks it and
+/// reads MTIP and MSIP from it before every instruction. MTIP is pending
+/// while mtime >= mtimecmp, and mtimecmp resets to all ones.
+#[derive(Clone)]
+pub struct Clint {
+    state: Rc<RefCell<State>>,
+}
+
+impl Clint {
+    /// CLINT for `harts` harts with mtime driven by `timebase`
+    pub fn new(harts: usize, timebase: Timebase) -> Clint {
+        Clint {
+            state: Rc::new(RefCell::new(State {
+                timebase,
+                steps: 0,
+                start: Instant::now(),
+                offset: 0,
+                msip: vec![false; harts],
+                mtimecmp: vec![u64::MAX; harts],
+            })),
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.state.borrow().mtime()
+    }
+}
+
+impl Bus for Clint {
+    fn read8(&mut self, addr: u64) -> Result<u8, BusFault> {
+        self.state.borrow().read(addr, 1).map(|v| v as u8)
+    }
+
+    fn write8(&mut self, addr: u64, value: u8) -> Result<(), BusFault> {
+        self.state.borrow_mut().write(addr, 1, value as u64)
+    }
+
+    // whole-register accesses must not tear a host-clock mtime, so none of
+    // them are composed from bytes
+
+    fn read16(&mut self, addr: u64) -> Result<u16, BusFault> {
+        self.state.borrow().read(addr, 2).map(|v| v as u16)
+    }
+
+    fn read32(&mut self, addr: u64) -> Result<u32, BusFault> {
+        self.state.borrow().read(addr, 4).map(|v| v as u32)
+    }
+
+    fn read64(&mut self, addr: u64) -> Result<u64, BusFault> {
+        self.state.borrow().read(addr, 8)
+    }
+
+    fn write16(&mut self, addr: u64, value: u16) -> Result<(), BusFault> {
+        self.state.borrow_mut().write(addr, 2, value as u64)
+    }
+
+    fn write32(&mut self, addr: u64, value: u32) -> Result<(), BusFault> {
+        self.state.borrow_mut().write(addr, 4, value as u64)
+    }
+
+    fn write64(&mut self, addr: u64, value: u64) -> Result<(), BusFault> {
+        self.state.borrow_mut().write(addr, 8, value)
+    }
+}
+
+impl InterruptSource for Clint {
+    fn tick(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.steps = state.steps.wrapping_add(1);
+    }
+
+    fn pending(&mut self, hart: u64) -> u64 {
+        let state = self.state.borrow();
+        let hart = hart as usize;
+        let msip = state.msip.get(hart).is_some_and(|msip| *msip);
+        let mtip = state
+            .mtimecmp
+            .get(hart)
+            .is_some_and(|mtimecmp| state.mtime() >= *mtimecmp);
+        (if msip { MIP_MSIP } else { 0 }) | (if mtip { MIP_MTIP } else { 0 })
+    }
+
+    fn time(&self) -> Option<u64> {
+        Some(self.mtime())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::clint::*;
+
+    #[test]
+    fn test_registers() {
+        let mut clint = Clint::new(2, Timebase::Instructions(1));
+        assert_eq!(clint.read64(MTIMECMP + 8), Ok(u64::MAX));
+        clint.write32(MSIP + 4, 0xffff_ffff).unwrap();
+        assert_eq!(clint.read32(MSIP + 4), Ok(1));
+        assert_eq!(clint.read32(MSIP), Ok(0));
+        // halves of mtimecmp, as RV32 software writes it
+        clint.write32(MTIMECMP, 0x1234).unwrap();
+        clint.write32(MTIMECMP + 4, 0).unwrap();
+        assert_eq!(clint.read64(MTIMECMP), Ok(0x1234));
+        assert_eq!(clint.read8(MTIMECMP + 1), Ok(0x12));
+
+        // reserved space and accesses straddling registers are refused
+        assert_eq!(clint.read32(MSIP + 8), Err(BusFault::Denied(MSIP + 8)));
+        assert_eq!(clint.read64(MSIP), Err(BusFault::Denied(MSIP)));
+        assert_eq!(
+            clint.read64(MTIMECMP + 16),
+            Err(BusFault::Denied(MTIMECMP + 16))
+        );
+    }
+
+    #[test]
+    fn test_instruction_timebase_and_pending() {
+        let mut clint = Clint::new(1, Timebase::Instructions(3));
+        clint.write64(MTIMECMP, 2).unwrap();
+        for _ in 0..5 {
+            clint.tick();
+        }
+        assert_eq!(clint.read64(MTIME), Ok(1));
+        assert_eq!(clint.pending(0), 0);
+        clint.tick();
+        assert_eq!(clint.mtime(), 2);
+        assert_eq!(clint.pending(0), MIP_MTIP);
+
+        // writing mtime or mtimecmp moves the deadline
+        clint.write64(MTIME, 0).unwrap();
+        assert_eq!(clint.pending(0), 0);
+        clint.write64(MTIME, 100).unwrap();
+        clint.tick();
+        assert_eq!(clint.time(), Some(100));
+        clint.write32(MSIP, 1).unwrap();
+        assert_eq!(clint.pending(0), MIP_MSIP | MIP_MTIP);
+        assert_eq!(clint.pending(1), 0);
+    }
+
+    #[test]
+    fn test_host_clock_timebase() {
+        let mut clint = Clint::new(1, Timebase::HostClock(1_000_000_000));
+        let before = clint.read64(MTIME).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let after = clint.read64(MTIME).unwrap();
+        assert!(after - before >= 2_000_000);
+        clint.write64(MTIME, 5).unwrap();
+        assert!(clint.mtime() < 1_000_000_000);
+    }
+}
@@ -0,0 +1,380 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::bus::{Bus, BusFault, InterruptSource};
+use crate::cpu::csr::{MIP_MEIP, MIP_SEIP};
+
+/// Size of the PLIC register block
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+/// Base address of the PLIC on the QEMU `virt` machine
+pub const PLIC_BASE: u64 = 0xc00_0000;
+
+/// Number of interrupt sources on the QEMU `virt` machine
+pub const DEFAULT_SOURCES: usize = 96;
+
+/// Register offsets: a 32-bit priority per source, then pending bits,
+/// enable bits per context and a threshold/claim pair per context
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+/// Priorities and thresholds are 3 bits wide
+const PRIORITY_MASK: u32 = 0b111;
+
+/// A 32-bit PLIC register
+enum Register {
+    Priority(usize),
+    /// Word of pending bits
+    Pending(usize),
+    /// Context and word of enable bits
+    Enable(usize, usize),
+    Threshold(usize),
+    Claim(usize),
+}
+
+struct State {
+    /// Per source, indexed by id; source 0 does not exist
+    priority: Vec<u32>,
+    level: Vec<bool>,
+    claimed: Vec<bool>,
+    /// Per context, one bit per source id
+    enable: Vec<Vec<u32>>,
+    threshold: Vec<u32>,
+}
+
+impl State {
+    /// Number of source ids, counting the nonexistent source 0
+    fn sources(&self) -> usize {
+        self.priority.len()
+    }
+
+    /// Whether `source` is waiting to be claimed
+    fn pending(&self, source: usize) -> bool {
+        self.level[source] && !self.claimed[source]
+    }
+
+    fn register(&self, offset: u64) -> Option<Register> {
+        let words = self.sources().div_ceil(32);
+        let contexts = self.threshold.len();
+        let register = match offset {
+            PRIORITY..PENDING => Register::Priority(((offset - PRIORITY) / 4) as usize),
+            PENDING..ENABLE => Register::Pending(((offset - PENDING) / 4) as usize),
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+                Register::Enable(context, word)
+            }
+            CONTEXT.. => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => Register::Threshold(context),
+                    4 => Register::Claim(context),
+                    _ => return None,
+                }
+            }
+        };
+        let exists = match register {
+            Register::Priority(source) => source < self.sources(),
+            Register::Pending(word) => word < words,
+            Register::Enable(context, word) => context < contexts && word < words,
+            Register::Threshold(context) | Register::Claim(context) => context < contexts,
+        };
+        exists.then_some(register)
+    }
+
+    /// Whether `source` exists and is enabled for `context`
+    fn enabled(&self, context: usize, source: usize) -> bool {
+        source < self.sources() && self.enable[context][source / 32] >> (source % 32) & 1 != 0
+    }
+
+    /// Highest-priority source that is pending, enabled for `context` and
+    /// above its threshold; ties go to the lowest id
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for source in 1..self.sources() {
+            if self.enabled(context, source)
+                && self.pending(source)
+                && self.priority[source] > self.threshold[context]
+                && best.is_none_or(|best| self.priority[source] > self.priority[best])
+            {
+                best = Some(source);
+            }
+        }
+        best
+    }
+
+    fn read(&mut self, offset: u64) -> Result<u32, BusFault> {
+        let register = self.register(offset).ok_or(BusFault::Denied(offset))?;
+        Ok(match register {
+            Register::Priority(source) => self.priority[source],
+            Register::Pending(word) => (0..32)
+                .map(|bit| 32 * word + bit)
+                .filter(|&source| source < self.sources() && self.pending(source))
+                .fold(0, |bits, source| bits | 1 << (source % 32)),
+            Register::Enable(context, word) => self.enable[context][word],
+            Register::Threshold(context) => self.threshold[context],
+            Register::Claim(context) => match self.best(context) {
+                Some(source) => {
+                    self.claimed[source] = true;
+                    source as u32
+                }
+                None => 0,
+            },
+        })
+    }
+
+    fn write(&mut self, offset: u64, value: u32) -> Result<(), BusFault> {
+        let register = self.register(offset).ok_or(BusFault::Denied(offset))?;
+        match register {
+            // source 0 does not exist and its priority is hardwired to 0
+            Register::Priority(0) => {}
+            Register::Priority(source) => self.priority[source] = value & PRIORITY_MASK,
+            // pending bits are read-only
+            Register::Pending(_) => {}
+            // bit 0, for source 0, is hardwired to 0
+            Register::Enable(context, word) => {
+                let valid = (0..32)
+                    .filter(|bit| (1..self.sources()).contains(&(32 * word + bit)))
+                    .fold(0, |mask, bit| mask | 1 << bit);
+                self.enable[context][word] = value & valid;
+            }
+            Register::Threshold(context) => self.threshold[context] = value & PRIORITY_MASK,
+            // completing a source that was not claimed, or that is not
+            // enabled for the context, has no effect
+            Register::Claim(context) => {
+                let source = value as usize;
+                if self.enabled(context, source) {
+                    self.claimed[source] = false;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Platform-Level Interrupt Controller: routes level-triggered interrupt
+/// lines from devices to the external interrupt bits of each hart
+///
+/// Context 2h drives MEIP and context 2h + 1 drives SEIP of hart h. Like
+/// `Clint`, a `Plic` is a handle to shared state: map one clone on the bus,
+/// connect another to the hart with `CPU::connect`, and hand each device
+/// an `InterruptLine` from `line`. A claimed source is not pending again
+/// until it is completed through a context that has it enabled, after
+/// which it is pending for as long as its line stays raised. Registers
+/// are 32 bits wide and other access sizes are refused.
+#[derive(Clone)]
+pub struct Plic {
+    state: Rc<RefCell<State>>,
+}
+
+impl Plic {
+    /// PLIC for `harts` harts (two contexts each) and sources 1 to `sources`
+    pub fn new(harts: usize, sources: usize) -> Plic {
+        let words = (sources + 1).div_ceil(32);
+        Plic {
+            state: Rc::new(RefCell::new(State {
+                priority: vec![0; sources + 1],
+                level: vec![false; sources + 1],
+                claimed: vec![false; sources + 1],
+                enable: vec![vec![0; words]; 2 * harts],
+                threshold: vec![0; 2 * harts],
+            })),
+        }
+    }
+
+    /// Handle through which a device drives interrupt source `source`
+    ///
+    /// Panics if the source does not exist, as that is a configuration
+    /// error.
+    pub fn line(&self, source: usize) -> InterruptLine {
+        let sources = self.state.borrow().sources();
+        if !(1..sources).contains(&source) {
+            panic!("no PLIC interrupt source {}", source);
+        }
+        InterruptLine {
+            state: self.state.clone(),
+            source,
+        }
+    }
+}
+
+impl Bus for Plic {
+    fn read8(&mut self, addr: u64) -> Result<u8, BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn write8(&mut self, addr: u64, _value: u8) -> Result<(), BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn read16(&mut self, addr: u64) -> Result<u16, BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn read32(&mut self, addr: u64) -> Result<u32, BusFault> {
+        self.state.borrow_mut().read(addr)
+    }
+
+    fn read64(&mut self, addr: u64) -> Result<u64, BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn write16(&mut self, addr: u64, _value: u16) -> Result<(), BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn write32(&mut self, addr: u64, value: u32) -> Result<(), BusFault> {
+        self.state.borrow_mut().write(addr, value)
+    }
+
+    fn write64(&mut self, addr: u64, _value: u64) -> Result<(), BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+}
+
+impl InterruptSource for Plic {
+    fn pending(&mut self, hart: u64) -> u64 {
+        let state = self.state.borrow();
+        let context = 2 * hart as usize;
+        let raised =
+            |context: usize| context < state.threshold.len() && state.best(context).is_some();
+        (if raised(context) { MIP_MEIP } else { 0 })
+            | (if raised(context + 1) { MIP_SEIP } else { 0 })
+    }
+}
+
+/// A device's interrupt line into the PLIC
+///
+/// Lines are level-triggered: a device raises its line while it needs
+/// service and lowers it once the cause is cleared.
+#[derive(Clone)]
+pub struct InterruptLine {
+    state: Rc<RefCell<State>>,
+    source: usize,
+}
+
+impl InterruptLine {
+    pub fn set(&self, level: bool) {
+        self.state.borrow_mut().level[self.source] = level;
+    }
+
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    pub fn lower(&self) {
+        self.set(false);
+    }
+
+    pub fn source(&self) -> usize {
+        self.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::plic::*;
+
+    const CLAIM: u64 = CONTEXT + 4;
+
+    #[test]
+    fn test_registers() {
+        let mut plic = Plic::new(1, 40);
+        plic.write32(PRIORITY + 4 * 5, 0xff).unwrap();
+        assert_eq!(plic.read32(PRIORITY + 4 * 5), Ok(7));
+        plic.write32(PRIORITY, 3).unwrap();
+        assert_eq!(plic.read32(PRIORITY), Ok(0));
+        // sources 0 and 41 upwards have no enable bits
+        plic.write32(ENABLE + ENABLE_STRIDE, u32::MAX).unwrap();
+        assert_eq!(plic.read32(ENABLE + ENABLE_STRIDE), Ok(!1));
+        plic.write32(ENABLE + ENABLE_STRIDE + 4, u32::MAX).unwrap();
+        assert_eq!(plic.read32(ENABLE + ENABLE_STRIDE + 4), Ok(0x1ff));
+        plic.write32(CONTEXT + CONTEXT_STRIDE, 9).unwrap();
+        assert_eq!(plic.read32(CONTEXT + CONTEXT_STRIDE), Ok(1));
+
+        plic.line(33).raise();
+        assert_eq!(plic.read32(PENDING + 4), Ok(0b10));
+        plic.write32(PENDING + 4, 0).unwrap();
+        assert_eq!(plic.read32(PENDING + 4), Ok(0b10));
+
+        // registers are 32-bit, and contexts and sources are bounded
+        assert_eq!(plic.read64(PRIORITY), Err(BusFault::Denied(PRIORITY)));
+        assert_eq!(plic.read8(CLAIM), Err(BusFault::Denied(CLAIM)));
+        let beyond = PRIORITY + 4 * 41;
+        assert_eq!(plic.read32(beyond), Err(BusFault::Denied(beyond)));
+        let beyond = CONTEXT + 2 * CONTEXT_STRIDE;
+        assert_eq!(plic.read32(beyond), Err(BusFault::Denied(beyond)));
+    }
+
+    #[test]
+    fn test_claim_and_complete() {
+        let mut plic = Plic::new(1, 8);
+        let (uart, disk) = (plic.line(3), plic.line(5));
+        plic.write32(PRIORITY + 4 * 3, 1).unwrap();
+        plic.write32(PRIORITY + 4 * 5, 2).unwrap();
+        plic.write32(ENABLE, 1 << 3 | 1 << 5).unwrap();
+        uart.raise();
+        disk.raise();
+        assert_eq!(plic.pending(0), MIP_MEIP);
+
+        // the highest priority is claimed first
+        assert_eq!(plic.read32(CLAIM), Ok(5));
+        assert_eq!(plic.read32(PENDING), Ok(1 << 3));
+        assert_eq!(plic.read32(CLAIM), Ok(3));
+        assert_eq!(plic.pending(0), 0);
+        assert_eq!(plic.read32(CLAIM), Ok(0));
+
+        // a completed source whose line is still raised is pending again
+        uart.lower();
+        plic.write32(CLAIM, 3).unwrap();
+        plic.write32(CLAIM, 5).unwrap();
+        assert_eq!(plic.read32(PENDING), Ok(1 << 5));
+        assert_eq!(plic.pending(0), MIP_MEIP);
+
+        // the threshold masks priorities at or below it
+        plic.write32(CONTEXT, 2).unwrap();
+        assert_eq!(plic.pending(0), 0);
+        assert_eq!(plic.read32(CLAIM), Ok(0));
+    }
+
+    #[test]
+    fn test_contexts() {
+        let mut plic = Plic::new(2, 8);
+        let line = plic.line(1);
+        plic.write32(PRIORITY + 4, 1).unwrap();
+        // context 3 is hart 1's S-mode
+        plic.write32(ENABLE + 3 * ENABLE_STRIDE, 1 << 1).unwrap();
+        line.raise();
+        assert_eq!(plic.pending(0), 0);
+        assert_eq!(plic.pending(1), MIP_SEIP);
+        line.lower();
+        assert_eq!(plic.pending(1), 0);
+    }
+
+    #[test]
+    fn test_complete_needs_enable() {
+        let mut plic = Plic::new(2, 8);
+        let line = plic.line(1);
+        plic.write32(PRIORITY + 4, 1).unwrap();
+        plic.write32(ENABLE, 1 << 1).unwrap();
+        line.raise();
+        assert_eq!(plic.read32(CLAIM), Ok(1));
+
+        // context 1 does not have source 1 enabled, so its completion is
+        // ignored
+        plic.write32(CLAIM + CONTEXT_STRIDE, 1).unwrap();
+        assert_eq!(plic.pending(0), 0);
+        plic.write32(CLAIM, 1).unwrap();
+        assert_eq!(plic.pending(0), MIP_MEIP);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_line_zero_panics() {
+        Plic::new(1, 8).line(0);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::bus::{Bus, BusFault};
+
+/// Zero-initialized random-access memory
+///
+/// Addresses are offsets from the start of the memory; map it into an
+/// `AddressMap` to place it at a base address.
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Ram {
+        Ram {
+            data: vec![0; size],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn slice<const N: usize>(&self, addr: u64) -> Result<[u8; N], BusFault> {
+        let start = usize::try_from(addr).map_err(|_| BusFault::Unmapped(addr))?;
+        self.data
+            .get(start..start.saturating_add(N))
+            .map(|bytes| bytes.try_into().unwrap())
+            .ok_or(BusFault::Unmapped(addr))
+    }
+
+    fn slice_mut(&mut self, addr: u64, len: usize) -> Result<&mut [u8], BusFault> {
+        let start = usize::try_from(addr).map_err(|_| BusFault::Unmapped(addr))?;
+        self.data
+            .get_mut(start..start.saturating_add(len))
+            .ok_or(BusFault::Unmapped(addr))
+    }
+}
+
+impl Bus for Ram {
+    fn read8(&mut self, addr: u64) -> Result<u8, BusFault> {
+        Ok(self.slice::<1>(addr)?[0])
+    }
+
+    fn write8(&mut self, addr: u64, value: u8) -> Result<(), BusFault> {
+        self.slice_mut(addr, 1)?[0] = value;
+        Ok(())
+    }
+
+    fn read16(&mut self, addr: u64) -> Result<u16, BusFault> {
+        Ok(u16::from_le_bytes(self.slice(addr)?))
+    }
+
+    fn read32(&mut self, addr: u64) -> Result<u32, BusFault> {
+        Ok(u32::from_le_bytes(self.slice(addr)?))
+    }
+
+    fn read64(&mut self, addr: u64) -> Result<u64, BusFault> {
+        Ok(u64::from_le_bytes(self.slice(addr)?))
+    }
+
+    fn write16(&mut self, addr: u64, value: u16) -> Result<(), BusFault> {
+        self.slice_mut(addr, 2)?
+            .copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn write32(&mut self, addr: u64, value: u32) -> Result<(), BusFault> {
+        self.slice_mut(addr, 4)?
+            .copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn write64(&mut self, addr: u64, value: u64) -> Result<(), BusFault> {
+        self.slice_mut(addr, 8)?
+            .copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn load(&mut self, addr: u64, data: &[u8]) -> Result<(), BusFault> {
+        self.slice_mut(addr, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+
+use crate::bus::plic::InterruptLine;
+use crate::bus::{Bus, BusFault, InterruptSource};
+
+/// Size of the UART register block
+pub const UART_SIZE: u64 = 0x100;
+
+/// Base address of the UART on the QEMU `virt` machine
+pub const UART_BASE: u64 = 0x1000_0000;
+
+/// PLIC source of the UART on the QEMU `virt` machine
+pub const UART_IRQ: usize = 10;
+
+/// Register offsets; 0 and 1 are DLL and DLM instead while LCR.DLAB is set
+const RBR_THR: u64 = 0;
+const IER: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RX: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_LINE: u8 = 1 << 2;
+
+/// Interrupt identifications, highest priority first
+const IIR_LINE: u8 = 0x06;
+const IIR_RX: u8 = 0x04;
+const IIR_THRE: u8 = 0x02;
+const IIR_NONE: u8 = 0x01;
+/// IIR bits 7:6 report the FIFOs as enabled
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// MSR with DCD, DSR and CTS asserted: a connected, ready terminal
+const MSR_CONNECTED: u8 = 0xb0;
+
+const FIFO_DEPTH: usize = 16;
+
+struct State {
+    output: Box<dyn Write>,
+    input: Option<Receiver<u8>>,
+    line: Option<InterruptLine>,
+    rx: VecDeque<u8>,
+    overrun: bool,
+    /// THR-empty interrupt waiting to be acknowledged
+    thre: bool,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fifo: bool,
+    divisor: [u8; 2],
+}
+
+impl State {
+    fn capacity(&self) -> usize {
+        if self.fifo {
+            FIFO_DEPTH
+        } else {
+            1
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < self.capacity() {
+            self.rx.push_back(byte);
+        } else {
+            self.overrun = true;
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOPBACK != 0 {
+            self.receive(byte);
+        } else {
+            // the 16550 has no way to tell the guest a byte was lost
+            let _ = self
+                .output
+                .write_all(&[byte])
+                .and_then(|()| self.output.flush());
+        }
+        // the byte leaves at once, so THR is empty again
+        self.thre = true;
+    }
+
+    /// Highest-priority enabled interrupt, as IIR reports it
+    fn interrupt(&self) -> u8 {
+        if self.ier & IER_LINE != 0 && self.overrun {
+            IIR_LINE
+        } else if self.ier & IER_RX != 0 && !self.rx.is_empty() {
+            IIR_RX
+        } else if self.ier & IER_THRE != 0 && self.thre {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn update_line(&self) {
+        if let Some(line) = &self.line {
+            line.set(self.interrupt() != IIR_NONE);
+        }
+    }
+
+    /// Move bytes from the host input into the receive FIFO while it has
+    /// room, so the host never overruns the guest
+    fn poll_input(&mut self) {
+        let Some(input) = &self.input else { return };
+        let mut received = Vec::new();
+        while self.rx.len() + received.len() < self.capacity() {
+            match input.try_recv() {
+                Ok(byte) => received.push(byte),
+                Err(_) => break,
+            }
+        }
+        if !received.is_empty() {
+            self.rx.extend(received);
+            self.update_line();
+        }
+    }
+
+    fn read(&mut self, offset: u64) -> Result<u8, BusFault> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR if dlab => self.divisor[0],
+            RBR_THR => self.rx.pop_front().unwrap_or(0),
+            IER if dlab => self.divisor[1],
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt();
+                // reading IIR acknowledges a THR-empty interrupt
+                if id == IIR_THRE {
+                    self.thre = false;
+                }
+                id | if self.fifo { IIR_FIFO } else { 0 }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let lsr = LSR_THRE
+                    | LSR_TEMT
+                    | if self.rx.is_empty() { 0 } else { LSR_DR }
+                    | if self.overrun { LSR_OE } else { 0 };
+                self.overrun = false;
+                lsr
+            }
+            MSR if self.mcr & MCR_LOOPBACK != 0 => {
+                // DTR, RTS, OUT1 and OUT2 loop back to DSR, CTS, RI and DCD
+                let mcr = self.mcr;
+                (mcr & 0b10) << 3 | (mcr & 0b1) << 5 | (mcr & 0b1100) << 4
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => return Err(BusFault::Denied(offset)),
+        };
+        self.update_line();
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u64, value: u8) -> Result<(), BusFault> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor[0] = value,
+            RBR_THR => self.transmit(value),
+            IER if dlab => self.divisor[1] = value,
+            IER => {
+                // enabling the THR-empty interrupt raises it at once, as
+                // THR is always empty
+                if value & !self.ier & IER_THRE != 0 {
+                    self.thre = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            IIR_FCR => {
+                self.fifo = value & FCR_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 || !self.fifo {
+                    self.rx.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            // LSR and MSR are read-only
+            LSR | MSR => {}
+            SCR => self.scr = value,
+            _ => return Err(BusFault::Denied(offset)),
+        }
+        self.update_line();
+        Ok(())
+    }
+}
+
+/// NS16550A UART with byte-wide registers, as on the QEMU `virt` machine
+///
+/// Transmitted bytes go straight to `output`, so THR is always empty, and
+/// received bytes come from the host through `set_input` or `receive`.
+/// The interrupt output drives an `InterruptLine` set with
+/// `set_interrupt`. Like `Clint`, a `Uart` is a handle to shared state:
+/// map one clone on the bus and connect another to the hart with
+/// `CPU::connect`, which polls the host input between instructions.
+#[derive(Clone)]
+pub struct Uart {
+    state: Rc<RefCell<State>>,
+}
+
+impl Uart {
+    pub fn new(output: Box<dyn Write>) -> Uart {
+        Uart {
+            state: Rc::new(RefCell::new(State {
+                output,
+                input: None,
+                line: None,
+                rx: VecDeque::new(),
+                overrun: false,
+                thre: false,
+                ier: 0,
+                lcr: 0,
+                mcr: 0,
+                scr: 0,
+                fifo: false,
+                divisor: [0; 2],
+            })),
+        }
+    }
+
+    /// Take received bytes from `input`, typically fed by a thread reading
+    /// the host terminal
+    pub fn set_input(&self, input: Receiver<u8>) {
+        self.state.borrow_mut().input = Some(input);
+    }
+
+    pub fn set_interrupt(&self, line: InterruptLine) {
+        let mut state = self.state.borrow_mut();
+        state.line = Some(line);
+        state.update_line();
+    }
+
+    /// Deliver `byte` to the receiver, setting the overrun error if the
+    /// FIFO is full
+    pub fn receive(&self, byte: u8) {
+        let mut state = self.state.borrow_mut();
+        state.receive(byte);
+        state.update_line();
+    }
+}
+
+impl Bus for Uart {
+    fn read8(&mut self, addr: u64) -> Result<u8, BusFault> {
+        self.state.borrow_mut().read(addr)
+    }
+
+    fn write8(&mut self, addr: u64, value: u8) -> Result<(), BusFault> {
+        self.state.borrow_mut().write(addr, value)
+    }
+
+    // registers are a byte wide; wider accesses would touch several of them
+    // and their side effects at once
+
+    fn read16(&mut self, addr: u64) -> Result<u16, BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn read32(&mut self, addr: u64) -> Result<u32, BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn read64(&mut self, addr: u64) -> Result<u64, BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn write16(&mut self, addr: u64, _value: u16) -> Result<(), BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn write32(&mut self, addr: u64, _value: u32) -> Result<(), BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+
+    fn write64(&mut self, addr: u64, _value: u64) -> Result<(), BusFault> {
+        Err(BusFault::Denied(addr))
+    }
+}
+
+/// The UART interrupts through the PLIC, not mip directly; connecting it
+/// only lets it poll the host input
+impl InterruptSource for Uart {
+    fn tick(&mut self) {
+        self.state.borrow_mut().poll_input();
+    }
+
+    fn pending(&mut self, _hart: u64) -> u64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::mpsc;
+
+    use crate::bus::plic::Plic;
+    use crate::bus::uart::*;
+    use crate::testing::Capture;
+
+    #[test]
+    fn test_transmit_and_registers() {
+        let output = Capture::default();
+        let mut uart = Uart::new(Box::new(output.clone()));
+        for byte in b"hi\n" {
+            assert_eq!(uart.read8(LSR).unwrap() & LSR_THRE, LSR_THRE);
+            uart.write8(RBR_THR, *byte).unwrap();
+        }
+        assert_eq!(*output.0.borrow(), b"hi\n");
+
+        // the divisor latch shadows RBR/THR and IER
+        uart.write8(LCR, LCR_DLAB | 0b11).unwrap();
+        uart.write8(RBR_THR, 0x01).unwrap();
+        uart.write8(IER, 0x02).unwrap();
+        assert_eq!(uart.read8(RBR_THR), Ok(0x01));
+        uart.write8(LCR, 0b11).unwrap();
+        assert_eq!(uart.read8(IER), Ok(0));
+        assert_eq!(output.0.borrow().len(), 3);
+
+        uart.write8(SCR, 0x5a).unwrap();
+        assert_eq!(uart.read8(SCR), Ok(0x5a));
+        assert_eq!(uart.read8(MSR), Ok(MSR_CONNECTED));
+        assert_eq!(uart.read8(IIR_FCR), Ok(IIR_NONE));
+        uart.write8(IIR_FCR, FCR_ENABLE).unwrap();
+        assert_eq!(uart.read8(IIR_FCR), Ok(IIR_FIFO | IIR_NONE));
+        assert_eq!(uart.read32(0), Err(BusFault::Denied(0)));
+        assert_eq!(uart.read8(8), Err(BusFault::Denied(8)));
+    }
+
+    #[test]
+    fn test_receive_fifo_and_overrun() {
+        let mut uart = Uart::new(Box::new(io::sink()));
+        uart.receive(b'a');
+        uart.receive(b'b');
+        // without FIFOs the receiver holds one byte
+        assert_eq!(uart.read8(LSR), Ok(LSR_THRE | LSR_TEMT | LSR_DR | LSR_OE));
+        assert_eq!(uart.read8(LSR).unwrap() & LSR_OE, 0);
+        assert_eq!(uart.read8(RBR_THR), Ok(b'a'));
+        assert_eq!(uart.read8(LSR).unwrap() & LSR_DR, 0);
+
+        uart.write8(IIR_FCR, FCR_ENABLE | FCR_CLEAR_RX).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        uart.set_input(receiver);
+        for byte in 0..20 {
+            sender.send(byte).unwrap();
+        }
+        uart.tick();
+        // the host is held back rather than overrunning the FIFO
+        assert_eq!(uart.read8(LSR).unwrap() & LSR_OE, 0);
+        let received: Vec<u8> = (0..16).map(|_| uart.read8(RBR_THR).unwrap()).collect();
+        assert_eq!(received, (0..16).collect::<Vec<u8>>());
+        uart.tick();
+        assert_eq!(uart.read8(RBR_THR), Ok(16));
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let plic = Plic::new(1, 16);
+        let mut uart = Uart::new(Box::new(io::sink()));
+        uart.set_interrupt(plic.line(UART_IRQ));
+        let pending = |plic: &Plic| {
+            let mut plic = plic.clone();
+            plic.read32(0x1000).unwrap() >> UART_IRQ & 1
+        };
+
+        uart.write8(IER, IER_RX).unwrap();
+        assert_eq!(pending(&plic), 0);
+        uart.receive(b'x');
+        assert_eq!(pending(&plic), 1);
+        assert_eq!(uart.read8(IIR_FCR), Ok(IIR_RX));
+        uart.read8(RBR_THR).unwrap();
+        assert_eq!(pending(&plic), 0);
+
+        // THR-empty raises on enable and on every transmit until IIR is read
+        uart.write8(IER, IER_RX | IER_THRE).unwrap();
+        assert_eq!(pending(&plic), 1);
+        assert_eq!(uart.read8(IIR_FCR), Ok(IIR_THRE));
+        assert_eq!(pending(&plic), 0);
+        uart.write8(RBR_THR, b'y').unwrap();
+        assert_eq!(pending(&plic), 1);
+
+        // received data outranks THR-empty, and line status outranks both
+        uart.write8(IER, IER_RX | IER_THRE | IER_LINE).unwrap();
+        uart.receive(b'1');
+        uart.receive(b'2');
+        assert_eq!(uart.read8(IIR_FCR), Ok(IIR_LINE));
+        uart.read8(LSR).unwrap();
+        assert_eq!(uart.read8(IIR_FCR), Ok(IIR_RX));
+    }
+
+    #[test]
+    fn test_loopback() {
+        let output = Capture::default();
+        let mut uart = Uart::new(Box::new(output.clone()));
+        uart.write8(MCR, MCR_LOOPBACK | 0b1010).unwrap();
+        uart.write8(RBR_THR, b'z').unwrap();
+        assert!(output.0.borrow().is_empty());
+        assert_eq!(uart.read8(RBR_THR), Ok(b'z'));
+        // RTS -> CTS and OUT2 -> DCD
+        assert_eq!(uart.read8(MSR), Ok(0x90));
+    }
+}
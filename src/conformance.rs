@@ -0,0 +1,564 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::bus::{AddressMap, Bus};
+use crate::cpu::csr;
+use crate::cpu::defs::Exception;
+use crate::cpu::CPU;
+use crate::elf::Elf;
+use crate::htif::Htif;
+
+/// Guest RAM given to each test, from its lowest segment up
+const MEMORY_SIZE: u64 = 16 << 20;
+
+/// Most RAM a test's segments may ask for
+const MAX_MEMORY_SIZE: u64 = 256 << 20;
+
+/// Instructions a test may execute before it counts as hung
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 10_000_000;
+
+/// How a conformance test ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The guest exited with status 0 and its signature, if it has a
+    /// reference, matched
+    Pass,
+    /// The guest exited with a non-zero status; riscv-tests report the
+    /// number of the failing test case
+    Fail(u64),
+    /// The signature differs from the reference at the given word
+    Mismatch(usize),
+    /// An exception was raised before the guest installed a trap handler
+    Fault(Exception),
+    /// The instruction limit was reached
+    Timeout,
+    /// The test could not be loaded or talked to the HTIF wrongly
+    Error(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(case) => write!(f, "FAIL (test case {})", case),
+            Outcome::Mismatch(word) => {
+                write!(f, "FAIL (signature differs at word {})", word)
+            }
+            Outcome::Fault(exception) => write!(f, "FAIL (unhandled {:?})", exception),
+            Outcome::Timeout => write!(f, "FAIL (instruction limit reached)"),
+            Outcome::Error(msg) => write!(f, "ERROR ({})", msg),
+        }
+    }
+}
+
+/// Result of running one test
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    /// Path below the suite root, without extension
+    pub name: String,
+    /// Extension the test is reported under; see `group`
+    pub group: String,
+    pub outcome: Outcome,
+    pub instructions: u64,
+}
+
+/// What running an image produced, before any signature check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    pub outcome: Outcome,
+    pub instructions: u64,
+    /// Words between `begin_signature` and `end_signature`, if the image
+    /// has them
+    pub signature: Option<Vec<u32>>,
+}
+
+/// Runs prebuilt riscv-tests and riscv-arch-test ELFs
+///
+/// Each test gets a fresh hart with RAM at its lowest segment and runs until
+/// it exits through the HTIF, as under Spike; console output is discarded.
+/// riscv-tests pass by exiting with status 0. riscv-arch-test images also
+/// have their signature region dumped to `signature_dir` and, when a
+/// reference output is found next to the image or in a sibling
+/// `references` directory, pass only if the signature matches it.
+#[derive(Debug, Clone)]
+pub struct Harness {
+    pub max_instructions: u64,
+    pub signature_dir: Option<PathBuf>,
+    /// Groups to run; all of them if empty
+    pub groups: Vec<String>,
+}
+
+impl Default for Harness {
+    fn default() -> Harness {
+        Harness {
+            max_instructions: DEFAULT_MAX_INSTRUCTIONS,
+            signature_dir: None,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl Harness {
+    /// Run every ELF file under `root` (or `root` itself, if it is a file)
+    /// in path order
+    pub fn run_suite(&self, root: &Path) -> io::Result<Vec<TestResult>> {
+        let mut results = Vec::new();
+        for path in discover(root)? {
+            let group = group(root, &path);
+            if self.groups.is_empty() || self.groups.contains(&group) {
+                results.push(self.run_test(root, &path, group));
+            }
+        }
+        Ok(results)
+    }
+
+    fn run_test(&self, root: &Path, path: &Path, group: String) -> TestResult {
+        let name = path
+            .strip_prefix(root)
+            .ok()
+            .filter(|name| !name.as_os_str().is_empty())
+            .unwrap_or(path.file_name().map_or(path, Path::new))
+            .with_extension("")
+            .to_string_lossy()
+            .into_owned();
+        let execution = match fs::read(path) {
+            Ok(image) => execute(&image, self.max_instructions),
+            Err(err) => Execution {
+                outcome: Outcome::Error(err.to_string()),
+                instructions: 0,
+                signature: None,
+            },
+        };
+        let mut outcome = execution.outcome;
+        if let Some(signature) = &execution.signature {
+            let text = format_signature(signature);
+            if let Some(dir) = &self.signature_dir {
+                let file = dir.join(&name).with_extension("signature");
+                let written = file
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|()| fs::write(&file, &text));
+                if let Err(err) = written {
+                    outcome = Outcome::Error(format!("{}: {}", file.display(), err));
+                }
+            }
+            if outcome == Outcome::Pass {
+                if let Some(reference) = reference(path) {
+                    outcome = match fs::read_to_string(&reference) {
+                        Ok(expected) => compare_signature(&text, &expected),
+                        Err(err) => Outcome::Error(format!("{}: {}", reference.display(), err)),
+                    };
+                }
+            }
+        }
+        TestResult {
+            name,
+            group,
+            outcome,
+            instructions: execution.instructions,
+        }
+    }
+}
+
+/// Load an ELF image and run it until it exits through the HTIF
+pub fn execute(image: &[u8], max_instructions: u64) -> Execution {
+    let error = |msg: String| Execution {
+        outcome: Outcome::Error(msg),
+        instructions: 0,
+        signature: None,
+    };
+    let elf = match Elf::parse(image) {
+        Ok(elf) => elf,
+        Err(err) => return error(err.to_string()),
+    };
+    let Some(mut htif) = Htif::from_elf(&elf, Box::new(io::sink())) else {
+        return error(String::from("no tohost symbol"));
+    };
+    let base = elf.segments.iter().map(|s| s.paddr).min().unwrap_or(0) & !0xfff;
+    let Some(end) = elf
+        .segments
+        .iter()
+        .map(|s| s.paddr.checked_add(s.memsz))
+        .try_fold(base, |end, s| Some(end.max(s?)))
+    else {
+        return error(String::from("segment wraps past the end of memory"));
+    };
+    if end - base > MAX_MEMORY_SIZE {
+        return error(format!(
+            "segments span {:#x} bytes, more than the {:#x} available",
+            end - base,
+            MAX_MEMORY_SIZE
+        ));
+    }
+    let size = MEMORY_SIZE.max((end - base).next_multiple_of(0x1000));
+    if base.checked_add(size).is_none() {
+        return error(format!("no room for RAM at {:#x}", base));
+    }
+    let mut bus = AddressMap::new();
+    bus.map_ram(base, size);
+    let mut cpu = CPU::with_bus(bus);
+    if let Err(err) = elf.load(&mut cpu) {
+        return error(err.to_string());
+    }
+
+    let mut instructions = 0;
+    let outcome = loop {
+        match htif.poll(&mut cpu.bus) {
+            Ok(Some(0)) => break Outcome::Pass,
+            Ok(Some(case)) => break Outcome::Fail(case),
+            Ok(None) => {}
+            Err(err) => break Outcome::Error(err.to_string()),
+        }
+        if instructions >= max_instructions {
+            break Outcome::Timeout;
+        }
+        let handled = cpu.csrs.read(csr::MTVEC) != Some(0);
+        match cpu.step() {
+            Err(exception) if !handled => break Outcome::Fault(exception),
+            _ => instructions += 1,
+        }
+    };
+
+    let signature = elf
+        .symbol("begin_signature")
+        .zip(elf.symbol("end_signature"))
+        .map(|(begin, end)| read_signature(&mut cpu.bus, begin.value, end.value, base, size))
+        .transpose();
+    match signature {
+        Ok(signature) => Execution {
+            outcome,
+            instructions,
+            signature,
+        },
+        Err(msg) => Execution {
+            outcome: Outcome::Error(msg),
+            instructions,
+            signature: None,
+        },
+    }
+}
+
+/// Words from `begin` up to `end`, which must lie in the test's RAM at
+/// `base`
+fn read_signature<B: Bus + ?Sized>(
+    bus: &mut B,
+    begin: u64,
+    end: u64,
+    base: u64,
+    size: u64,
+) -> Result<Vec<u32>, String> {
+    if begin > end || begin < base || end - base > size {
+        return Err(format!(
+            "signature [{:#x}, {:#x}) is outside RAM [{:#x}, {:#x})",
+            begin,
+            end,
+            base,
+            base + size
+        ));
+    }
+    (begin..end)
+        .step_by(4)
+        .map(|addr| {
+            bus.read32(addr)
+                .map_err(|_| format!("signature word at {:#x} cannot be read", addr))
+        })
+        .collect()
+}
+
+/// Signature as the riscv-arch-test reference models write it: one 32-bit
+/// word per line in lowercase hex
+pub fn format_signature(words: &[u32]) -> String {
+    words.iter().map(|word| format!("{:08x}\n", word)).collect()
+}
+
+/// Compare a signature with a reference output, ignoring case and blank
+/// lines
+fn compare_signature(actual: &str, expected: &str) -> Outcome {
+    let words = |text: &str| -> Vec<String> {
+        text.lines()
+            .map(|line| line.trim().to_ascii_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect()
+    };
+    let (actual, expected) = (words(actual), words(expected));
+    match actual.iter().zip(&expected).position(|(a, e)| a != e) {
+        Some(word) => Outcome::Mismatch(word),
+        None if actual.len() != expected.len() => {
+            Outcome::Mismatch(actual.len().min(expected.len()))
+        }
+        None => Outcome::Pass,
+    }
+}
+
+/// Reference output for the test at `path`: `<name>.reference_output` next
+/// to it, in `references` beside it, or in `../references` for an image
+/// built in riscv-arch-test's `src` directory
+fn reference(path: &Path) -> Option<PathBuf> {
+    let name = Path::new(path.file_stem()?).with_extension("reference_output");
+    let dir = path.parent()?;
+    [
+        dir.join(&name),
+        dir.join("references").join(&name),
+        dir.join("..").join("references").join(&name),
+    ]
+    .into_iter()
+    .find(|candidate| candidate.is_file())
+}
+
+/// ELF files under `root`, sorted by path
+pub fn discover(root: &Path) -> io::Result<Vec<PathBuf>> {
+    if root.is_file() {
+        return Ok(vec![root.to_path_buf()]);
+    }
+    let mut found = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if is_elf(&path) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn is_elf(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    fs::File::open(path)
+        .and_then(|mut file| io::Read::read_exact(&mut file, &mut magic))
+        .is_ok_and(|()| magic == *b"\x7fELF")
+}
+
+/// Extension a test is reported under: the riscv-tests prefix of its file
+/// name (`rv64ui` for `rv64ui-p-add`), or else the first directory below
+/// the suite root (`I` for `I/add-01.elf` in riscv-arch-test)
+pub fn group(root: &Path, path: &Path) -> String {
+    let file = path.file_name().unwrap_or_default().to_string_lossy();
+    if file.starts_with("rv32") || file.starts_with("rv64") {
+        if let Some((prefix, _)) = file.split_once('-') {
+            return prefix.to_string();
+        }
+    }
+    let dir = match path.strip_prefix(root) {
+        Ok(relative) if relative.components().count() > 1 => relative.components().next(),
+        _ => path
+            .parent()
+            .and_then(|parent| parent.components().next_back()),
+    };
+    dir.map_or(String::from("other"), |dir| {
+        dir.as_os_str().to_string_lossy().into_owned()
+    })
+}
+
+/// Passed and total test counts for each group
+pub fn summarize(results: &[TestResult]) -> BTreeMap<&str, (usize, usize)> {
+    let mut summary = BTreeMap::new();
+    for result in results {
+        let (passed, total) = summary.entry(result.group.as_str()).or_insert((0, 0));
+        *passed += (result.outcome == Outcome::Pass) as usize;
+        *total += 1;
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use crate::asm::{assemble, Layout};
+    use crate::conformance::*;
+
+    /// riscv-tests style program: test case 1 checks that 2 + 3 == `sum`,
+    /// then the signature holds the sum
+    fn program(sum: u32) -> Vec<u8> {
+        let source = format!(
+            "
+            _start:
+                la t0, trap
+                csrw mtvec, t0
+                li gp, 1
+                li a0, 2
+                addi a0, a0, 3
+                li a1, {sum}
+                bne a0, a1, fail
+                la t1, begin_signature
+                sw a0, 0(t1)
+                li t2, 1
+                j write_tohost
+            fail:
+                slli t2, gp, 1
+                ori t2, t2, 1
+            write_tohost:
+                la t0, tohost
+                sd t2, 0(t0)
+            spin:
+                j spin
+            trap:
+                j fail
+            .data
+            .align 3
+            tohost:
+                .dword 0
+            fromhost:
+                .dword 0
+            begin_signature:
+                .word 0xdeadbeef
+                .word 0x0badcafe
+            end_signature:
+            "
+        );
+        let layout = Layout {
+            text: 0x8000_0000,
+            data: None,
+        };
+        assemble(&source, &layout).unwrap().elf()
+    }
+
+    /// Empty scratch directory for one test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rast-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_execute() {
+        let passed = execute(&program(5), DEFAULT_MAX_INSTRUCTIONS);
+        assert_eq!(passed.outcome, Outcome::Pass);
+        assert_eq!(passed.signature, Some(vec![5, 0x0bad_cafe]));
+        assert_eq!(
+            execute(&program(6), DEFAULT_MAX_INSTRUCTIONS).outcome,
+            Outcome::Fail(1)
+        );
+        assert_eq!(execute(&program(5), 5).outcome, Outcome::Timeout);
+        assert!(matches!(
+            execute(b"nonsense", 10).outcome,
+            Outcome::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_execute_rejects_huge_segments() {
+        // give the data segment, the last program header, 1 TiB of bss
+        let mut image = program(5);
+        let phoff = u64::from_le_bytes(image[32..40].try_into().unwrap()) as usize;
+        let phnum = u16::from_le_bytes(image[56..58].try_into().unwrap()) as usize;
+        let memsz = phoff + 56 * (phnum - 1) + 40;
+        image[memsz..memsz + 8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert!(matches!(
+            execute(&image, DEFAULT_MAX_INSTRUCTIONS).outcome,
+            Outcome::Error(msg) if msg.contains("more than")
+        ));
+    }
+
+    #[test]
+    fn test_signature_outside_ram() {
+        let mut bus = AddressMap::new();
+        bus.map_ram(0x1000, 0x1000);
+        bus.write32(0x1ffc, 7).unwrap();
+        assert_eq!(
+            read_signature(&mut bus, 0x1ffc, 0x2000, 0x1000, 0x1000),
+            Ok(vec![7])
+        );
+        for (begin, end) in [(0x1ffc, 0x2004), (0x800, 0x1004), (0x1008, 0x1004)] {
+            assert!(read_signature(&mut bus, begin, end, 0x1000, 0x1000).is_err());
+        }
+        // a word straddling the end of RAM
+        assert_eq!(
+            read_signature(&mut bus, 0x1ffe, 0x2000, 0x1000, 0x1000),
+            Err(String::from("signature word at 0x1ffe cannot be read"))
+        );
+    }
+
+    #[test]
+    fn test_suite_with_signatures() {
+        let root = scratch("suite");
+        fs::create_dir_all(root.join("I/references")).unwrap();
+        fs::write(root.join("I/add-01.elf"), program(5)).unwrap();
+        fs::write(root.join("I/add-02.elf"), program(5)).unwrap();
+        fs::write(
+            root.join("I/references/add-01.reference_output"),
+            "00000005\n0BADCAFE\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("I/references/add-02.reference_output"),
+            "00000005\n00000000\n",
+        )
+        .unwrap();
+        fs::write(root.join("I/add-01.dump"), "not an ELF").unwrap();
+        fs::create_dir_all(root.join("isa")).unwrap();
+        fs::write(root.join("isa/rv64ui-p-add"), program(5)).unwrap();
+        fs::write(root.join("isa/rv64um-p-mul"), program(4)).unwrap();
+
+        let harness = Harness {
+            signature_dir: Some(root.join("signatures")),
+            ..Harness::default()
+        };
+        let results = harness.run_suite(&root).unwrap();
+        let outcomes: Vec<(&str, &str, &Outcome)> = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.group.as_str(), &r.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("I/add-01", "I", &Outcome::Pass),
+                ("I/add-02", "I", &Outcome::Mismatch(1)),
+                ("isa/rv64ui-p-add", "rv64ui", &Outcome::Pass),
+                ("isa/rv64um-p-mul", "rv64um", &Outcome::Fail(1)),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("signatures/I/add-01.signature")).unwrap(),
+            "00000005\n0badcafe\n"
+        );
+        let summary = summarize(&results);
+        assert_eq!(summary["I"], (1, 2));
+        assert_eq!(summary["rv64um"], (0, 1));
+
+        let harness = Harness {
+            groups: vec![String::from("rv64ui")],
+            ..Harness::default()
+        };
+        assert_eq!(harness.run_suite(&root).unwrap().len(), 1);
+        assert_eq!(
+            harness.run_suite(&root.join("isa/rv64ui-p-add")).unwrap()[0].name,
+            "rv64ui-p-add"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Run a prebuilt suite named by RAST_TEST_SUITE, optionally limited to
+    /// the comma-separated groups in RAST_TEST_GROUPS, and require every
+    /// test to pass; run it with
+    /// `RAST_TEST_SUITE=<dir> cargo test -- --ignored`
+    #[test]
+    #[ignore = "needs a prebuilt suite named by RAST_TEST_SUITE"]
+    fn test_suite_from_environment() {
+        let root = env::var_os("RAST_TEST_SUITE").expect("RAST_TEST_SUITE is not set");
+        let harness = Harness {
+            groups: env::var("RAST_TEST_GROUPS")
+                .map(|groups| groups.split(',').map(String::from).collect())
+                .unwrap_or_default(),
+            ..Harness::default()
+        };
+        let results = harness.run_suite(Path::new(&root)).unwrap();
+        let failed: Vec<String> = results
+            .iter()
+            .filter(|r| r.outcome != Outcome::Pass)
+            .map(|r| format!("{}: {}", r.name, r.outcome))
+            .collect();
+        assert!(!results.is_empty(), "no tests found");
+        assert!(failed.is_empty(), "{}", failed.join("\n"));
+    }
+}
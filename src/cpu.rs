@@ -1,29 +1,1017 @@
+pub mod csr;
 pub mod defs;
 
-mod decoder;
+pub mod decoder;
+pub mod disasm;
+pub mod encoder;
+mod executor;
+pub mod mmu;
 
+use std::fmt;
 
+use crate::bus::{AddressMap, Bus, InterruptSource};
+use crate::cpu::csr::CsrFile;
+use crate::cpu::decoder::decode;
 use crate::cpu::defs::*;
+use crate::cpu::mmu::{Access, Mmu};
+
+/// Default size of the guest RAM in bytes (1 MiB)
+pub const DEFAULT_MEMORY_SIZE: u64 = 1 << 20;
 
-#[derive(Debug, PartialEq, Eq)]
 pub struct CPU {
     pub registers: [u64; 32],
-    pc: u64
+    pc: u64,
+    pub csrs: CsrFile,
+    pub bus: AddressMap,
+    mmu: Mmu,
+    devices: Vec<Box<dyn InterruptSource>>,
 }
 
 impl CPU {
+    /// Create a CPU with `DEFAULT_MEMORY_SIZE` bytes of RAM mapped at address 0
     pub fn new() -> CPU {
+        let mut bus = AddressMap::new();
+        bus.map_ram(0, DEFAULT_MEMORY_SIZE);
+        CPU::with_bus(bus)
+    }
+
+    /// Create a CPU that fetches, loads and stores through `bus`
+    pub fn with_bus(bus: AddressMap) -> CPU {
         CPU {
             registers: [0; 32],
-            pc: 0
+            pc: 0,
+            csrs: CsrFile::new(),
+            bus,
+            mmu: Mmu::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    /// Connect a device's interrupt lines to the hart
+    ///
+    /// The device is ticked and its lines sampled into mip before every
+    /// instruction; the first device that keeps real time drives the time
+    /// CSR.
+    pub fn connect(&mut self, device: Box<dyn InterruptSource>) {
+        self.devices.push(device);
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    /// Fetch, decode and execute a single instruction
+    ///
+    /// An instruction that raises an exception does not retire and leaves
+    /// the registers and memory untouched; instead the trap is taken
+    /// through mtvec and the exception is returned so the host can observe
+    /// it. If an enabled interrupt is pending, the step takes the interrupt
+    /// trap instead of executing an instruction.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        if self.interrupt() {
+            return Ok(());
+        }
+        let result = self.fetch().and_then(|instr| {
+            let decoded = decode(instr).map_err(|_| Exception::IllegalInstruction(instr))?;
+            self.execute(&decoded, instr)
+        });
+        match result {
+            Ok(()) => self.csrs.retire(),
+            Err(exception) => self.trap(&exception),
+        }
+        result
+    }
+
+    /// Poll the connected devices and take the highest-priority enabled
+    /// interrupt, returning whether one was taken
+    fn interrupt(&mut self) -> bool {
+        if self.devices.is_empty() && self.csrs.read(csr::MIP) == Some(0) {
+            return false;
+        }
+        let hart = self.csrs.read(csr::MHARTID).unwrap_or(0);
+        let mut lines = 0;
+        let mut time = None;
+        for device in self.devices.iter_mut() {
+            device.tick();
+            lines |= device.pending(hart);
+            time = time.or(device.time());
+        }
+        self.csrs.set_lines(lines);
+        self.csrs.set_time(time);
+        match self.csrs.pending_interrupt() {
+            Some(code) => {
+                self.pc = self.csrs.trap(self.pc, csr::MCAUSE_INTERRUPT | code, 0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take a trap for `exception`, raised by the instruction at the pc
+    fn trap(&mut self, exception: &Exception) {
+        let cause = exception.code(self.csrs.privilege());
+        self.pc = self.csrs.trap(self.pc, cause, exception.tval());
+    }
+
+    /// Step until an instruction raises an exception or `max_steps`
+    /// instructions have been executed, returning the number executed
+    ///
+    /// The trap for the exception has been taken when this returns, so
+    /// calling it again resumes in the trap handler.
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<u64, Exception> {
+        let mut steps: u64 = 0;
+        while max_steps.is_none_or(|max| steps < max) {
+            self.step()?;
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    fn fetch(&mut self) -> Result<u32, Exception> {
+        if self.pc & 0b11 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(self.pc));
+        }
+        let addr = self.translate(self.pc, Access::Fetch)?;
+        self.bus
+            .read32(addr)
+            .map_err(|_| Exception::InstructionAccessFault(self.pc))
+    }
+
+    /// Physical address of `vaddr` for `access` from the current mode, or
+    /// from mstatus.MPP for M-mode loads and stores with MPRV set
+    fn translate(&mut self, vaddr: u64, access: Access) -> Result<u64, Exception> {
+        let mut privilege = self.csrs.privilege();
+        let status = self.csrs.read(csr::MSTATUS).unwrap_or(0);
+        if access != Access::Fetch
+            && privilege == Privilege::Machine
+            && status & csr::MSTATUS_MPRV != 0
+        {
+            privilege = Privilege::from_u64((status & csr::MSTATUS_MPP) >> 11).unwrap_or(privilege);
+        }
+        self.mmu
+            .translate(&mut self.bus, &self.csrs, vaddr, access, privilege)
+    }
+
+    /// Physical `(address, length)` runs holding the `size` bytes at
+    /// `vaddr`: one run when they lie in a single page, otherwise one per
+    /// physically contiguous stretch
+    fn translate_range(
+        &mut self,
+        vaddr: u64,
+        size: u64,
+        access: Access,
+    ) -> Result<Vec<(u64, u64)>, Exception> {
+        if (vaddr & 0xfff) + size <= 0x1000 {
+            return Ok(vec![(self.translate(vaddr, access)?, size)]);
+        }
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for i in 0..size {
+            let addr = self.translate(vaddr.wrapping_add(i), access)?;
+            match runs.last_mut() {
+                Some((base, len)) if base.wrapping_add(*len) == addr => *len += 1,
+                _ => runs.push((addr, 1)),
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Read `size` bytes (1, 2, 4 or 8) little-endian from virtual address
+    /// `vaddr`
+    fn read(&mut self, vaddr: u64, size: u64) -> Result<u64, Exception> {
+        let fault = |_| Exception::LoadAccessFault(vaddr);
+        let mut value = 0;
+        let mut shift = 0;
+        for (addr, len) in self.translate_range(vaddr, size, Access::Load)? {
+            let part = match len {
+                1 => self.bus.read8(addr).map(|v| v as u64),
+                2 => self.bus.read16(addr).map(|v| v as u64),
+                4 => self.bus.read32(addr).map(|v| v as u64),
+                8 => self.bus.read64(addr),
+                _ => (0..len).try_fold(0, |part, i| {
+                    let byte = self.bus.read8(addr.wrapping_add(i))?;
+                    Ok(part | (byte as u64) << (8 * i))
+                }),
+            }
+            .map_err(fault)?;
+            value |= part << shift;
+            shift += 8 * len;
         }
+        Ok(value)
+    }
+
+    /// Read `buf.len()` bytes at virtual address `vaddr` for a debugger
+    ///
+    /// Addresses translate as the hart's loads would, falling back to
+    /// fetches for execute-only pages, and no trap is taken.
+    pub fn debug_read(&mut self, vaddr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let vaddr = vaddr.wrapping_add(i as u64);
+            let addr = self
+                .translate(vaddr, Access::Load)
+                .or_else(|_| self.translate(vaddr, Access::Fetch))?;
+            *byte = self
+                .bus
+                .read8(addr)
+                .map_err(|_| Exception::LoadAccessFault(vaddr))?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at virtual address `vaddr` for a debugger, translating
+    /// as the hart's stores would without taking a trap
+    pub fn debug_write(&mut self, vaddr: u64, data: &[u8]) -> Result<(), Exception> {
+        let runs = self.translate_range(vaddr, data.len() as u64, Access::Store)?;
+        let mut data = data;
+        for (addr, len) in runs {
+            let (run, rest) = data.split_at(len as usize);
+            self.bus
+                .load(addr, run)
+                .map_err(|_| Exception::StoreAccessFault(vaddr))?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Drop every cached translation, as after a debugger writes satp
+    pub fn flush_tlb(&mut self) {
+        self.mmu.fence(None, None);
+    }
+
+    /// Write the low `size` bytes (1, 2, 4 or 8) of `value` little-endian to
+    /// virtual address `vaddr`
+    ///
+    /// Every byte is translated, and every physical range it lands in is
+    /// checked to be mapped, before any is written, so a page fault or an
+    /// access fault on a store that crosses pages leaves memory untouched.
+    fn write(&mut self, vaddr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let fault = |_| Exception::StoreAccessFault(vaddr);
+        let runs = self.translate_range(vaddr, size, Access::Store)?;
+        if !runs
+            .iter()
+            .all(|&(addr, len)| self.bus.is_mapped(addr, len))
+        {
+            return Err(Exception::StoreAccessFault(vaddr));
+        }
+        let bytes = value.to_le_bytes();
+        let mut start = 0;
+        for (addr, len) in runs {
+            let part = value >> (8 * start);
+            match len {
+                1 => self.bus.write8(addr, part as u8),
+                2 => self.bus.write16(addr, part as u16),
+                4 => self.bus.write32(addr, part as u32),
+                8 => self.bus.write64(addr, part),
+                _ => self.bus.load(addr, &bytes[start..start + len as usize]),
+            }
+            .map_err(fault)?;
+            start += len as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new()
+    }
+}
+
+impl fmt::Debug for CPU {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CPU")
+            .field("registers", &self.registers)
+            .field("pc", &self.pc)
+            .field("bus", &self.bus)
+            .finish()
     }
 }
 
 #[cfg(test)]
+#[allow(non_snake_case, clippy::assertions_on_constants)]
 mod tests {
+    use crate::cpu::*;
+    use crate::program::Program;
+
+    /// Load `program` at address 0 of a fresh CPU
+    fn cpu_with(program: &[u32]) -> CPU {
+        let mut bus = AddressMap::new();
+        bus.map_ram(0, 0x1000);
+        let mut cpu = CPU::with_bus(bus);
+        for (i, word) in program.iter().enumerate() {
+            cpu.bus.write32(4 * i as u64, *word).unwrap();
+        }
+        cpu
+    }
+
     #[test]
     fn alwayspass() {
         assert!(true);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_ADDI_negative() {
+        // addi x1, x0, -1; addi x2, x1, -2047
+        let mut cpu = cpu_with(&[0xfff0_0093, 0x8010_8113]);
+        assert_eq!(cpu.run(Some(2)), Ok(2));
+        assert_eq!(cpu.registers[1], u64::MAX);
+        assert_eq!(cpu.registers[2], (-2048i64) as u64);
+        assert_eq!(cpu.pc(), 8);
+    }
+
+    #[test]
+    fn test_x0_hardwired() {
+        // addi x0, x0, 5
+        let mut cpu = cpu_with(&[0x0050_0013]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers[0], 0);
+    }
+
+    #[test]
+    fn test_LUI_sign_extends() {
+        // lui x5, 0x80000
+        let mut cpu = cpu_with(&[0x8000_02b7]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers[5], 0xffff_ffff_8000_0000);
+    }
+
+    #[test]
+    fn test_loop_with_backward_branch() {
+        // addi x1, x0, 10
+        // loop: addi x2, x2, 3
+        //       addi x1, x1, -1
+        //       bne x1, x0, loop
+        let mut cpu = cpu_with(&[0x00a0_0093, 0x0031_0113, 0xfff0_8093, 0xfe00_9ce3]);
+        cpu.run(Some(1 + 3 * 10)).unwrap();
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.registers[2], 30);
+        assert_eq!(cpu.pc(), 16);
+    }
+
+    #[test]
+    fn test_JAL_JALR() {
+        // jal x1, 8; (skipped); jalr x2, 0(x1)
+        let mut cpu = cpu_with(&[0x0080_00ef, 0x0000_0013, 0x0000_8167]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers[1], 4);
+        assert_eq!(cpu.pc(), 8);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers[2], 12);
+        assert_eq!(cpu.pc(), 4);
+    }
+
+    #[test]
+    fn test_loads_and_stores() {
+        // addi x1, x0, -128; sb x1, 0x100(x0); lb x2, 0x100(x0); lbu x3, 0x100(x0)
+        // sw x1, 0x104(x0); lw x4, 0x104(x0); lhu x5, 0x104(x0)
+        let mut cpu = cpu_with(&[
+            0xf800_0093,
+            0x1010_0023,
+            0x1000_0103,
+            0x1000_4183,
+            0x1010_2223,
+            0x1040_2203,
+            0x1040_5283,
+        ]);
+        cpu.run(Some(7)).unwrap();
+        assert_eq!(cpu.registers[2], (-128i64) as u64);
+        assert_eq!(cpu.registers[3], 0x80);
+        assert_eq!(cpu.registers[4], (-128i64) as u64);
+        assert_eq!(cpu.registers[5], 0xff80);
+    }
+
+    #[test]
+    fn test_immediate_shifts_use_six_bit_shamt() {
+        // slli x2, x1, 63; srli x3, x1, 60; srai x4, x1, 60
+        let mut cpu = cpu_with(&[0x03f0_9113, 0x03c0_d193, 0x43c0_d213]);
+        cpu.registers[1] = 0x8000_0000_0000_0001;
+        cpu.run(Some(3)).unwrap();
+        assert_eq!(cpu.registers[2], 0x8000_0000_0000_0000);
+        assert_eq!(cpu.registers[3], 0x8);
+        assert_eq!(cpu.registers[4], 0xffff_ffff_ffff_fff8);
+    }
+
+    #[test]
+    fn test_doubleword_loads_and_stores() {
+        // sd x1, 0x100(x0); ld x2, 0x100(x0); lw x3, 0x100(x0); lwu x4, 0x100(x0)
+        let mut cpu = cpu_with(&[0x1010_3023, 0x1000_3103, 0x1000_2183, 0x1000_6203]);
+        cpu.registers[1] = 0x1234_5678_9abc_def0;
+        cpu.run(Some(4)).unwrap();
+        assert_eq!(cpu.registers[2], 0x1234_5678_9abc_def0);
+        assert_eq!(cpu.registers[3], 0xffff_ffff_9abc_def0);
+        assert_eq!(cpu.registers[4], 0x9abc_def0);
+    }
+
+    #[test]
+    fn test_word_ops_sign_extend() {
+        // addiw x2, x1, 1; slliw x3, x1, 31; srliw x4, x1, 4; sraiw x5, x1, 4
+        // addw x6, x1, x1; subw x7, x0, x1; sllw x8, x1, x1; srlw x9, x1, x1; sraw x10, x1, x1
+        let mut cpu = cpu_with(&[
+            0x0010_811b,
+            0x01f0_919b,
+            0x0040_d21b,
+            0x4040_d29b,
+            0x0010_833b,
+            0x4010_03bb,
+            0x0010_943b,
+            0x0010_d4bb,
+            0x4010_d53b,
+        ]);
+        cpu.registers[1] = 0xffff_ffff_7fff_ffff;
+        cpu.run(Some(9)).unwrap();
+        assert_eq!(cpu.registers[2], 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.registers[3], 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.registers[4], 0x07ff_ffff);
+        assert_eq!(cpu.registers[5], 0x07ff_ffff);
+        assert_eq!(cpu.registers[6], 0xffff_ffff_ffff_fffe);
+        assert_eq!(cpu.registers[7], 0xffff_ffff_8000_0001);
+        // shift amounts come from the low five bits of rs2 (31)
+        assert_eq!(cpu.registers[8], 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.registers[9], 0);
+        assert_eq!(cpu.registers[10], 0);
+    }
+
+    #[test]
+    fn test_division_edge_cases() {
+        // addi x1, x0, 7; div x2, x1, x0; divu x3, x1, x0; rem x4, x1, x0; remu x5, x1, x0
+        let mut cpu = cpu_with(&[
+            0x0070_0093,
+            0x0200_c133,
+            0x0200_d1b3,
+            0x0200_e233,
+            0x0200_f2b3,
+        ]);
+        cpu.run(Some(5)).unwrap();
+        assert_eq!(cpu.registers[2], u64::MAX);
+        assert_eq!(cpu.registers[3], u64::MAX);
+        assert_eq!(cpu.registers[4], 7);
+        assert_eq!(cpu.registers[5], 7);
+
+        // div x3, x1, x2; rem x4, x1, x2 with the most negative dividend over -1
+        let mut cpu = cpu_with(&[0x0220_c1b3, 0x0220_e233]);
+        cpu.registers[1] = i64::MIN as u64;
+        cpu.registers[2] = u64::MAX;
+        cpu.run(Some(2)).unwrap();
+        assert_eq!(cpu.registers[3], i64::MIN as u64);
+        assert_eq!(cpu.registers[4], 0);
+    }
+
+    /// Encode `op x3, x1, x2` for an R-type instruction
+    fn r_type(opcode: OPCODE, funct3: u32, funct7: u32) -> u32 {
+        funct7 << 25 | 2 << 20 | 1 << 15 | funct3 << 12 | 3 << 7 | opcode.to_u32()
+    }
+
+    /// Check `op x3, x1, x2` against `(rs1, rs2, rd)` vectors
+    fn check_vectors(instr: u32, vectors: &[(i64, i64, i64)]) {
+        for &(rs1, rs2, rd) in vectors {
+            let mut cpu = cpu_with(&[instr]);
+            cpu.registers[1] = rs1 as u64;
+            cpu.registers[2] = rs2 as u64;
+            cpu.step().unwrap();
+            assert_eq!(
+                cpu.registers[3] as i64, rd,
+                "{:#010x} with rs1 = {:#x}, rs2 = {:#x}",
+                instr, rs1, rs2
+            );
+        }
+    }
+
+    #[test]
+    fn test_RV64M_word_vectors() {
+        const MIN: i64 = -1 << 31;
+        check_vectors(
+            r_type(OPCODE::OP_32, 0b000, 1), // mulw
+            &[
+                (0, 0, 0),
+                (1, 1, 1),
+                (3, 7, 21),
+                (0, -0x8000, 0),
+                (MIN, 0, 0),
+                (MIN, -0x8000, 0),
+                (0x7fff_ffff, 2, -2),
+                (0x1_0000_0003, 0x2_0000_0005, 15),
+            ],
+        );
+        check_vectors(
+            r_type(OPCODE::OP_32, 0b100, 1), // divw
+            &[
+                (20, 6, 3),
+                (-20, 6, -3),
+                (20, -6, -3),
+                (-20, -6, 3),
+                (MIN, 1, MIN),
+                (MIN, -1, MIN),
+                (MIN, 0, -1),
+                (1, 0, -1),
+                (0, 0, -1),
+                (0x1_0000_0014, 0x2_0000_0006, 3),
+            ],
+        );
+        check_vectors(
+            r_type(OPCODE::OP_32, 0b101, 1), // divuw
+            &[
+                (20, 6, 3),
+                (-20, 6, 715_827_879),
+                (20, -6, 0),
+                (-20, -6, 0),
+                (MIN, 1, MIN),
+                (MIN, -1, 0),
+                (MIN, 0, -1),
+                (1, 0, -1),
+                (0, 0, -1),
+            ],
+        );
+        check_vectors(
+            r_type(OPCODE::OP_32, 0b110, 1), // remw
+            &[
+                (20, 6, 2),
+                (-20, 6, -2),
+                (20, -6, 2),
+                (-20, -6, -2),
+                (MIN, 1, 0),
+                (MIN, -1, 0),
+                (MIN, 0, MIN),
+                (1, 0, 1),
+                (0, 0, 0),
+                (-1897, 0, -1897),
+                (0x1_8000_0000, 0, MIN),
+            ],
+        );
+        check_vectors(
+            r_type(OPCODE::OP_32, 0b111, 1), // remuw
+            &[
+                (20, 6, 2),
+                (-20, 6, 2),
+                (20, -6, 20),
+                (-20, -6, -20),
+                (MIN, 1, 0),
+                (MIN, -1, MIN),
+                (MIN, 0, MIN),
+                (1, 0, 1),
+                (0, 0, 0),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_MULH_variants() {
+        // mulh x3, x1, x2; mulhsu x4, x1, x2; mulhu x5, x1, x2
+        let mut cpu = cpu_with(&[0x0220_91b3, 0x0220_a233, 0x0220_b2b3]);
+        cpu.registers[1] = u64::MAX; // -1
+        cpu.registers[2] = u64::MAX;
+        cpu.run(Some(3)).unwrap();
+        assert_eq!(cpu.registers[3], 0);
+        assert_eq!(cpu.registers[4], u64::MAX);
+        assert_eq!(cpu.registers[5], u64::MAX - 1);
+    }
+
+    #[test]
+    fn test_illegal_instruction_stops_run() {
+        let mut cpu = cpu_with(&[0x0000_0013, 0xffff_ffff]);
+        assert_eq!(
+            cpu.run(None),
+            Err(Exception::IllegalInstruction(0xffff_ffff))
+        );
+        assert_eq!(cpu.csrs.read(csr::MEPC), Some(4));
+        assert_eq!(cpu.csrs.read(csr::MTVAL), Some(0xffff_ffff));
+    }
+
+    #[test]
+    fn test_access_faults() {
+        // lw x1, 0(x2); sw x1, 0(x2)
+        let mut cpu = cpu_with(&[0x0001_2083, 0x0011_2023]);
+        cpu.registers[2] = 0x1000;
+        assert_eq!(cpu.step(), Err(Exception::LoadAccessFault(0x1000)));
+        cpu.set_pc(4);
+        assert_eq!(cpu.step(), Err(Exception::StoreAccessFault(0x1000)));
+        cpu.set_pc(0x2000);
+        assert_eq!(cpu.step(), Err(Exception::InstructionAccessFault(0x2000)));
+    }
+
+    #[test]
+    fn test_store_past_end_of_ram_writes_nothing() {
+        // sd x1, 0x7fc(x2); ld x3, 0x7fc(x2)
+        let mut cpu = cpu_with(&[0x7e11_3e23, 0x7fc1_3183]);
+        cpu.registers[1] = u64::MAX;
+        cpu.registers[2] = 0x800;
+        assert_eq!(cpu.step(), Err(Exception::StoreAccessFault(0xffc)));
+        assert_eq!(cpu.bus.read32(0xffc), Ok(0));
+        cpu.set_pc(4);
+        assert_eq!(cpu.step(), Err(Exception::LoadAccessFault(0xffc)));
+        assert_eq!(cpu.registers[3], 0);
+    }
+
+    #[test]
+    fn test_system_instructions() {
+        // fence; fence.i; ecall; ebreak
+        let mut cpu = cpu_with(&[0x0ff0_000f, 0x0000_100f, 0x0000_0073, 0x0010_0073]);
+        assert_eq!(cpu.run(None), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.csrs.read(csr::MEPC), Some(8));
+        cpu.set_pc(12);
+        assert_eq!(cpu.step(), Err(Exception::Breakpoint(12)));
+        assert_eq!(cpu.csrs.read(csr::MEPC), Some(12));
+    }
+
+    #[test]
+    fn test_traps_enter_handler_and_mret_returns() {
+        let mut cpu = Program::new()
+            .li(REG::x1, 0x100)
+            .csrrw(REG::x0, csr::MTVEC, REG::x1)
+            .csrrsi(REG::x0, csr::MSTATUS, 0b1000) // MIE
+            .ecall()
+            .ebreak()
+            .nop() // replaced by an illegal word
+            .j(0)
+            .cpu()
+            .unwrap();
+        cpu.bus.write32(0x14, 0).unwrap();
+        // skip the trapping instruction and count traps in x31
+        Program::new()
+            .at(0x100)
+            .csrrs(REG::x5, csr::MEPC, REG::x0)
+            .addi(REG::x5, REG::x5, 4)
+            .csrrw(REG::x0, csr::MEPC, REG::x5)
+            .addi(REG::x31, REG::x31, 1)
+            .mret()
+            .load(&mut cpu)
+            .unwrap();
+        cpu.set_pc(0);
+        let enables = |cpu: &CPU| {
+            cpu.csrs.read(csr::MSTATUS).unwrap() & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE)
+        };
+
+        assert_eq!(cpu.run(None), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.pc(), 0x100);
+        assert_eq!(cpu.csrs.read(csr::MEPC), Some(0xc));
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), Some(11));
+        assert_eq!(enables(&cpu), csr::MSTATUS_MPIE);
+        assert_eq!(cpu.run(Some(5)), Ok(5));
+        assert_eq!(cpu.pc(), 0x10);
+        assert_eq!(enables(&cpu), csr::MSTATUS_MIE | csr::MSTATUS_MPIE);
+
+        assert_eq!(cpu.step(), Err(Exception::Breakpoint(0x10)));
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), Some(3));
+        assert_eq!(cpu.csrs.read(csr::MTVAL), Some(0x10));
+        cpu.run(Some(5)).unwrap();
+
+        assert_eq!(cpu.step(), Err(Exception::IllegalInstruction(0)));
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), Some(2));
+        assert_eq!(cpu.csrs.read(csr::MTVAL), Some(0));
+        cpu.run(Some(5)).unwrap();
+        assert_eq!(cpu.pc(), 0x18);
+        assert_eq!(cpu.registers[31], 3);
+    }
+
+    #[test]
+    fn test_privilege_modes() {
+        // M-mode: delegate user ECALLs and illegal instructions, then MRET
+        // to S-mode at 0x300
+        let mut cpu = Program::new()
+            .li(REG::x5, 0x100)
+            .csrrw(REG::x0, csr::MTVEC, REG::x5)
+            .li(REG::x5, 0x200)
+            .csrrw(REG::x0, csr::STVEC, REG::x5)
+            .li(REG::x5, 1 << 8 | 1 << 2)
+            .csrrw(REG::x0, csr::MEDELEG, REG::x5)
+            .li(REG::x5, 0x300)
+            .csrrw(REG::x0, csr::MEPC, REG::x5)
+            .li(REG::x5, csr::MSTATUS_MPP as i64)
+            .csrrc(REG::x0, csr::MSTATUS, REG::x5)
+            .li(REG::x5, 1 << 11)
+            .csrrs(REG::x0, csr::MSTATUS, REG::x5)
+            .mret()
+            .cpu()
+            .unwrap();
+        // S-mode: SRET to U-mode at 0x400
+        Program::new()
+            .at(0x300)
+            .csrrs(REG::x6, csr::SSTATUS, REG::x0)
+            .li(REG::x5, 0x400)
+            .csrrw(REG::x0, csr::SEPC, REG::x5)
+            .sret()
+            .ecall()
+            .mret()
+            .sret()
+            .wfi()
+            .load(&mut cpu)
+            .unwrap();
+        // U-mode
+        Program::new()
+            .at(0x400)
+            .ecall()
+            .csrrs(REG::x7, csr::MSTATUS, REG::x0)
+            .csrrs(REG::x7, csr::CYCLE, REG::x0)
+            .wfi()
+            .load(&mut cpu)
+            .unwrap();
+        cpu.set_pc(0);
+
+        assert_eq!(cpu.run(None), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.registers[6] & csr::MSTATUS_MPP, 0);
+        assert_eq!(cpu.csrs.privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x200);
+        assert_eq!(cpu.csrs.read(csr::SCAUSE), Some(8));
+        assert_eq!(cpu.csrs.read(csr::SEPC), Some(0x400));
+
+        // CSRs above the current privilege and counters not enabled in
+        // mcounteren are illegal
+        let user = |cpu: &mut CPU, pc| {
+            cpu.csrs.set_privilege(Privilege::User);
+            cpu.set_pc(pc);
+            cpu.step()
+        };
+        assert!(matches!(
+            user(&mut cpu, 0x404),
+            Err(Exception::IllegalInstruction(_))
+        ));
+        assert_eq!(cpu.csrs.read(csr::SCAUSE), Some(2));
+        assert_eq!(cpu.csrs.read(csr::STVAL), Some(0x3000_23f3));
+        assert!(user(&mut cpu, 0x408).is_err());
+        cpu.csrs.write(csr::MCOUNTEREN, 1).unwrap();
+        cpu.csrs.write(csr::SCOUNTEREN, 1).unwrap();
+        assert_eq!(user(&mut cpu, 0x408), Ok(()));
+        assert_eq!(user(&mut cpu, 0x40c), Ok(()));
+
+        // S-mode ECALL is not delegated and goes to M
+        let supervisor = |cpu: &mut CPU, pc| {
+            cpu.csrs.set_privilege(Privilege::Supervisor);
+            cpu.set_pc(pc);
+            cpu.step()
+        };
+        assert_eq!(supervisor(&mut cpu, 0x310), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.csrs.privilege(), Privilege::Machine);
+        assert_eq!(cpu.pc(), 0x100);
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), Some(9));
+        let status = cpu.csrs.read(csr::MSTATUS).unwrap();
+        assert_eq!(status & csr::MSTATUS_MPP, 1 << 11);
+
+        // MRET below M, and SRET and WFI when trapped by TSR and TW
+        assert!(supervisor(&mut cpu, 0x314).is_err());
+        assert_eq!(cpu.csrs.read(csr::SCAUSE), Some(2));
+        assert_eq!(supervisor(&mut cpu, 0x31c), Ok(()));
+        cpu.csrs
+            .write(csr::MSTATUS, status | csr::MSTATUS_TSR | csr::MSTATUS_TW)
+            .unwrap();
+        assert!(supervisor(&mut cpu, 0x318).is_err());
+        assert!(supervisor(&mut cpu, 0x31c).is_err());
+        assert!(user(&mut cpu, 0x40c).is_err());
+    }
+
+    #[test]
+    fn test_fetch_faults_trap() {
+        let mut cpu = Program::new()
+            .li(REG::x1, 0x200)
+            .csrrw(REG::x0, csr::MTVEC, REG::x1)
+            .jalr(REG::x0, 2, REG::x1)
+            .cpu()
+            .unwrap();
+        // misaligned target: the jump itself traps, with the target in mtval
+        assert_eq!(
+            cpu.run(None),
+            Err(Exception::InstructionAddressMisaligned(0x202))
+        );
+        assert_eq!(cpu.pc(), 0x200);
+        assert_eq!(cpu.csrs.read(csr::MEPC), Some(0x8));
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), Some(0));
+        assert_eq!(cpu.csrs.read(csr::MTVAL), Some(0x202));
+
+        cpu.csrs.write(csr::MTVEC, 0x300).unwrap();
+        cpu.set_pc(0x10_0000);
+        assert_eq!(
+            cpu.step(),
+            Err(Exception::InstructionAccessFault(0x10_0000))
+        );
+        assert_eq!(cpu.pc(), 0x300);
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), Some(1));
+        assert_eq!(cpu.csrs.read(csr::MTVAL), Some(0x10_0000));
+    }
+
+    #[test]
+    fn test_virtual_memory() {
+        // M-mode: enable Sv39 with the root table at 0x10000, then MRET to
+        // S-mode at virtual address 0x1000_0000
+        let mut cpu = Program::new()
+            .li(REG::x5, 0x100)
+            .csrrw(REG::x0, csr::MTVEC, REG::x5)
+            .li(REG::x5, (csr::SATP_MODE_SV39 << 60 | 0x10) as i64)
+            .csrrw(REG::x0, csr::SATP, REG::x5)
+            .li(REG::x5, 0x1000_0000)
+            .csrrw(REG::x0, csr::MEPC, REG::x5)
+            .li(REG::x5, csr::MSTATUS_MPP as i64)
+            .csrrc(REG::x0, csr::MSTATUS, REG::x5)
+            .li(REG::x5, 1 << 11)
+            .csrrs(REG::x0, csr::MSTATUS, REG::x5)
+            .mret()
+            .cpu()
+            .unwrap();
+        // S-mode code at physical 0x20000: store to its data page, then
+        // load from a user page without SUM
+        Program::new()
+            .at(0x20000)
+            .li(REG::x5, 0x1000_1000)
+            .li(REG::x6, 42)
+            .sd(REG::x6, 8, REG::x5)
+            .li(REG::x5, 0x1000_2000)
+            .ld(REG::x7, 0, REG::x5)
+            .sfence_vma(REG::x0, REG::x0)
+            .load(&mut cpu)
+            .unwrap();
+        let pte = |ppn: u64, flags: u64| ppn << 10 | flags;
+        let (r, w, x, u) = (1 << 1, 1 << 2, 1 << 3, 1 << 4);
+        cpu.bus.write64(0x10000, pte(0x11, 1)).unwrap();
+        cpu.bus.write64(0x11000 + 8 * 0x80, pte(0x12, 1)).unwrap();
+        cpu.bus.write64(0x12000, pte(0x20, 1 | r | x)).unwrap();
+        cpu.bus.write64(0x12008, pte(0x21, 1 | r | w)).unwrap();
+        cpu.bus.write64(0x12010, pte(0x22, 1 | r | w | u)).unwrap();
+        cpu.set_pc(0);
+
+        assert_eq!(cpu.run(None), Err(Exception::LoadPageFault(0x1000_2000)));
+        assert_eq!(cpu.bus.read64(0x21008), Ok(42));
+        // accessed and dirty bits set by the walks
+        assert_eq!(cpu.bus.read64(0x12008).unwrap() >> 6 & 0b11, 0b11);
+        assert_eq!(cpu.bus.read64(0x12000).unwrap() >> 6 & 0b11, 0b01);
+        assert_eq!(cpu.pc(), 0x100);
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), Some(13));
+        assert_eq!(cpu.csrs.read(csr::MTVAL), Some(0x1000_2000));
+        let load = cpu.csrs.read(csr::MEPC).unwrap();
+        assert_eq!(load & !0xfff, 0x1000_0000);
+
+        // with SUM the load succeeds
+        cpu.csrs.set_privilege(Privilege::Supervisor);
+        let status = cpu.csrs.read(csr::MSTATUS).unwrap();
+        cpu.csrs
+            .write(csr::MSTATUS, status | csr::MSTATUS_SUM)
+            .unwrap();
+        cpu.set_pc(load);
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.step(), Ok(()));
+
+        // SFENCE.VMA is illegal in U-mode and in S-mode with TVM set; a
+        // U-mode fetch from a supervisor page is a page fault
+        cpu.csrs.set_privilege(Privilege::User);
+        cpu.set_pc(load + 4);
+        assert_eq!(cpu.step(), Err(Exception::InstructionPageFault(load + 4)));
+        cpu.csrs.write(csr::MSTATUS, csr::MSTATUS_TVM).unwrap();
+        cpu.csrs.set_privilege(Privilege::Supervisor);
+        cpu.set_pc(load + 4);
+        assert!(matches!(cpu.step(), Err(Exception::IllegalInstruction(_))));
+
+        // M-mode loads with MPRV set translate as MPP
+        cpu.csrs.set_privilege(Privilege::Machine);
+        cpu.csrs
+            .write(csr::MSTATUS, csr::MSTATUS_MPRV | 1 << 11)
+            .unwrap();
+        assert_eq!(cpu.read(0x1000_1008, 8), Ok(42));
+        assert_eq!(cpu.read(0x21008, 8), Err(Exception::LoadPageFault(0x21008)));
+        cpu.csrs.write(csr::MSTATUS, 1 << 11).unwrap();
+        assert_eq!(cpu.read(0x21008, 8), Ok(42));
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        use crate::bus::clint::{Clint, Timebase, CLINT_BASE, CLINT_SIZE};
+
+        // arm mtimecmp 20 ticks ahead, enable MTIE and MIE, then spin; the
+        // handler at 0x100 masks the timer by setting mtimecmp to all ones
+        let program = Program::new()
+            .li(REG::x5, 0x100)
+            .csrrw(REG::x0, csr::MTVEC, REG::x5)
+            .li(REG::x5, CLINT_BASE as i64 + 0x4000)
+            .li(REG::x7, CLINT_BASE as i64 + 0xbff8)
+            .ld(REG::x6, 0, REG::x7)
+            .addi(REG::x6, REG::x6, 20)
+            .sd(REG::x6, 0, REG::x5)
+            .li(REG::x6, csr::MIP_MTIP as i64)
+            .csrrs(REG::x0, csr::MIE, REG::x6)
+            .csrrsi(REG::x0, csr::MSTATUS, csr::MSTATUS_MIE as u32)
+            .label("spin")
+            .j("spin");
+        let spin = program.address("spin").unwrap();
+        let mut cpu = program.cpu().unwrap();
+        Program::new()
+            .at(0x100)
+            .li(REG::x6, -1)
+            .sd(REG::x6, 0, REG::x5)
+            .csrrs(REG::x10, csr::MCAUSE, REG::x0)
+            .csrrs(REG::x11, csr::MEPC, REG::x0)
+            .ecall()
+            .load(&mut cpu)
+            .unwrap();
+        let clint = Clint::new(1, Timebase::Instructions(1));
+        cpu.bus.map(CLINT_BASE, CLINT_SIZE, Box::new(clint.clone()));
+        cpu.connect(Box::new(clint.clone()));
+
+        cpu.set_pc(0);
+        assert_eq!(cpu.run(Some(100)), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.registers[10], csr::MCAUSE_INTERRUPT | 7);
+        let status = cpu.csrs.read(csr::MSTATUS).unwrap();
+        assert_eq!(status & csr::MSTATUS_MIE, 0);
+        assert_eq!(cpu.csrs.read(csr::MIP).unwrap() & csr::MIP_MTIP, 0);
+        assert_eq!(cpu.registers[11], spin);
+        assert!(clint.mtime() >= 20);
+        assert_eq!(cpu.csrs.read(csr::TIME), Some(clint.mtime()));
+    }
+
+    #[test]
+    fn test_external_interrupt() {
+        use crate::bus::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+
+        // source 10 at priority 1, enabled for hart 0's M-mode context; the
+        // handler at 0x100 claims it twice and completes it
+        let mut cpu = Program::new()
+            .li(REG::x5, 0x100)
+            .csrrw(REG::x0, csr::MTVEC, REG::x5)
+            .li(REG::x5, PLIC_BASE as i64)
+            .li(REG::x6, 1)
+            .sw(REG::x6, 40, REG::x5)
+            .li(REG::x7, PLIC_BASE as i64 + 0x2000)
+            .li(REG::x6, 1 << 10)
+            .sw(REG::x6, 0, REG::x7)
+            .li(REG::x6, csr::MIP_MEIP as i64)
+            .csrrs(REG::x0, csr::MIE, REG::x6)
+            .csrrsi(REG::x0, csr::MSTATUS, csr::MSTATUS_MIE as u32)
+            .label("spin")
+            .j("spin")
+            .cpu()
+            .unwrap();
+        Program::new()
+            .at(0x100)
+            .li(REG::x7, PLIC_BASE as i64 + 0x20_0004)
+            .lw(REG::x10, 0, REG::x7)
+            .lw(REG::x11, 0, REG::x7)
+            .sw(REG::x10, 0, REG::x7)
+            .csrrs(REG::x12, csr::MCAUSE, REG::x0)
+            .ecall()
+            .load(&mut cpu)
+            .unwrap();
+        let plic = Plic::new(1, 16);
+        cpu.bus.map(PLIC_BASE, PLIC_SIZE, Box::new(plic.clone()));
+        cpu.connect(Box::new(plic.clone()));
+        cpu.set_pc(0);
+
+        assert_eq!(cpu.run(Some(50)), Ok(50));
+        plic.line(10).raise();
+        assert_eq!(cpu.run(Some(50)), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.registers[10], 10);
+        // claimed, so not offered twice
+        assert_eq!(cpu.registers[11], 0);
+        assert_eq!(cpu.registers[12], csr::MCAUSE_INTERRUPT | 11);
+        // completed with the line still raised: pending again
+        assert_eq!(
+            cpu.csrs.read(csr::MIP).unwrap() & csr::MIP_MEIP,
+            csr::MIP_MEIP
+        );
+    }
+
+    #[test]
+    fn test_CSR_instructions() {
+        // csrrw x2, mscratch, x1; csrrs x3, mscratch, x0; csrrci x4, mscratch, 0b101
+        // csrrsi x5, mscratch, 0b10; csrrc x0, mscratch, x1; csrrs x6, minstret, x0
+        let mut cpu = cpu_with(&[
+            0x3400_9173,
+            0x3400_21f3,
+            0x3402_f273,
+            0x3401_62f3,
+            0x3400_b073,
+            0xb020_2373,
+        ]);
+        cpu.registers[1] = 0xff;
+        cpu.run(Some(6)).unwrap();
+        assert_eq!(cpu.registers[2], 0);
+        assert_eq!(cpu.registers[3], 0xff);
+        assert_eq!(cpu.registers[4], 0xff);
+        assert_eq!(cpu.registers[5], 0xfa);
+        assert_eq!(cpu.csrs.read(csr::MSCRATCH), Some(0));
+        assert_eq!(cpu.registers[6], 5);
+    }
+
+    #[test]
+    fn test_CSR_write_suppression() {
+        // csrrs x1, mhartid, x0 reads a read-only CSR without writing it
+        let mut cpu = cpu_with(&[0xf140_20f3]);
+        assert_eq!(cpu.step(), Ok(()));
+
+        // csrrsi x1, mhartid, 1 and csrrw x0, mhartid, x0 attempt a write
+        let mut cpu = cpu_with(&[0xf140_e0f3, 0xf140_1073]);
+        assert_eq!(cpu.step(), Err(Exception::IllegalInstruction(0xf140_e0f3)));
+        cpu.set_pc(4);
+        assert_eq!(cpu.step(), Err(Exception::IllegalInstruction(0xf140_1073)));
+
+        // csrrs x1, 0x7ff, x0 accesses an unimplemented CSR
+        let mut cpu = cpu_with(&[0x7ff0_20f3]);
+        assert_eq!(cpu.step(), Err(Exception::IllegalInstruction(0x7ff0_20f3)));
+        assert_eq!(cpu.registers[1], 0);
+    }
+
+    #[test]
+    fn test_misaligned_jump_target() {
+        // jalr x1, 2(x0)
+        let mut cpu = cpu_with(&[0x0020_00e7]);
+        assert_eq!(cpu.step(), Err(Exception::InstructionAddressMisaligned(2)));
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.pc(), 0);
+    }
+}
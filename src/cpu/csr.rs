@@ -0,0 +1,756 @@
+use crate::cpu::defs::Privilege;
+
+/// CSR addresses
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+pub const MCONFIGPTR: u32 = 0xf15;
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MCOUNTINHIBIT: u32 = 0x320;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+
+/// mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+pub const MSTATUS_SXL: u64 = 0b11 << 34;
+
+/// mcause bit distinguishing interrupts from exceptions
+pub const MCAUSE_INTERRUPT: u64 = 1 << 63;
+
+/// mie/mip bits
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+/// satp MODE field values
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+
+/// misa for RV64IMSU: MXL = 2 (64-bit) with the I and M extension bits and
+/// the S and U mode bits
+const MISA_RV64IMSU: u64 =
+    2 << 62 | 1 << (b'I' - b'A') | 1 << (b'M' - b'A') | 1 << (b'S' - b'A') | 1 << (b'U' - b'A');
+
+/// Implemented mstatus bits that software may change
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+/// mstatus bits visible through sstatus
+const SSTATUS_MASK: u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_UXL;
+
+/// Implemented interrupt-enable bits
+const MIE_WRITABLE: u64 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+
+/// mip bits machine-mode software may change; the others follow the
+/// interrupt sources
+const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Interrupts that may be delegated to S-mode
+const MIDELEG_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Exceptions that may be delegated to S-mode: everything but ECALL from
+/// M-mode (11) and the reserved codes 10 and 14
+const MEDELEG_WRITABLE: u64 = 0xb3ff;
+
+/// Assembler name of a standard CSR, or None if the address has none
+pub fn name(addr: u32) -> Option<String> {
+    let name = match addr {
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        0xc03..=0xc1f => return Some(format!("hpmcounter{}", addr - 0xc00)),
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        0x10a => "senvcfg",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        MCONFIGPTR => "mconfigptr",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        0x30a => "menvcfg",
+        MCOUNTINHIBIT => "mcountinhibit",
+        0x323..=0x33f => return Some(format!("mhpmevent{}", addr - 0x320)),
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        0x34a => "mtinst",
+        0x34b => "mtval2",
+        0x3a0..=0x3af => return Some(format!("pmpcfg{}", addr - 0x3a0)),
+        0x3b0..=0x3ef => return Some(format!("pmpaddr{}", addr - 0x3b0)),
+        0x7a0 => "tselect",
+        0x7a1 => "tdata1",
+        0x7a2 => "tdata2",
+        0x7a3 => "tdata3",
+        0x7b0 => "dcsr",
+        0x7b1 => "dpc",
+        0x7b2 => "dscratch0",
+        0x7b3 => "dscratch1",
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        0xb03..=0xb1f => return Some(format!("mhpmcounter{}", addr - 0xb00)),
+        _ => return None,
+    };
+    Some(String::from(name))
+}
+
+/// Address of the standard CSR named `name`
+pub fn address(name: &str) -> Option<u32> {
+    (0..1 << 12).find(|&addr| self::name(addr).is_some_and(|n| n == name))
+}
+
+/// Control and status registers of a hart, and its privilege mode
+///
+/// Every implemented CSR is stored in a flat table indexed by address, and
+/// `read`/`write` apply each CSR's field behaviour on top: read-only CSRs
+/// (address bits [11:10] = 0b11) reject writes, WARL fields keep only legal
+/// values, the supervisor CSRs sstatus, sie and sip are views of their
+/// machine-mode counterparts, and unimplemented CSRs reject every access.
+/// `read`/`write` are host accesses; instructions must also pass
+/// `accessible` at the current privilege. Counters advance through
+/// `retire`.
+pub struct CsrFile {
+    csrs: Vec<u64>,
+    privilege: Privilege,
+    /// mip bits asserted by devices, ORed into reads of mip and sip
+    lines: u64,
+    /// Value of the time CSR if a device keeps real time
+    time: Option<u64>,
+}
+
+impl CsrFile {
+    pub fn new() -> CsrFile {
+        let mut csrs = vec![0; 4096];
+        csrs[MISA as usize] = MISA_RV64IMSU;
+        // XLEN is 64 in every mode; MPP starts at M so that firmware can
+        // MRET without setting it
+        csrs[MSTATUS as usize] = MSTATUS_MPP | 2 << 32 | 2 << 34;
+        CsrFile {
+            csrs,
+            privilege: Privilege::Machine,
+            lines: 0,
+            time: None,
+        }
+    }
+
+    /// Current privilege mode of the hart
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.privilege = privilege;
+    }
+
+    /// Set the mip bits driven by interrupt lines (MSIP, MTIP, SEIP and
+    /// MEIP); software cannot clear them through mip
+    pub fn set_lines(&mut self, lines: u64) {
+        self.lines = lines;
+    }
+
+    /// Set the value the time CSR reads, or None for it to follow cycle
+    pub fn set_time(&mut self, time: Option<u64>) {
+        self.time = time;
+    }
+
+    /// Cause code of the interrupt to take before the next instruction, if
+    /// any
+    ///
+    /// An interrupt must be pending in mip and enabled in mie, and the mode
+    /// it traps to (S-mode if delegated in mideleg, else M-mode) must be
+    /// above the current mode, or equal with its xIE bit set. Interrupts
+    /// for M-mode go first, each group in the order MEI, MSI, MTI, SEI,
+    /// SSI, STI.
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        let pending = (self.csrs[MIP as usize] | self.lines) & self.csrs[MIE as usize];
+        if pending == 0 {
+            return None;
+        }
+        let mideleg = self.csrs[MIDELEG as usize];
+        let status = self.csrs[MSTATUS as usize];
+        let machine = match self.privilege {
+            Privilege::Machine => status & MSTATUS_MIE != 0,
+            _ => true,
+        };
+        let supervisor = match self.privilege {
+            Privilege::Machine => false,
+            Privilege::Supervisor => status & MSTATUS_SIE != 0,
+            Privilege::User => true,
+        };
+        let groups = [
+            if machine { pending & !mideleg } else { 0 },
+            if supervisor { pending & mideleg } else { 0 },
+        ];
+        groups.into_iter().find_map(|enabled| {
+            [11, 3, 7, 9, 1, 5]
+                .into_iter()
+                .find(|code| enabled >> code & 1 != 0)
+        })
+    }
+
+    /// Whether `addr` names an implemented CSR
+    fn exists(addr: u32) -> bool {
+        matches!(
+            addr,
+            CYCLE
+                | TIME
+                | INSTRET
+                | 0xc03..=0xc1f // hpmcounter3-31
+                | SSTATUS
+                | SIE
+                | STVEC
+                | SCOUNTEREN
+                | SSCRATCH
+                | SEPC
+                | SCAUSE
+                | STVAL
+                | SIP
+                | SATP
+                | MVENDORID
+                | MARCHID
+                | MIMPID
+                | MHARTID
+                | MCONFIGPTR
+                | MSTATUS
+                | MISA
+                | MEDELEG
+                | MIDELEG
+                | MIE
+                | MTVEC
+                | MCOUNTEREN
+                | MCOUNTINHIBIT
+                | 0x323..=0x33f // mhpmevent3-31
+                | MSCRATCH
+                | MEPC
+                | MCAUSE
+                | MTVAL
+                | MIP
+                | MCYCLE
+                | MINSTRET
+                | 0xb03..=0xb1f // mhpmcounter3-31
+        )
+    }
+
+    /// Whether `addr` is in the read-only CSR space
+    pub fn is_read_only(addr: u32) -> bool {
+        addr >> 10 == 0b11
+    }
+
+    /// Whether an instruction at the current privilege may access `addr`:
+    /// the mode must be at least the one encoded in address bits [9:8],
+    /// satp is trapped by mstatus.TVM, and lower modes read the counters
+    /// only where mcounteren (and for U-mode scounteren) allows
+    pub fn accessible(&self, addr: u32) -> bool {
+        let privilege = self.privilege;
+        if (privilege as u32) < (addr >> 8) & 0b11 {
+            return false;
+        }
+        match addr {
+            SATP => {
+                privilege != Privilege::Supervisor || self.csrs[MSTATUS as usize] & MSTATUS_TVM == 0
+            }
+            CYCLE..=0xc1f => {
+                let bit = 1 << (addr - CYCLE);
+                match privilege {
+                    Privilege::Machine => true,
+                    Privilege::Supervisor => self.csrs[MCOUNTEREN as usize] & bit != 0,
+                    Privilege::User => {
+                        self.csrs[MCOUNTEREN as usize] & self.csrs[SCOUNTEREN as usize] & bit != 0
+                    }
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// Read a CSR, or None if it is not implemented
+    pub fn read(&self, addr: u32) -> Option<u64> {
+        if !CsrFile::exists(addr) {
+            return None;
+        }
+        let mideleg = self.csrs[MIDELEG as usize];
+        Some(match addr {
+            CYCLE => self.csrs[MCYCLE as usize],
+            // without a real-time clock, time advances with cycle
+            TIME => self.time.unwrap_or(self.csrs[MCYCLE as usize]),
+            INSTRET => self.csrs[MINSTRET as usize],
+            SSTATUS => self.csrs[MSTATUS as usize] & SSTATUS_MASK,
+            SIE => self.csrs[MIE as usize] & mideleg,
+            MIP => self.csrs[MIP as usize] | self.lines,
+            SIP => (self.csrs[MIP as usize] | self.lines) & mideleg,
+            _ => self.csrs[addr as usize],
+        })
+    }
+
+    /// Write a CSR, or None if it is not implemented or read-only
+    pub fn write(&mut self, addr: u32, value: u64) -> Option<()> {
+        if !CsrFile::exists(addr) || CsrFile::is_read_only(addr) {
+            return None;
+        }
+        let mideleg = self.csrs[MIDELEG as usize];
+        // writes through a view land in the underlying CSR
+        let (target, mask) = match addr {
+            MSTATUS => (MSTATUS, MSTATUS_WRITABLE),
+            SSTATUS => (MSTATUS, SSTATUS_MASK & MSTATUS_WRITABLE),
+            MIE => (MIE, MIE_WRITABLE),
+            SIE => (MIE, mideleg & MIE_WRITABLE),
+            MIP => (MIP, MIP_WRITABLE),
+            SIP => (MIP, mideleg & MIP_SSIP),
+            MIDELEG => (MIDELEG, MIDELEG_WRITABLE),
+            MEDELEG => (MEDELEG, MEDELEG_WRITABLE),
+            MCOUNTEREN | SCOUNTEREN => (addr, 0xffff_ffff),
+            _ => (addr, u64::MAX),
+        };
+        let old = self.csrs[target as usize];
+        let value = (old & !mask) | (value & mask);
+        self.csrs[target as usize] = match addr {
+            // WARL: the extension set is fixed
+            MISA => old,
+            // WARL: MPP = 2 is reserved; keep the previous mode
+            MSTATUS if value & MSTATUS_MPP == 2 << 11 => {
+                (value & !MSTATUS_MPP) | (old & MSTATUS_MPP)
+            }
+            // WARL: only direct (0) and vectored (1) modes are legal;
+            // reserved modes leave the register unchanged
+            MTVEC | STVEC if value & 0b11 >= 2 => old,
+            // WARL: IALIGN is 32, so the low two bits read as zero
+            MEPC | SEPC => value & !0b11,
+            // WARL: writes selecting an unsupported translation mode have
+            // no effect
+            SATP if !matches!(
+                value >> 60,
+                SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48
+            ) =>
+            {
+                old
+            }
+            // WARL: the performance-monitoring counters and events are not
+            // implemented and read as zero
+            MCOUNTINHIBIT | 0x323..=0x33f | 0xb03..=0xb1f => 0,
+            _ => value,
+        };
+        Some(())
+    }
+
+    /// Take a trap at `pc` and return the address of the handler
+    ///
+    /// `cause` is the mcause value, with `MCAUSE_INTERRUPT` set for
+    /// interrupts. Traps from S- and U-mode whose bit is set in medeleg (or
+    /// mideleg for interrupts) go to S-mode through stvec, all others to
+    /// M-mode through mtvec. In vectored mode interrupts go to
+    /// BASE + 4 * code; exceptions always go to BASE.
+    pub fn trap(&mut self, pc: u64, cause: u64, tval: u64) -> u64 {
+        let interrupt = cause & MCAUSE_INTERRUPT != 0;
+        let code = cause & !MCAUSE_INTERRUPT;
+        let delegation = self.csrs[if interrupt { MIDELEG } else { MEDELEG } as usize];
+        let status = self.csrs[MSTATUS as usize];
+        let previous = self.privilege;
+
+        let tvec = if previous != Privilege::Machine && delegation >> code & 1 != 0 {
+            let spie = if status & MSTATUS_SIE != 0 {
+                MSTATUS_SPIE
+            } else {
+                0
+            };
+            let spp = if previous == Privilege::Supervisor {
+                MSTATUS_SPP
+            } else {
+                0
+            };
+            self.csrs[MSTATUS as usize] =
+                (status & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | spp;
+            self.csrs[SEPC as usize] = pc & !0b11;
+            self.csrs[SCAUSE as usize] = cause;
+            self.csrs[STVAL as usize] = tval;
+            self.privilege = Privilege::Supervisor;
+            self.csrs[STVEC as usize]
+        } else {
+            let mpie = if status & MSTATUS_MIE != 0 {
+                MSTATUS_MPIE
+            } else {
+                0
+            };
+            self.csrs[MSTATUS as usize] = (status & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP))
+                | mpie
+                | (previous as u64) << 11;
+            self.csrs[MEPC as usize] = pc & !0b11;
+            self.csrs[MCAUSE as usize] = cause;
+            self.csrs[MTVAL as usize] = tval;
+            self.privilege = Privilege::Machine;
+            self.csrs[MTVEC as usize]
+        };
+
+        let base = tvec & !0b11;
+        if tvec & 0b11 == 1 && interrupt {
+            base.wrapping_add(4 * code)
+        } else {
+            base
+        }
+    }
+
+    /// Return from a machine-mode trap (MRET) and return the address to
+    /// resume at
+    pub fn mret(&mut self) -> u64 {
+        let status = self.csrs[MSTATUS as usize];
+        let privilege = Privilege::from_u64((status & MSTATUS_MPP) >> 11).unwrap();
+        let mie = if status & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        let mut status = (status & !(MSTATUS_MIE | MSTATUS_MPP)) | mie | MSTATUS_MPIE;
+        if privilege != Privilege::Machine {
+            status &= !MSTATUS_MPRV;
+        }
+        self.csrs[MSTATUS as usize] = status;
+        self.privilege = privilege;
+        self.csrs[MEPC as usize]
+    }
+
+    /// Return from a supervisor-mode trap (SRET) and return the address to
+    /// resume at
+    pub fn sret(&mut self) -> u64 {
+        let status = self.csrs[MSTATUS as usize];
+        let privilege = if status & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        let sie = if status & MSTATUS_SPIE != 0 {
+            MSTATUS_SIE
+        } else {
+            0
+        };
+        self.csrs[MSTATUS as usize] =
+            (status & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | sie | MSTATUS_SPIE;
+        self.privilege = privilege;
+        self.csrs[SEPC as usize]
+    }
+
+    /// Advance the cycle and instret counters for a retired instruction
+    pub fn retire(&mut self) {
+        self.csrs[MCYCLE as usize] = self.csrs[MCYCLE as usize].wrapping_add(1);
+        self.csrs[MINSTRET as usize] = self.csrs[MINSTRET as usize].wrapping_add(1);
+    }
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        CsrFile::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::csr::*;
+
+    /// mstatus at reset
+    const RESET: u64 = MSTATUS_MPP | MSTATUS_UXL & 2 << 32 | MSTATUS_SXL & 2 << 34;
+
+    #[test]
+    fn test_read_only_csrs() {
+        let mut csrs = CsrFile::new();
+        assert_eq!(csrs.read(MHARTID), Some(0));
+        assert_eq!(csrs.write(MHARTID, 1), None);
+        assert_eq!(csrs.write(CYCLE, 1), None);
+        assert_eq!(csrs.read(0x7ff), None);
+        assert_eq!(csrs.write(0x7ff, 1), None);
+    }
+
+    #[test]
+    fn test_warl_fields() {
+        let mut csrs = CsrFile::new();
+        csrs.write(MISA, 0).unwrap();
+        assert_eq!(csrs.read(MISA), Some(MISA_RV64IMSU));
+
+        csrs.write(MSTATUS, u64::MAX).unwrap();
+        assert_eq!(csrs.read(MSTATUS), Some(RESET | MSTATUS_WRITABLE));
+        csrs.write(MSTATUS, 0).unwrap();
+        assert_eq!(csrs.read(MSTATUS), Some(RESET & !MSTATUS_MPP));
+        csrs.write(MSTATUS, 2 << 11 | MSTATUS_MIE).unwrap();
+        assert_eq!(csrs.read(MSTATUS), Some(RESET & !MSTATUS_MPP | MSTATUS_MIE));
+
+        csrs.write(MTVEC, 0x8000_0001).unwrap();
+        csrs.write(MTVEC, 0x9000_0002).unwrap();
+        assert_eq!(csrs.read(MTVEC), Some(0x8000_0001));
+
+        csrs.write(MEPC, 0x8000_0007).unwrap();
+        assert_eq!(csrs.read(MEPC), Some(0x8000_0004));
+        csrs.write(SEPC, 0x8000_0007).unwrap();
+        assert_eq!(csrs.read(SEPC), Some(0x8000_0004));
+
+        csrs.write(MIE, u64::MAX).unwrap();
+        assert_eq!(csrs.read(MIE), Some(0xaaa));
+        csrs.write(MIP, u64::MAX).unwrap();
+        assert_eq!(csrs.read(MIP), Some(MIP_SSIP | MIP_STIP | MIP_SEIP));
+
+        csrs.write(MEDELEG, u64::MAX).unwrap();
+        assert_eq!(csrs.read(MEDELEG), Some(0xb3ff));
+        csrs.write(MIDELEG, u64::MAX).unwrap();
+        assert_eq!(csrs.read(MIDELEG), Some(0x222));
+
+        csrs.write(SATP, 10 << 60 | 0x1234).unwrap();
+        assert_eq!(csrs.read(SATP), Some(0));
+        csrs.write(SATP, SATP_MODE_SV39 << 60 | 0x1234).unwrap();
+        assert_eq!(csrs.read(SATP), Some(8 << 60 | 0x1234));
+        csrs.write(SATP, 10 << 60).unwrap();
+        assert_eq!(csrs.read(SATP), Some(8 << 60 | 0x1234));
+
+        csrs.write(0xb03, 5).unwrap();
+        assert_eq!(csrs.read(0xb03), Some(0));
+    }
+
+    #[test]
+    fn test_supervisor_views() {
+        let mut csrs = CsrFile::new();
+        csrs.write(SSTATUS, u64::MAX).unwrap();
+        assert_eq!(
+            csrs.read(MSTATUS),
+            Some(RESET | MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR)
+        );
+        assert_eq!(csrs.read(SSTATUS).unwrap() & MSTATUS_MPP, 0);
+        assert_eq!(csrs.read(SSTATUS).unwrap() & MSTATUS_UXL, 2 << 32);
+
+        // sie and sip only show delegated interrupts
+        csrs.write(MIE, MIP_STIP | MIP_MTIP).unwrap();
+        csrs.write(MIP, MIP_SSIP | MIP_STIP).unwrap();
+        assert_eq!(csrs.read(SIE), Some(0));
+        assert_eq!(csrs.write(SIP, 0), Some(()));
+        assert_eq!(csrs.read(MIP), Some(MIP_SSIP | MIP_STIP));
+        csrs.write(MIDELEG, MIP_SSIP | MIP_STIP).unwrap();
+        assert_eq!(csrs.read(SIE), Some(MIP_STIP));
+        assert_eq!(csrs.read(SIP), Some(MIP_SSIP | MIP_STIP));
+        // S-mode may only clear SSIP
+        csrs.write(SIP, 0).unwrap();
+        assert_eq!(csrs.read(MIP), Some(MIP_STIP));
+        csrs.write(SIE, 0).unwrap();
+        assert_eq!(csrs.read(MIE), Some(MIP_MTIP));
+    }
+
+    #[test]
+    fn test_privilege_checks() {
+        let mut csrs = CsrFile::new();
+        csrs.set_privilege(Privilege::Supervisor);
+        assert!(!csrs.accessible(MSTATUS));
+        assert!(csrs.accessible(SSTATUS));
+        assert!(csrs.accessible(SATP));
+        assert!(!csrs.accessible(CYCLE));
+        csrs.set_privilege(Privilege::User);
+        assert!(!csrs.accessible(SSTATUS));
+        assert!(!csrs.accessible(CYCLE));
+
+        csrs.write(MCOUNTEREN, 0b101).unwrap();
+        assert!(!csrs.accessible(CYCLE));
+        csrs.write(SCOUNTEREN, 0b001).unwrap();
+        assert!(csrs.accessible(CYCLE));
+        assert!(!csrs.accessible(INSTRET));
+        csrs.set_privilege(Privilege::Supervisor);
+        assert!(csrs.accessible(INSTRET));
+        assert!(!csrs.accessible(TIME));
+
+        csrs.write(MSTATUS, MSTATUS_TVM).unwrap();
+        assert!(!csrs.accessible(SATP));
+        csrs.set_privilege(Privilege::Machine);
+        assert!(csrs.accessible(SATP));
+        assert!(csrs.accessible(MHARTID));
+    }
+
+    #[test]
+    fn test_trap_and_mret() {
+        let mut csrs = CsrFile::new();
+        csrs.write(MSTATUS, MSTATUS_MIE | MSTATUS_MPP).unwrap();
+        csrs.write(MTVEC, 0x8000_0001).unwrap();
+
+        // exceptions ignore vectored mode
+        assert_eq!(csrs.trap(0x1000, 2, 0xdead), 0x8000_0000);
+        assert_eq!(csrs.read(MEPC), Some(0x1000));
+        assert_eq!(csrs.read(MCAUSE), Some(2));
+        assert_eq!(csrs.read(MTVAL), Some(0xdead));
+        assert_eq!(csrs.read(MSTATUS), Some(RESET | MSTATUS_MPIE));
+
+        // MRET returns to M and leaves MPP at U
+        assert_eq!(csrs.mret(), 0x1000);
+        assert_eq!(csrs.privilege(), Privilege::Machine);
+        assert_eq!(
+            csrs.read(MSTATUS),
+            Some(RESET & !MSTATUS_MPP | MSTATUS_MIE | MSTATUS_MPIE)
+        );
+
+        assert_eq!(csrs.trap(0x2000, MCAUSE_INTERRUPT | 7, 0), 0x8000_001c);
+        csrs.write(MTVEC, 0x8000_0000).unwrap();
+        assert_eq!(csrs.trap(0x2000, MCAUSE_INTERRUPT | 7, 0), 0x8000_0000);
+        // MIE was clear on the second trap, so MRET leaves it clear
+        csrs.mret();
+        assert_eq!(csrs.read(MSTATUS).unwrap() & MSTATUS_MIE, 0);
+    }
+
+    #[test]
+    fn test_delegation_and_sret() {
+        let mut csrs = CsrFile::new();
+        csrs.write(MTVEC, 0x100).unwrap();
+        csrs.write(STVEC, 0x200).unwrap();
+        csrs.write(MEDELEG, 1 << 8 | 1 << 2).unwrap();
+        csrs.write(MSTATUS, MSTATUS_SIE | MSTATUS_MPRV).unwrap();
+
+        // delegated exceptions from U go to S
+        csrs.set_privilege(Privilege::User);
+        assert_eq!(csrs.trap(0x1000, 8, 0), 0x200);
+        assert_eq!(csrs.privilege(), Privilege::Supervisor);
+        assert_eq!(csrs.read(SEPC), Some(0x1000));
+        assert_eq!(csrs.read(SCAUSE), Some(8));
+        let status = csrs.read(MSTATUS).unwrap();
+        assert_eq!(
+            status & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP),
+            MSTATUS_SPIE
+        );
+
+        // undelegated ones go to M, recording S in MPP
+        assert_eq!(csrs.trap(0x2000, 9, 0), 0x100);
+        assert_eq!(csrs.privilege(), Privilege::Machine);
+        assert_eq!(csrs.read(MSTATUS).unwrap() & MSTATUS_MPP, 1 << 11);
+        // M-mode traps are never delegated
+        assert_eq!(csrs.trap(0x3000, 2, 0), 0x100);
+        assert_eq!(csrs.read(MSTATUS).unwrap() & MSTATUS_MPP, 3 << 11);
+
+        csrs.write(
+            MSTATUS,
+            (csrs.read(MSTATUS).unwrap() & !MSTATUS_MPP) | 1 << 11,
+        )
+        .unwrap();
+        assert_eq!(csrs.mret(), 0x3000);
+        assert_eq!(csrs.privilege(), Privilege::Supervisor);
+        let status = csrs.read(MSTATUS).unwrap();
+        assert_eq!(status & (MSTATUS_MPRV | MSTATUS_MPP), 0);
+
+        assert_eq!(csrs.sret(), 0x1000);
+        assert_eq!(csrs.privilege(), Privilege::User);
+        // SIE was set when the trap was taken
+        let status = csrs.read(MSTATUS).unwrap();
+        assert_eq!(
+            status & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP),
+            MSTATUS_SIE | MSTATUS_SPIE
+        );
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut csrs = CsrFile::new();
+        csrs.retire();
+        csrs.retire();
+        assert_eq!(csrs.read(CYCLE), Some(2));
+        assert_eq!(csrs.read(INSTRET), Some(2));
+        csrs.write(MINSTRET, 100).unwrap();
+        csrs.retire();
+        assert_eq!(csrs.read(INSTRET), Some(101));
+        assert_eq!(csrs.read(MCYCLE), Some(3));
+        assert_eq!(csrs.read(TIME), Some(3));
+        csrs.set_time(Some(42));
+        assert_eq!(csrs.read(TIME), Some(42));
+    }
+
+    #[test]
+    fn test_interrupt_priority_and_enables() {
+        let mut csrs = CsrFile::new();
+        csrs.set_lines(MIP_MTIP | MIP_MSIP);
+        csrs.write(MIP, MIP_SSIP).unwrap();
+        assert_eq!(csrs.read(MIP), Some(MIP_MTIP | MIP_MSIP | MIP_SSIP));
+        // device lines cannot be cleared by software
+        csrs.write(MIP, 0).unwrap();
+        assert_eq!(csrs.read(MIP), Some(MIP_MTIP | MIP_MSIP));
+
+        // nothing is taken until mie and mstatus.MIE enable it in M-mode
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.write(MIE, MIP_MTIP | MIP_MSIP | MIP_SSIP).unwrap();
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.write(MSTATUS, MSTATUS_MIE).unwrap();
+        assert_eq!(csrs.pending_interrupt(), Some(3));
+        csrs.set_lines(MIP_MTIP);
+        assert_eq!(csrs.pending_interrupt(), Some(7));
+
+        // delegated interrupts are never taken in M-mode, always below S
+        csrs.set_lines(0);
+        csrs.write(MIDELEG, MIP_SSIP).unwrap();
+        csrs.write(MIP, MIP_SSIP).unwrap();
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.set_privilege(Privilege::Supervisor);
+        csrs.write(MSTATUS, 0).unwrap();
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.write(MSTATUS, MSTATUS_SIE).unwrap();
+        assert_eq!(csrs.pending_interrupt(), Some(1));
+        csrs.set_privilege(Privilege::User);
+        csrs.write(MSTATUS, 0).unwrap();
+        assert_eq!(csrs.pending_interrupt(), Some(1));
+        // M-mode interrupts go before S-mode ones, even below M
+        csrs.set_lines(MIP_MTIP);
+        assert_eq!(csrs.pending_interrupt(), Some(7));
+        assert_eq!(csrs.read(SIP), Some(MIP_SSIP));
+    }
+}
@@ -1,12 +1,18 @@
 use crate::cpu::defs::*;
 
-/// Decode a 32-bit RISC-V (FIXME: RV64I + ???) Instruction
-pub fn decode(instr: u32) -> Option<DecodedInstr> {
+/// Sign-extend the low `bits` bits of an immediate field
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as i64
+}
+
+/// Decode a 32-bit RISC-V (RV64IM) Instruction
+pub fn decode(instr: u32) -> Result<DecodedInstr, DecodeError> {
     match OPCODE::from_u32(instr & 0b111_1111) {
         Some(OPCODE::LUI) => {
             let rd: u32 = (instr >> 7) & 0b1_1111;
             let imm: u32 = (instr >> 12) << 12;
-            Some(DecodedInstr {
+            Ok(DecodedInstr {
                 format: FORMAT::U,
                 mnemonic: MNEMONIC::LUI,
                 opcode: OPCODE::LUI,
@@ -15,14 +21,16 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                 rd: REG::from_u32(rd),
                 rs1: None,
                 rs2: None,
-                imm: Some(imm as u64),
+                imm: Some(imm as i32 as i64),
+                shamt: None,
+                csr: None,
             })
         }
 
         Some(OPCODE::AUIPC) => {
             let rd: u32 = (instr >> 7) & 0b1_1111;
             let imm: u32 = (instr >> 12) << 12;
-            Some(DecodedInstr {
+            Ok(DecodedInstr {
                 format: FORMAT::U,
                 mnemonic: MNEMONIC::AUIPC,
                 opcode: OPCODE::AUIPC,
@@ -31,7 +39,9 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                 rd: REG::from_u32(rd),
                 rs1: None,
                 rs2: None,
-                imm: Some(imm as u64),
+                imm: Some(imm as i32 as i64),
+                shamt: None,
+                csr: None,
             })
         }
 
@@ -41,7 +51,7 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                 | ((instr >> 9) & 0b1000_0000_0000) // imm[11]
                 | (instr & 0b1111_1111_0000_0000_0000) // imm[19:12]
                 | ((instr >> 11) & 0b1_0000_0000_0000_0000_0000); // imm[20]
-            Some(DecodedInstr {
+            Ok(DecodedInstr {
                 format: FORMAT::J,
                 mnemonic: MNEMONIC::JAL,
                 opcode: OPCODE::JAL,
@@ -50,7 +60,9 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                 rd: REG::from_u32(rd),
                 rs1: None,
                 rs2: None,
-                imm: Some(imm as u64),
+                imm: Some(sign_extend(imm, 21)),
+                shamt: None,
+                csr: None,
             })
         }
 
@@ -61,7 +73,7 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
             let funct3: u32 = (instr >> 12) & 0b111;
 
             if funct3 == 0b000 {
-                Some(DecodedInstr {
+                Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::JALR,
                     opcode: OPCODE::JALR,
@@ -70,14 +82,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 })
             } else {
-                dbg!(format!(
-                    "decode: unknown funct3 {:#03b} for JALR operation",
-                    funct3
-                ));
-                None
+                Err(DecodeError::ReservedFunct3(instr))
             }
         }
 
@@ -90,7 +100,7 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                 | ((instr << 4) & 0b1000_0000_0000) // imm[11]
                 | ((instr >> 19) & 0b1_0000_0000_0000); // imm[12]
             match funct3 {
-                0b000 => Some(DecodedInstr {
+                0b000 => Ok(DecodedInstr {
                     format: FORMAT::B,
                     mnemonic: MNEMONIC::BEQ,
                     opcode: OPCODE::BRANCH,
@@ -99,10 +109,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 13)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b001 => Some(DecodedInstr {
+                0b001 => Ok(DecodedInstr {
                     format: FORMAT::B,
                     mnemonic: MNEMONIC::BNE,
                     opcode: OPCODE::BRANCH,
@@ -111,10 +123,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 13)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b100 => Some(DecodedInstr {
+                0b100 => Ok(DecodedInstr {
                     format: FORMAT::B,
                     mnemonic: MNEMONIC::BLT,
                     opcode: OPCODE::BRANCH,
@@ -123,10 +137,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 13)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b101 => Some(DecodedInstr {
+                0b101 => Ok(DecodedInstr {
                     format: FORMAT::B,
                     mnemonic: MNEMONIC::BGE,
                     opcode: OPCODE::BRANCH,
@@ -135,10 +151,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 13)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b110 => Some(DecodedInstr {
+                0b110 => Ok(DecodedInstr {
                     format: FORMAT::B,
                     mnemonic: MNEMONIC::BLTU,
                     opcode: OPCODE::BRANCH,
@@ -147,10 +165,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 13)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b111 => Some(DecodedInstr {
+                0b111 => Ok(DecodedInstr {
                     format: FORMAT::B,
                     mnemonic: MNEMONIC::BGEU,
                     opcode: OPCODE::BRANCH,
@@ -159,16 +179,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 13)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                _ => {
-                    dbg!(format!(
-                        "decode: unknown funct3 {:#03b} for BRANCH operation",
-                        funct3
-                    ));
-                    None
-                }
+                _ => Err(DecodeError::ReservedFunct3(instr)),
             }
         }
 
@@ -178,7 +194,7 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
             let rs1: u32 = (instr >> 15) & 0b1_1111;
             let imm: u32 = (instr >> 20) & 0b1111_1111_1111;
             match funct3 {
-                0b000 => Some(DecodedInstr {
+                0b000 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::LB,
                     opcode: OPCODE::LOAD,
@@ -187,10 +203,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b001 => Some(DecodedInstr {
+                0b001 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::LH,
                     opcode: OPCODE::LOAD,
@@ -199,10 +217,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b010 => Some(DecodedInstr {
+                0b010 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::LW,
                     opcode: OPCODE::LOAD,
@@ -211,10 +231,26 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
+                }),
+
+                0b011 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::LD,
+                    opcode: OPCODE::LOAD,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b100 => Some(DecodedInstr {
+                0b100 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::LBU,
                     opcode: OPCODE::LOAD,
@@ -223,10 +259,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b101 => Some(DecodedInstr {
+                0b101 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::LHU,
                     opcode: OPCODE::LOAD,
@@ -235,16 +273,26 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                _ => {
-                    dbg!(format!(
-                        "decode: unknown funct3 {:#03b} for LOAD operation",
-                        funct3
-                    ));
-                    None
-                }
+                0b110 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::LWU,
+                    opcode: OPCODE::LOAD,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
+                }),
+
+                _ => Err(DecodeError::ReservedFunct3(instr)),
             }
         }
 
@@ -255,7 +303,7 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
             let imm: u32 = ((instr >> 7) & 0b1_1111) // imm[4:0]
             | ((instr >> 20) & 0b1111_1110_0000); // imm[11:5]
             match funct3 {
-                0b000 => Some(DecodedInstr {
+                0b000 => Ok(DecodedInstr {
                     format: FORMAT::S,
                     mnemonic: MNEMONIC::SB,
                     opcode: OPCODE::STORE,
@@ -264,10 +312,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b001 => Some(DecodedInstr {
+                0b001 => Ok(DecodedInstr {
                     format: FORMAT::S,
                     mnemonic: MNEMONIC::SH,
                     opcode: OPCODE::STORE,
@@ -276,10 +326,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b010 => Some(DecodedInstr {
+                0b010 => Ok(DecodedInstr {
                     format: FORMAT::S,
                     mnemonic: MNEMONIC::SW,
                     opcode: OPCODE::STORE,
@@ -288,16 +340,26 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: None,
                     rs1: REG::from_u32(rs1),
                     rs2: REG::from_u32(rs2),
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                _ => {
-                    dbg!(format!(
-                        "decode: unknown funct3 {:#03b} for STORE operation",
-                        funct3
-                    ));
-                    None
-                }
+                0b011 => Ok(DecodedInstr {
+                    format: FORMAT::S,
+                    mnemonic: MNEMONIC::SD,
+                    opcode: OPCODE::STORE,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: None,
+                    rs1: REG::from_u32(rs1),
+                    rs2: REG::from_u32(rs2),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
+                }),
+
+                _ => Err(DecodeError::ReservedFunct3(instr)),
             }
         }
 
@@ -306,8 +368,10 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
             let funct3: u32 = (instr >> 12) & 0b111;
             let rs1: u32 = (instr >> 15) & 0b1_1111;
             let imm: u32 = (instr >> 20) & 0b1111_1111_1111;
+            let shamt: u32 = (instr >> 20) & 0b11_1111;
+            let funct6: u32 = (instr >> 26) & 0b11_1111;
             match funct3 {
-                0b000 => Some(DecodedInstr {
+                0b000 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::ADDI,
                     opcode: OPCODE::OP_IMM,
@@ -316,10 +380,30 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b010 => Some(DecodedInstr {
+                0b001 => match funct6 {
+                    0b00_0000 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::SLLI,
+                        opcode: OPCODE::OP_IMM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: None,
+                        imm: None,
+                        shamt: Some(shamt),
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b010 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::SLTI,
                     opcode: OPCODE::OP_IMM,
@@ -328,10 +412,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b011 => Some(DecodedInstr {
+                0b011 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::SLTIU,
                     opcode: OPCODE::OP_IMM,
@@ -340,10 +426,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b100 => Some(DecodedInstr {
+                0b100 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::XORI,
                     opcode: OPCODE::OP_IMM,
@@ -352,10 +440,44 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                0b110 => Some(DecodedInstr {
+                0b101 => match funct6 {
+                    0b00_0000 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::SRLI,
+                        opcode: OPCODE::OP_IMM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: None,
+                        imm: None,
+                        shamt: Some(shamt),
+                        csr: None,
+                    }),
+
+                    0b01_0000 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::SRAI,
+                        opcode: OPCODE::OP_IMM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: None,
+                        imm: None,
+                        shamt: Some(shamt),
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b110 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::ORI,
                     opcode: OPCODE::OP_IMM,
@@ -366,10 +488,14 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rs1: REG::from_u32(rs1),
                     rs2: None,
 
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+
+                    shamt: None,
+
+                    csr: None,
                 }),
 
-                0b111 => Some(DecodedInstr {
+                0b111 => Ok(DecodedInstr {
                     format: FORMAT::I,
                     mnemonic: MNEMONIC::ANDI,
                     opcode: OPCODE::OP_IMM,
@@ -378,16 +504,12 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                     rd: REG::from_u32(rd),
                     rs1: REG::from_u32(rs1),
                     rs2: None,
-                    imm: Some(imm as u64),
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
                 }),
 
-                _ => {
-                    dbg!(format!(
-                        "decode: unknown funct3 {:#03b} for OP_IMM operation",
-                        funct3
-                    ));
-                    None
-                }
+                _ => Err(DecodeError::ReservedFunct3(instr)),
             }
         }
 
@@ -399,7 +521,7 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
             let funct7: u32 = (instr >> 25) & 0b111_1111;
             match funct3 {
                 0b000 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::ADD,
                         opcode: OPCODE::OP,
@@ -409,9 +531,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::MUL,
                         opcode: OPCODE::OP,
@@ -421,9 +545,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b010_0000 => Some(DecodedInstr {
+                    0b010_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::SUB,
                         opcode: OPCODE::OP,
@@ -433,19 +559,15 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
                 0b001 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::SLL,
                         opcode: OPCODE::OP,
@@ -455,9 +577,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::MULH,
                         opcode: OPCODE::OP,
@@ -467,19 +591,15 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
                 0b010 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::SLT,
                         opcode: OPCODE::OP,
@@ -489,9 +609,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::MULHSU,
                         opcode: OPCODE::OP,
@@ -501,19 +623,15 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
                 0b011 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::SLTU,
                         opcode: OPCODE::OP,
@@ -523,9 +641,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::MULHU,
                         opcode: OPCODE::OP,
@@ -535,19 +655,15 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
                 0b100 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::XOR,
                         opcode: OPCODE::OP,
@@ -557,9 +673,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::DIV,
                         opcode: OPCODE::OP,
@@ -569,19 +687,15 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
                 0b101 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::SRL,
                         opcode: OPCODE::OP,
@@ -591,9 +705,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::DIVU,
                         opcode: OPCODE::OP,
@@ -603,9 +719,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b010_0000 => Some(DecodedInstr {
+                    0b010_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::SRA,
                         opcode: OPCODE::OP,
@@ -615,19 +733,15 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
                 0b110 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::OR,
                         opcode: OPCODE::OP,
@@ -637,9 +751,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::REM,
                         opcode: OPCODE::OP,
@@ -649,19 +765,15 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
                 0b111 => match funct7 {
-                    0b000_0000 => Some(DecodedInstr {
+                    0b000_0000 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::AND,
                         opcode: OPCODE::OP,
@@ -671,9 +783,11 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    0b000_0001 => Some(DecodedInstr {
+                    0b000_0001 => Ok(DecodedInstr {
                         format: FORMAT::R,
                         mnemonic: MNEMONIC::REMU,
                         opcode: OPCODE::OP,
@@ -683,22 +797,503 @@ pub fn decode(instr: u32) -> Option<DecodedInstr> {
                         rs1: REG::from_u32(rs1),
                         rs2: REG::from_u32(rs2),
                         imm: None,
+                        shamt: None,
+                        csr: None,
                     }),
 
-                    _ => {
-                        dbg!(format!(
-                            "decode: unknown funct7 {:#07b} for OP operation with funct3 {:#03b}",
-                            funct7, funct3
-                        ));
-                        None
-                    }
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                _ => Err(DecodeError::ReservedFunct3(instr)),
+            }
+        }
+
+        Some(OPCODE::OP_IMM_32) => {
+            let rd: u32 = (instr >> 7) & 0b1_1111;
+            let funct3: u32 = (instr >> 12) & 0b111;
+            let rs1: u32 = (instr >> 15) & 0b1_1111;
+            let imm: u32 = (instr >> 20) & 0b1111_1111_1111;
+            let shamt: u32 = (instr >> 20) & 0b1_1111;
+            let funct7: u32 = (instr >> 25) & 0b111_1111;
+            match funct3 {
+                0b000 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::ADDIW,
+                    opcode: OPCODE::OP_IMM_32,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: Some(sign_extend(imm, 12)),
+                    shamt: None,
+                    csr: None,
+                }),
+
+                0b001 => match funct7 {
+                    0b000_0000 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::SLLIW,
+                        opcode: OPCODE::OP_IMM_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: None,
+                        imm: None,
+                        shamt: Some(shamt),
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b101 => match funct7 {
+                    0b000_0000 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::SRLIW,
+                        opcode: OPCODE::OP_IMM_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: None,
+                        imm: None,
+                        shamt: Some(shamt),
+                        csr: None,
+                    }),
+
+                    0b010_0000 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::SRAIW,
+                        opcode: OPCODE::OP_IMM_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: None,
+                        imm: None,
+                        shamt: Some(shamt),
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                _ => Err(DecodeError::ReservedFunct3(instr)),
+            }
+        }
+
+        Some(OPCODE::OP_32) => {
+            let rd: u32 = (instr >> 7) & 0b1_1111;
+            let funct3: u32 = (instr >> 12) & 0b111;
+            let rs1: u32 = (instr >> 15) & 0b1_1111;
+            let rs2: u32 = (instr >> 20) & 0b1_1111;
+            let funct7: u32 = (instr >> 25) & 0b111_1111;
+            match funct3 {
+                0b000 => match funct7 {
+                    0b000_0000 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::ADDW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b000_0001 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::MULW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b010_0000 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::SUBW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b001 => match funct7 {
+                    0b000_0000 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::SLLW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b100 => match funct7 {
+                    0b000_0001 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::DIVW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b101 => match funct7 {
+                    0b000_0000 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::SRLW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b000_0001 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::DIVUW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b010_0000 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::SRAW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b110 => match funct7 {
+                    0b000_0001 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::REMW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
+                },
+
+                0b111 => match funct7 {
+                    0b000_0001 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::REMUW,
+                        opcode: OPCODE::OP_32,
+                        funct3: Some(funct3),
+                        funct7: Some(funct7),
+                        rd: REG::from_u32(rd),
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32(rs2),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::ReservedFunct7(instr)),
                 },
 
-                _ => panic!("control should not reach here"),
+                _ => Err(DecodeError::ReservedFunct3(instr)),
+            }
+        }
+
+        Some(OPCODE::MISC_MEM) => {
+            let rd: u32 = (instr >> 7) & 0b1_1111;
+            let funct3: u32 = (instr >> 12) & 0b111;
+            let rs1: u32 = (instr >> 15) & 0b1_1111;
+            let imm: u32 = (instr >> 20) & 0b1111_1111_1111;
+            match funct3 {
+                0b000 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::FENCE,
+                    opcode: OPCODE::MISC_MEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: Some(imm as i64),
+                    shamt: None,
+                    csr: None,
+                }),
+
+                0b001 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::FENCE_I,
+                    opcode: OPCODE::MISC_MEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: Some(imm as i64),
+                    shamt: None,
+                    csr: None,
+                }),
+
+                _ => Err(DecodeError::ReservedFunct3(instr)),
             }
         }
 
-        _ => None,
+        Some(OPCODE::SYSTEM) => {
+            let rd: u32 = (instr >> 7) & 0b1_1111;
+            let funct3: u32 = (instr >> 12) & 0b111;
+            let rs1: u32 = (instr >> 15) & 0b1_1111;
+            let csr: u32 = (instr >> 20) & 0b1111_1111_1111;
+            match funct3 {
+                0b000 => match instr {
+                    0b0000_0000_0000_0000_0000_0000_0111_0011 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::ECALL,
+                        opcode: OPCODE::SYSTEM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: None,
+                        rs1: None,
+                        rs2: None,
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b0000_0000_0001_0000_0000_0000_0111_0011 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::EBREAK,
+                        opcode: OPCODE::SYSTEM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: None,
+                        rs1: None,
+                        rs2: None,
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b0011_0000_0010_0000_0000_0000_0111_0011 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::MRET,
+                        opcode: OPCODE::SYSTEM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: None,
+                        rs1: None,
+                        rs2: None,
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b0001_0000_0010_0000_0000_0000_0111_0011 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::SRET,
+                        opcode: OPCODE::SYSTEM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: None,
+                        rs1: None,
+                        rs2: None,
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    0b0001_0000_0101_0000_0000_0000_0111_0011 => Ok(DecodedInstr {
+                        format: FORMAT::I,
+                        mnemonic: MNEMONIC::WFI,
+                        opcode: OPCODE::SYSTEM,
+                        funct3: Some(funct3),
+                        funct7: None,
+                        rd: None,
+                        rs1: None,
+                        rs2: None,
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ if instr >> 25 == 0b000_1001 && rd == 0 => Ok(DecodedInstr {
+                        format: FORMAT::R,
+                        mnemonic: MNEMONIC::SFENCE_VMA,
+                        opcode: OPCODE::SYSTEM,
+                        funct3: Some(funct3),
+                        funct7: Some(0b000_1001),
+                        rd: None,
+                        rs1: REG::from_u32(rs1),
+                        rs2: REG::from_u32((instr >> 20) & 0b1_1111),
+                        imm: None,
+                        shamt: None,
+                        csr: None,
+                    }),
+
+                    _ => Err(DecodeError::Reserved(instr)),
+                },
+
+                0b001 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::CSRRW,
+                    opcode: OPCODE::SYSTEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: None,
+                    shamt: None,
+                    csr: Some(csr),
+                }),
+
+                0b010 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::CSRRS,
+                    opcode: OPCODE::SYSTEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: None,
+                    shamt: None,
+                    csr: Some(csr),
+                }),
+
+                0b011 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::CSRRC,
+                    opcode: OPCODE::SYSTEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: REG::from_u32(rs1),
+                    rs2: None,
+                    imm: None,
+                    shamt: None,
+                    csr: Some(csr),
+                }),
+
+                0b101 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::CSRRWI,
+                    opcode: OPCODE::SYSTEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: None,
+                    rs2: None,
+                    imm: Some(rs1 as i64),
+                    shamt: None,
+                    csr: Some(csr),
+                }),
+
+                0b110 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::CSRRSI,
+                    opcode: OPCODE::SYSTEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: None,
+                    rs2: None,
+                    imm: Some(rs1 as i64),
+                    shamt: None,
+                    csr: Some(csr),
+                }),
+
+                0b111 => Ok(DecodedInstr {
+                    format: FORMAT::I,
+                    mnemonic: MNEMONIC::CSRRCI,
+                    opcode: OPCODE::SYSTEM,
+                    funct3: Some(funct3),
+                    funct7: None,
+                    rd: REG::from_u32(rd),
+                    rs1: None,
+                    rs2: None,
+                    imm: Some(rs1 as i64),
+                    shamt: None,
+                    csr: Some(csr),
+                }),
+
+                _ => Err(DecodeError::ReservedFunct3(instr)),
+            }
+        }
+
+        None => Err(unknown_opcode(instr)),
+    }
+}
+
+/// Classify a word whose major opcode is not decoded
+fn unknown_opcode(instr: u32) -> DecodeError {
+    if instr & 0b11 != 0b11 {
+        return DecodeError::UnsupportedExtension(instr, "C");
+    }
+    match instr & 0b111_1111 {
+        0b000_0111 | 0b010_0111 | 0b100_0011 | 0b100_0111 | 0b100_1011 | 0b100_1111
+        | 0b101_0011 => DecodeError::UnsupportedExtension(instr, "F/D"),
+        0b010_1111 => DecodeError::UnsupportedExtension(instr, "A"),
+        0b101_0111 => DecodeError::UnsupportedExtension(instr, "V"),
+        _ => DecodeError::UnknownOpcode(instr),
     }
 }
 
@@ -711,6 +1306,15 @@ mod tests {
 
     const ITERS: u32 = 1000;
 
+    /// Interpret the low `bits` bits of `value` as two's complement
+    fn signed(value: u32, bits: u32) -> i64 {
+        if (value >> (bits - 1)) & 1 == 1 {
+            value as i64 - (1 << bits)
+        } else {
+            value as i64
+        }
+    }
+
     #[test]
     fn test_LUI() {
         let mut rng = rand::thread_rng();
@@ -721,7 +1325,7 @@ mod tests {
             let instruction: u32 = imm << 12 | rd << 7 | OPCODE::LUI.to_u32();
 
             // decode and check
-            let instr = decode(instruction).expect("decode returned None");
+            let instr = decode(instruction).expect("decode failed");
             assert_eq!(instr.format, FORMAT::U);
             assert_eq!(instr.mnemonic, MNEMONIC::LUI);
             assert_eq!(instr.opcode, OPCODE::LUI);
@@ -730,7 +1334,7 @@ mod tests {
             assert_eq!(instr.rd, REG::from_u32(rd));
             assert_eq!(instr.rs1, None);
             assert_eq!(instr.rs2, None);
-            assert_eq!(instr.imm, Some((imm as u64) << 12));
+            assert_eq!(instr.imm, Some(signed(imm << 12, 32)));
         }
     }
 
@@ -744,7 +1348,7 @@ mod tests {
             let instruction: u32 = imm << 12 | rd << 7 | OPCODE::AUIPC.to_u32();
 
             // decode and check
-            let instr = decode(instruction).expect("decode returned None");
+            let instr = decode(instruction).expect("decode failed");
             assert_eq!(instr.format, FORMAT::U);
             assert_eq!(instr.mnemonic, MNEMONIC::AUIPC);
             assert_eq!(instr.opcode, OPCODE::AUIPC);
@@ -753,7 +1357,7 @@ mod tests {
             assert_eq!(instr.rd, REG::from_u32(rd));
             assert_eq!(instr.rs1, None);
             assert_eq!(instr.rs2, None);
-            assert_eq!(instr.imm, Some((imm as u64) << 12));
+            assert_eq!(instr.imm, Some(signed(imm << 12, 32)));
         }
     }
 
@@ -767,7 +1371,7 @@ mod tests {
             let instruction: u32 = imm << 12 | rd << 7 | OPCODE::JAL.to_u32();
 
             // decode and check
-            let instr = decode(instruction).expect("decode returned None");
+            let instr = decode(instruction).expect("decode failed");
             assert_eq!(instr.format, FORMAT::J);
             assert_eq!(instr.mnemonic, MNEMONIC::JAL);
             assert_eq!(instr.opcode, OPCODE::JAL);
@@ -778,12 +1382,13 @@ mod tests {
             assert_eq!(instr.rs2, None);
             assert_eq!(
                 instr.imm,
-                Some(
-                    (((imm << 1) & 0b1_0000_0000_0000_0000_0000)
+                Some(signed(
+                    ((imm << 1) & 0b1_0000_0000_0000_0000_0000)
                         | ((imm >> 8) & 0b111_1111_1110)
                         | ((imm << 3) & 0b1000_0000_0000)
-                        | ((imm << 12) & 0b1111_1111_0000_0000_0000)) as u64
-                )
+                        | ((imm << 12) & 0b1111_1111_0000_0000_0000),
+                    21
+                ))
             );
         }
     }
@@ -803,9 +1408,9 @@ mod tests {
                 // decode and check
                 let instr = decode(instruction);
                 if funct3 != 0b000 {
-                    assert_eq!(instr, None);
+                    assert_eq!(instr, Err(DecodeError::ReservedFunct3(instruction)));
                 } else {
-                    let instr = instr.expect("decode returned None");
+                    let instr = instr.expect("decode failed");
                     assert_eq!(instr.format, FORMAT::I);
                     assert_eq!(instr.mnemonic, MNEMONIC::JALR);
                     assert_eq!(instr.opcode, OPCODE::JALR);
@@ -814,7 +1419,7 @@ mod tests {
                     assert_eq!(instr.rd, REG::from_u32(rd));
                     assert_eq!(instr.rs1, REG::from_u32(rs1));
                     assert_eq!(instr.rs2, None);
-                    assert_eq!(instr.imm, Some(imm as u64));
+                    assert_eq!(instr.imm, Some(signed(imm, 12)));
                 }
             }
         }
@@ -840,7 +1445,7 @@ mod tests {
                 // decode and check
                 let instr = decode(instruction);
                 if funct3 == 0b010 || funct3 == 0b011 {
-                    assert_eq!(instr, None);
+                    assert_eq!(instr, Err(DecodeError::ReservedFunct3(instruction)));
                 } else {
                     let instr = instr.unwrap();
                     assert_eq!(instr.format, FORMAT::B);
@@ -861,13 +1466,13 @@ mod tests {
                     assert_eq!(instr.rs2, REG::from_u32(rs2));
                     assert_eq!(
                         instr.imm,
-                        Some(
-                            (((imm2 << 6) & 0b1_0000_0000_0000) // imm[12]
+                        Some(signed(
+                            ((imm2 << 6) & 0b1_0000_0000_0000) // imm[12]
                                 | ((imm2 << 5) & 0b111_1110_0000) // imm[10:5]
                                 | (imm1 & 0b1_1110) // imm[4:1]
-                                | (imm1 << 11) & 0b1000_0000_0000) // imm[11]
-                                as u64
-                        )
+                                | (imm1 << 11) & 0b1000_0000_0000, // imm[11]
+                            13
+                        ))
                     );
                 }
             }
@@ -888,8 +1493,8 @@ mod tests {
 
                 // decode and check
                 let instr = decode(instruction);
-                if funct3 == 0b011 || funct3 == 0b110 || funct3 == 0b111 {
-                    assert_eq!(instr, None);
+                if funct3 == 0b111 {
+                    assert_eq!(instr, Err(DecodeError::ReservedFunct3(instruction)));
                 } else {
                     let instr = instr.unwrap();
                     assert_eq!(instr.format, FORMAT::I);
@@ -897,8 +1502,10 @@ mod tests {
                         0b000 => assert_eq!(instr.mnemonic, MNEMONIC::LB),
                         0b001 => assert_eq!(instr.mnemonic, MNEMONIC::LH),
                         0b010 => assert_eq!(instr.mnemonic, MNEMONIC::LW),
+                        0b011 => assert_eq!(instr.mnemonic, MNEMONIC::LD),
                         0b100 => assert_eq!(instr.mnemonic, MNEMONIC::LBU),
                         0b101 => assert_eq!(instr.mnemonic, MNEMONIC::LHU),
+                        0b110 => assert_eq!(instr.mnemonic, MNEMONIC::LWU),
                         _ => panic!("control should not reach here"),
                     }
                     assert_eq!(instr.opcode, OPCODE::LOAD);
@@ -907,7 +1514,7 @@ mod tests {
                     assert_eq!(instr.rd, REG::from_u32(rd));
                     assert_eq!(instr.rs1, REG::from_u32(rs1));
                     assert_eq!(instr.rs2, None);
-                    assert_eq!(instr.imm, Some(imm as u64));
+                    assert_eq!(instr.imm, Some(signed(imm, 12)));
                 }
             }
         }
@@ -932,13 +1539,8 @@ mod tests {
 
                 // decode and check
                 let instr = decode(instruction);
-                if funct3 == 0b011
-                    || funct3 == 0b100
-                    || funct3 == 0b101
-                    || funct3 == 0b110
-                    || funct3 == 0b111
-                {
-                    assert_eq!(instr, None);
+                if funct3 == 0b100 || funct3 == 0b101 || funct3 == 0b110 || funct3 == 0b111 {
+                    assert_eq!(instr, Err(DecodeError::ReservedFunct3(instruction)));
                 } else {
                     let instr = instr.unwrap();
                     assert_eq!(instr.format, FORMAT::S);
@@ -946,6 +1548,7 @@ mod tests {
                         0b000 => assert_eq!(instr.mnemonic, MNEMONIC::SB),
                         0b001 => assert_eq!(instr.mnemonic, MNEMONIC::SH),
                         0b010 => assert_eq!(instr.mnemonic, MNEMONIC::SW),
+                        0b011 => assert_eq!(instr.mnemonic, MNEMONIC::SD),
                         _ => panic!("control should not reach here"),
                     }
                     assert_eq!(instr.opcode, OPCODE::STORE);
@@ -954,7 +1557,7 @@ mod tests {
                     assert_eq!(instr.rd, None);
                     assert_eq!(instr.rs1, REG::from_u32(rs1));
                     assert_eq!(instr.rs2, REG::from_u32(rs2));
-                    assert_eq!(instr.imm, Some(((imm2 << 5) | imm1) as u64));
+                    assert_eq!(instr.imm, Some(signed((imm2 << 5) | imm1, 12)));
                 }
             }
         }
@@ -975,30 +1578,330 @@ mod tests {
                 // decode and check
                 let instr = decode(instruction);
                 if funct3 == 0b001 || funct3 == 0b101 {
-                    assert_eq!(instr, None);
+                    // shifts are checked by test_OP_IMM_shifts
+                    continue;
+                }
+                let instr = instr.unwrap();
+                assert_eq!(instr.format, FORMAT::I);
+                match funct3 {
+                    0b000 => assert_eq!(instr.mnemonic, MNEMONIC::ADDI),
+                    0b010 => assert_eq!(instr.mnemonic, MNEMONIC::SLTI),
+                    0b011 => assert_eq!(instr.mnemonic, MNEMONIC::SLTIU),
+                    0b100 => assert_eq!(instr.mnemonic, MNEMONIC::XORI),
+                    0b110 => assert_eq!(instr.mnemonic, MNEMONIC::ORI),
+                    0b111 => assert_eq!(instr.mnemonic, MNEMONIC::ANDI),
+                    _ => panic!("control should not reach here"),
+                }
+                assert_eq!(instr.opcode, OPCODE::OP_IMM);
+                assert_eq!(instr.funct3, Some(funct3));
+                assert_eq!(instr.funct7, None);
+                assert_eq!(instr.rd, REG::from_u32(rd));
+                assert_eq!(instr.rs1, REG::from_u32(rs1));
+                assert_eq!(instr.rs2, None);
+                assert_eq!(instr.imm, Some(signed(imm, 12)));
+                assert_eq!(instr.shamt, None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_OP_IMM_shifts() {
+        let mut rng = rand::thread_rng();
+        for funct3 in [0b001, 0b101] {
+            for funct6 in 0..=0b11_1111 {
+                for _ in 0..ITERS / 10 {
+                    // generate random SLLI/SRLI/SRAI instruction
+                    let rd: u32 = rng.gen_range(0..=0b1_1111);
+                    let rs1: u32 = rng.gen_range(0..=0b1_1111);
+                    let shamt: u32 = rng.gen_range(0..=0b11_1111);
+                    let instruction: u32 = funct6 << 26
+                        | shamt << 20
+                        | rs1 << 15
+                        | funct3 << 12
+                        | rd << 7
+                        | OPCODE::OP_IMM.to_u32();
+
+                    // decode and check
+                    let instr = decode(instruction);
+                    let mnemonic = match (funct3, funct6) {
+                        (0b001, 0b00_0000) => MNEMONIC::SLLI,
+                        (0b101, 0b00_0000) => MNEMONIC::SRLI,
+                        (0b101, 0b01_0000) => MNEMONIC::SRAI,
+                        _ => {
+                            assert_eq!(instr, Err(DecodeError::ReservedFunct7(instruction)));
+                            continue;
+                        }
+                    };
+                    let instr = instr.unwrap();
+                    assert_eq!(instr.format, FORMAT::I);
+                    assert_eq!(instr.mnemonic, mnemonic);
+                    assert_eq!(instr.opcode, OPCODE::OP_IMM);
+                    assert_eq!(instr.funct3, Some(funct3));
+                    assert_eq!(instr.funct7, None);
+                    assert_eq!(instr.rd, REG::from_u32(rd));
+                    assert_eq!(instr.rs1, REG::from_u32(rs1));
+                    assert_eq!(instr.rs2, None);
+                    assert_eq!(instr.imm, None);
+                    assert_eq!(instr.shamt, Some(shamt));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_OP_IMM_32s() {
+        let mut rng = rand::thread_rng();
+        for funct3 in 0..=0b111 {
+            for _ in 0..ITERS {
+                // generate random OP_IMM_32 instruction
+                let rd: u32 = rng.gen_range(0..=0b1_1111);
+                let rs1: u32 = rng.gen_range(0..=0b1_1111);
+                let imm: u32 = rng.gen_range(0..=0b1111_1111_1111);
+                let instruction: u32 =
+                    imm << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | OPCODE::OP_IMM_32.to_u32();
+                let funct7: u32 = imm >> 5;
+
+                // decode and check
+                let instr = decode(instruction);
+                let mnemonic = match (funct3, funct7) {
+                    (0b000, _) => MNEMONIC::ADDIW,
+                    (0b001, 0b000_0000) => MNEMONIC::SLLIW,
+                    (0b101, 0b000_0000) => MNEMONIC::SRLIW,
+                    (0b101, 0b010_0000) => MNEMONIC::SRAIW,
+                    _ => {
+                        assert_eq!(
+                            instr,
+                            Err(if funct3 == 0b001 || funct3 == 0b101 {
+                                DecodeError::ReservedFunct7(instruction)
+                            } else {
+                                DecodeError::ReservedFunct3(instruction)
+                            })
+                        );
+                        continue;
+                    }
+                };
+                let instr = instr.unwrap();
+                assert_eq!(instr.format, FORMAT::I);
+                assert_eq!(instr.opcode, OPCODE::OP_IMM_32);
+                assert_eq!(instr.funct3, Some(funct3));
+                assert_eq!(instr.rd, REG::from_u32(rd));
+                assert_eq!(instr.rs1, REG::from_u32(rs1));
+                assert_eq!(instr.rs2, None);
+                if mnemonic == MNEMONIC::ADDIW {
+                    assert_eq!(instr.funct7, None);
+                    assert_eq!(instr.imm, Some(signed(imm, 12)));
+                    assert_eq!(instr.shamt, None);
+                } else {
+                    assert_eq!(instr.funct7, Some(funct7));
+                    assert_eq!(instr.imm, None);
+                    assert_eq!(instr.shamt, Some(imm & 0b1_1111));
+                }
+                assert_eq!(instr.mnemonic, mnemonic);
+            }
+        }
+    }
+
+    #[test]
+    fn test_OP_32s() {
+        let mut rng = rand::thread_rng();
+        for funct3 in 0..=0b111 {
+            for funct7 in [0b000_0000, 0b000_0001, 0b010_0000, 0b111_1111] {
+                for _ in 0..ITERS {
+                    // generate random OP_32 instruction
+                    let rd: u32 = rng.gen_range(0..=0b1_1111);
+                    let rs1: u32 = rng.gen_range(0..=0b1_1111);
+                    let rs2: u32 = rng.gen_range(0..=0b1_1111);
+                    let instruction: u32 = funct7 << 25
+                        | rs2 << 20
+                        | rs1 << 15
+                        | funct3 << 12
+                        | rd << 7
+                        | OPCODE::OP_32.to_u32();
+
+                    // decode and check
+                    let instr = decode(instruction);
+                    let mnemonic = match (funct3, funct7) {
+                        (0b000, 0b000_0000) => MNEMONIC::ADDW,
+                        (0b000, 0b000_0001) => MNEMONIC::MULW,
+                        (0b000, 0b010_0000) => MNEMONIC::SUBW,
+                        (0b001, 0b000_0000) => MNEMONIC::SLLW,
+                        (0b100, 0b000_0001) => MNEMONIC::DIVW,
+                        (0b101, 0b000_0000) => MNEMONIC::SRLW,
+                        (0b101, 0b000_0001) => MNEMONIC::DIVUW,
+                        (0b101, 0b010_0000) => MNEMONIC::SRAW,
+                        (0b110, 0b000_0001) => MNEMONIC::REMW,
+                        (0b111, 0b000_0001) => MNEMONIC::REMUW,
+                        _ => {
+                            assert_eq!(
+                                instr,
+                                Err(if funct3 == 0b010 || funct3 == 0b011 {
+                                    DecodeError::ReservedFunct3(instruction)
+                                } else {
+                                    DecodeError::ReservedFunct7(instruction)
+                                })
+                            );
+                            continue;
+                        }
+                    };
+                    let instr = instr.unwrap();
+                    assert_eq!(instr.format, FORMAT::R);
+                    assert_eq!(instr.mnemonic, mnemonic);
+                    assert_eq!(instr.opcode, OPCODE::OP_32);
+                    assert_eq!(instr.funct3, Some(funct3));
+                    assert_eq!(instr.funct7, Some(funct7));
+                    assert_eq!(instr.rd, REG::from_u32(rd));
+                    assert_eq!(instr.rs1, REG::from_u32(rs1));
+                    assert_eq!(instr.rs2, REG::from_u32(rs2));
+                    assert_eq!(instr.imm, None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_MISC_MEMs() {
+        let mut rng = rand::thread_rng();
+        for funct3 in 0..=0b111 {
+            for _ in 0..ITERS {
+                // generate random MISC_MEM instruction
+                let rd: u32 = rng.gen_range(0..=0b1_1111);
+                let rs1: u32 = rng.gen_range(0..=0b1_1111);
+                let imm: u32 = rng.gen_range(0..=0b1111_1111_1111);
+                let instruction: u32 =
+                    imm << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | OPCODE::MISC_MEM.to_u32();
+
+                // decode and check
+                let instr = decode(instruction);
+                if funct3 > 0b001 {
+                    assert_eq!(instr, Err(DecodeError::ReservedFunct3(instruction)));
                 } else {
                     let instr = instr.unwrap();
                     assert_eq!(instr.format, FORMAT::I);
                     match funct3 {
-                        0b000 => assert_eq!(instr.mnemonic, MNEMONIC::ADDI),
-                        0b010 => assert_eq!(instr.mnemonic, MNEMONIC::SLTI),
-                        0b011 => assert_eq!(instr.mnemonic, MNEMONIC::SLTIU),
-                        0b100 => assert_eq!(instr.mnemonic, MNEMONIC::XORI),
-                        0b110 => assert_eq!(instr.mnemonic, MNEMONIC::ORI),
-                        0b111 => assert_eq!(instr.mnemonic, MNEMONIC::ANDI),
+                        0b000 => assert_eq!(instr.mnemonic, MNEMONIC::FENCE),
+                        0b001 => assert_eq!(instr.mnemonic, MNEMONIC::FENCE_I),
                         _ => panic!("control should not reach here"),
                     }
-                    assert_eq!(instr.opcode, OPCODE::OP_IMM);
+                    assert_eq!(instr.opcode, OPCODE::MISC_MEM);
                     assert_eq!(instr.funct3, Some(funct3));
                     assert_eq!(instr.funct7, None);
                     assert_eq!(instr.rd, REG::from_u32(rd));
                     assert_eq!(instr.rs1, REG::from_u32(rs1));
                     assert_eq!(instr.rs2, None);
-                    assert_eq!(instr.imm, Some(imm as u64));
+                    assert_eq!(instr.imm, Some(imm as i64));
                 }
             }
         }
     }
 
+    #[test]
+    fn test_ECALL_EBREAK_xRET_WFI() {
+        let ecall = decode(OPCODE::SYSTEM.to_u32()).expect("decode failed");
+        assert_eq!(ecall.format, FORMAT::I);
+        assert_eq!(ecall.mnemonic, MNEMONIC::ECALL);
+        assert_eq!(ecall.opcode, OPCODE::SYSTEM);
+        assert_eq!(ecall.funct3, Some(0));
+        assert_eq!(ecall.rd, None);
+        assert_eq!(ecall.rs1, None);
+
+        let ebreak = decode(1 << 20 | OPCODE::SYSTEM.to_u32()).expect("decode failed");
+        assert_eq!(ebreak.mnemonic, MNEMONIC::EBREAK);
+        assert_eq!(ebreak.opcode, OPCODE::SYSTEM);
+
+        let mret = decode(0x302 << 20 | OPCODE::SYSTEM.to_u32()).expect("decode failed");
+        assert_eq!(mret.mnemonic, MNEMONIC::MRET);
+        assert_eq!(mret.rd, None);
+        let sret = decode(0x102 << 20 | OPCODE::SYSTEM.to_u32()).expect("decode failed");
+        assert_eq!(sret.mnemonic, MNEMONIC::SRET);
+        let wfi = decode(0x105 << 20 | OPCODE::SYSTEM.to_u32()).expect("decode failed");
+        assert_eq!(wfi.mnemonic, MNEMONIC::WFI);
+        // sfence.vma a0, a1
+        let sfence = decode(0x12b50073).expect("decode failed");
+        assert_eq!(sfence.mnemonic, MNEMONIC::SFENCE_VMA);
+        assert_eq!(sfence.format, FORMAT::R);
+        assert_eq!(sfence.rs1, Some(REG::x10));
+        assert_eq!(sfence.rs2, Some(REG::x11));
+        let word = 0x12b50073 | 1 << 7;
+        assert_eq!(decode(word), Err(DecodeError::Reserved(word)));
+
+        // non-zero rd or rs1 fields are reserved
+        let word = 1 << 7 | OPCODE::SYSTEM.to_u32();
+        assert_eq!(decode(word), Err(DecodeError::Reserved(word)));
+        let word = 1 << 15 | 1 << 20 | OPCODE::SYSTEM.to_u32();
+        assert_eq!(decode(word), Err(DecodeError::Reserved(word)));
+        let word = 2 << 20 | OPCODE::SYSTEM.to_u32();
+        assert_eq!(decode(word), Err(DecodeError::Reserved(word)));
+    }
+
+    #[test]
+    fn test_CSRs() {
+        let mut rng = rand::thread_rng();
+        for funct3 in 0b001..=0b111 {
+            for _ in 0..ITERS {
+                // generate random Zicsr instruction
+                let rd: u32 = rng.gen_range(0..=0b1_1111);
+                let rs1: u32 = rng.gen_range(0..=0b1_1111);
+                let csr: u32 = rng.gen_range(0..=0b1111_1111_1111);
+                let instruction: u32 =
+                    csr << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | OPCODE::SYSTEM.to_u32();
+
+                // decode and check
+                let instr = decode(instruction);
+                if funct3 == 0b100 {
+                    assert_eq!(instr, Err(DecodeError::ReservedFunct3(instruction)));
+                } else {
+                    let instr = instr.unwrap();
+                    assert_eq!(instr.format, FORMAT::I);
+                    match funct3 {
+                        0b001 => assert_eq!(instr.mnemonic, MNEMONIC::CSRRW),
+                        0b010 => assert_eq!(instr.mnemonic, MNEMONIC::CSRRS),
+                        0b011 => assert_eq!(instr.mnemonic, MNEMONIC::CSRRC),
+                        0b101 => assert_eq!(instr.mnemonic, MNEMONIC::CSRRWI),
+                        0b110 => assert_eq!(instr.mnemonic, MNEMONIC::CSRRSI),
+                        0b111 => assert_eq!(instr.mnemonic, MNEMONIC::CSRRCI),
+                        _ => panic!("control should not reach here"),
+                    }
+                    assert_eq!(instr.opcode, OPCODE::SYSTEM);
+                    assert_eq!(instr.funct3, Some(funct3));
+                    assert_eq!(instr.funct7, None);
+                    assert_eq!(instr.rd, REG::from_u32(rd));
+                    assert_eq!(instr.rs2, None);
+                    assert_eq!(instr.csr, Some(csr));
+                    if funct3 & 0b100 == 0 {
+                        assert_eq!(instr.rs1, REG::from_u32(rs1));
+                        assert_eq!(instr.imm, None);
+                    } else {
+                        assert_eq!(instr.rs1, None);
+                        assert_eq!(instr.imm, Some(rs1 as i64));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_opcodes() {
+        // compressed
+        assert_eq!(
+            decode(0x0000_4501),
+            Err(DecodeError::UnsupportedExtension(0x0000_4501, "C"))
+        );
+        // fld fa0, 0(a0)
+        assert_eq!(
+            decode(0x0005_3507),
+            Err(DecodeError::UnsupportedExtension(0x0005_3507, "F/D"))
+        );
+        // amoadd.w a0, a1, (a2)
+        assert_eq!(
+            decode(0x00b6_252f),
+            Err(DecodeError::UnsupportedExtension(0x00b6_252f, "A"))
+        );
+        assert_eq!(
+            decode(0xffff_ffff),
+            Err(DecodeError::UnknownOpcode(0xffff_ffff))
+        );
+        assert_eq!(DecodeError::ReservedFunct3(0x1234).word(), 0x1234);
+    }
+
     // do no fold me
 }
@@ -1,5 +1,9 @@
+use std::fmt;
+
+use crate::cpu::encoder;
+
 /// Register names for the RISC-V ISA
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum REG {
     x0,
@@ -151,7 +155,7 @@ impl REG {
 }
 
 /// Register ABI names for the RISC-V ISA
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum ABI {
     zero,
@@ -265,19 +269,31 @@ impl ABI {
 }
 
 /// Instruction formats for the RISC-V ISA
-#[derive(Debug, PartialEq, Eq)]
+///
+/// The immediate of each format is sign-extended from its top bit into
+/// `DecodedInstr::imm` as described per variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum FORMAT {
+    /// Register-register; no immediate
     R,
+    /// imm[11:0] from bits [31:20], range -2048..=2047
     I,
+    /// imm[11:5] from bits [31:25] and imm[4:0] from bits [11:7], range -2048..=2047
     S,
+    /// imm[12|10:5] from bits [31:25] and imm[4:1|11] from bits [11:7]; a
+    /// multiple of 2 in -4096..=4094
     B,
+    /// imm[31:12] from bits [31:12] with the low 12 bits zero; a multiple of
+    /// 4096 in -2^31..2^31
     U,
+    /// imm[20|10:1|11|19:12] from bits [31:12]; a multiple of 2 in
+    /// -2^20..2^20
     J,
 }
 
 /// Instruction opcodes for the RISC-V ISA
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum OPCODE {
     LUI,
@@ -289,6 +305,10 @@ pub enum OPCODE {
     STORE,
     OP_IMM,
     OP,
+    OP_IMM_32,
+    OP_32,
+    MISC_MEM,
+    SYSTEM,
 }
 impl OPCODE {
     pub fn to_u32(&self) -> u32 {
@@ -302,6 +322,10 @@ impl OPCODE {
             OPCODE::STORE => 0b010_0011,
             OPCODE::OP_IMM => 0b001_0011,
             OPCODE::OP => 0b011_0011,
+            OPCODE::OP_IMM_32 => 0b001_1011,
+            OPCODE::OP_32 => 0b011_1011,
+            OPCODE::MISC_MEM => 0b000_1111,
+            OPCODE::SYSTEM => 0b111_0011,
         }
     }
 
@@ -316,13 +340,17 @@ impl OPCODE {
             0b010_0011 => Some(OPCODE::STORE),
             0b001_0011 => Some(OPCODE::OP_IMM),
             0b011_0011 => Some(OPCODE::OP),
+            0b001_1011 => Some(OPCODE::OP_IMM_32),
+            0b011_1011 => Some(OPCODE::OP_32),
+            0b000_1111 => Some(OPCODE::MISC_MEM),
+            0b111_0011 => Some(OPCODE::SYSTEM),
             _ => None,
         }
     }
 }
 
-/// Instruction mnemonics for the RISC-V ISA (RV64I + ???)
-#[derive(Debug, PartialEq, Eq)]
+/// Instruction mnemonics for the RISC-V ISA (RV64IM)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum MNEMONIC {
     // RV32I
@@ -350,6 +378,9 @@ pub enum MNEMONIC {
     XORI,
     ORI,
     ANDI,
+    SLLI,
+    SRLI,
+    SRAI,
     ADD,
     SUB,
     SLL,
@@ -360,6 +391,34 @@ pub enum MNEMONIC {
     SRA,
     OR,
     AND,
+    FENCE,
+    ECALL,
+    EBREAK,
+
+    // RV64I
+    LWU,
+    LD,
+    SD,
+    ADDIW,
+    SLLIW,
+    SRLIW,
+    SRAIW,
+    ADDW,
+    SUBW,
+    SLLW,
+    SRLW,
+    SRAW,
+
+    // Zifencei
+    FENCE_I,
+
+    // Zicsr
+    CSRRW,
+    CSRRS,
+    CSRRC,
+    CSRRWI,
+    CSRRSI,
+    CSRRCI,
 
     // RV32M
     MUL,
@@ -370,10 +429,116 @@ pub enum MNEMONIC {
     DIVU,
     REM,
     REMU,
+
+    // RV64M
+    MULW,
+    DIVW,
+    DIVUW,
+    REMW,
+    REMUW,
+
+    // Privileged
+    MRET,
+    SRET,
+    WFI,
+    SFENCE_VMA,
+}
+impl MNEMONIC {
+    /// Every mnemonic, in declaration order
+    pub const ALL: [MNEMONIC; 76] = [
+        MNEMONIC::LUI,
+        MNEMONIC::AUIPC,
+        MNEMONIC::JAL,
+        MNEMONIC::JALR,
+        MNEMONIC::BEQ,
+        MNEMONIC::BNE,
+        MNEMONIC::BLT,
+        MNEMONIC::BGE,
+        MNEMONIC::BLTU,
+        MNEMONIC::BGEU,
+        MNEMONIC::LB,
+        MNEMONIC::LH,
+        MNEMONIC::LW,
+        MNEMONIC::LBU,
+        MNEMONIC::LHU,
+        MNEMONIC::SB,
+        MNEMONIC::SH,
+        MNEMONIC::SW,
+        MNEMONIC::ADDI,
+        MNEMONIC::SLTI,
+        MNEMONIC::SLTIU,
+        MNEMONIC::XORI,
+        MNEMONIC::ORI,
+        MNEMONIC::ANDI,
+        MNEMONIC::SLLI,
+        MNEMONIC::SRLI,
+        MNEMONIC::SRAI,
+        MNEMONIC::ADD,
+        MNEMONIC::SUB,
+        MNEMONIC::SLL,
+        MNEMONIC::SLT,
+        MNEMONIC::SLTU,
+        MNEMONIC::XOR,
+        MNEMONIC::SRL,
+        MNEMONIC::SRA,
+        MNEMONIC::OR,
+        MNEMONIC::AND,
+        MNEMONIC::FENCE,
+        MNEMONIC::ECALL,
+        MNEMONIC::EBREAK,
+        MNEMONIC::LWU,
+        MNEMONIC::LD,
+        MNEMONIC::SD,
+        MNEMONIC::ADDIW,
+        MNEMONIC::SLLIW,
+        MNEMONIC::SRLIW,
+        MNEMONIC::SRAIW,
+        MNEMONIC::ADDW,
+        MNEMONIC::SUBW,
+        MNEMONIC::SLLW,
+        MNEMONIC::SRLW,
+        MNEMONIC::SRAW,
+        MNEMONIC::FENCE_I,
+        MNEMONIC::CSRRW,
+        MNEMONIC::CSRRS,
+        MNEMONIC::CSRRC,
+        MNEMONIC::CSRRWI,
+        MNEMONIC::CSRRSI,
+        MNEMONIC::CSRRCI,
+        MNEMONIC::MUL,
+        MNEMONIC::MULH,
+        MNEMONIC::MULHSU,
+        MNEMONIC::MULHU,
+        MNEMONIC::DIV,
+        MNEMONIC::DIVU,
+        MNEMONIC::REM,
+        MNEMONIC::REMU,
+        MNEMONIC::MULW,
+        MNEMONIC::DIVW,
+        MNEMONIC::DIVUW,
+        MNEMONIC::REMW,
+        MNEMONIC::REMUW,
+        MNEMONIC::MRET,
+        MNEMONIC::SRET,
+        MNEMONIC::WFI,
+        MNEMONIC::SFENCE_VMA,
+    ];
+
+    /// Assembler name of the mnemonic, e.g. `fence.i`
+    pub fn name(&self) -> String {
+        format!("{:?}", self).to_lowercase().replace('_', ".")
+    }
+
+    /// Mnemonic with the assembler name `name`
+    pub fn from_name(name: &str) -> Option<MNEMONIC> {
+        MNEMONIC::ALL
+            .into_iter()
+            .find(|mnemonic| mnemonic.name() == name)
+    }
 }
 
 /// Decoded instruction structure
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DecodedInstr {
     pub format: FORMAT,
     pub mnemonic: MNEMONIC,
@@ -383,5 +548,220 @@ pub struct DecodedInstr {
     pub rd: Option<REG>,
     pub rs1: Option<REG>,
     pub rs2: Option<REG>,
-    pub imm: Option<u64>,
+    /// Immediate sign-extended according to `format`, except for FENCE,
+    /// whose fm/pred/succ fields are kept unsigned, and the Zicsr immediate
+    /// forms, whose 5-bit uimm is zero-extended
+    pub imm: Option<i64>,
+    /// Shift amount of the immediate shift instructions, kept apart from `imm`
+    pub shamt: Option<u32>,
+    /// CSR address of the Zicsr instructions (their 5-bit uimm is in `imm`)
+    pub csr: Option<u32>,
+}
+
+impl DecodedInstr {
+    /// Instruction `mnemonic` with its format, opcode, funct3 and funct7 as
+    /// `decode` reports them and no operands; set the operands the mnemonic
+    /// takes before encoding it
+    pub fn new(mnemonic: MNEMONIC) -> DecodedInstr {
+        let (format, opcode, funct3, funct7) = encoder::fields(&mnemonic);
+        DecodedInstr {
+            format,
+            mnemonic,
+            opcode,
+            funct3,
+            funct7,
+            rd: None,
+            rs1: None,
+            rs2: None,
+            imm: None,
+            shamt: None,
+            csr: None,
+        }
+    }
+}
+
+/// Reasons a 32-bit word does not decode, each carrying the raw word
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The major opcode (bits [6:0]) is not a known RISC-V opcode
+    UnknownOpcode(u32),
+    /// funct3 is reserved for the opcode
+    ReservedFunct3(u32),
+    /// funct7 (funct6 for the RV64 immediate shifts) is reserved for the
+    /// opcode and funct3
+    ReservedFunct7(u32),
+    /// Any other reserved encoding, such as non-zero fields in ECALL/EBREAK
+    Reserved(u32),
+    /// The word belongs to an extension that is not implemented (name)
+    UnsupportedExtension(u32, &'static str),
+}
+
+impl DecodeError {
+    /// The word that failed to decode
+    pub fn word(&self) -> u32 {
+        match self {
+            DecodeError::UnknownOpcode(word)
+            | DecodeError::ReservedFunct3(word)
+            | DecodeError::ReservedFunct7(word)
+            | DecodeError::Reserved(word)
+            | DecodeError::UnsupportedExtension(word, _) => *word,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(word) => {
+                write!(
+                    f,
+                    "{:#010x}: unknown opcode {:#09b}",
+                    word,
+                    word & 0b111_1111
+                )
+            }
+            DecodeError::ReservedFunct3(word) => write!(
+                f,
+                "{:#010x}: reserved funct3 {:#05b} for opcode {:#09b}",
+                word,
+                (word >> 12) & 0b111,
+                word & 0b111_1111
+            ),
+            DecodeError::ReservedFunct7(word) => write!(
+                f,
+                "{:#010x}: reserved funct7 {:#09b} for opcode {:#09b} with funct3 {:#05b}",
+                word,
+                word >> 25,
+                word & 0b111_1111,
+                (word >> 12) & 0b111
+            ),
+            DecodeError::Reserved(word) => write!(f, "{:#010x}: reserved encoding", word),
+            DecodeError::UnsupportedExtension(word, extension) => write!(
+                f,
+                "{:#010x}: {} extension is not supported",
+                word, extension
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Reasons a `DecodedInstr` cannot be encoded into a 32-bit word
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    /// An operand the mnemonic needs is None (field name)
+    MissingField(&'static str),
+    /// An operand the mnemonic does not have is set (field name)
+    UnexpectedField(&'static str),
+    /// format, opcode, funct3 or funct7 disagrees with the mnemonic (field name)
+    FieldMismatch(&'static str),
+    /// Immediate outside the range of the format (immediate)
+    ImmediateOutOfRange(i64),
+    /// Immediate that is not a multiple of the format's alignment (immediate)
+    MisalignedImmediate(i64),
+    /// Shift amount wider than the operand (shift amount)
+    ShamtOutOfRange(u32),
+    /// CSR address wider than 12 bits (address)
+    CsrOutOfRange(u32),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::MissingField(field) => write!(f, "missing {}", field),
+            EncodeError::UnexpectedField(field) => write!(f, "unexpected {}", field),
+            EncodeError::FieldMismatch(field) => {
+                write!(f, "{} does not match the mnemonic", field)
+            }
+            EncodeError::ImmediateOutOfRange(imm) => write!(f, "immediate {} out of range", imm),
+            EncodeError::MisalignedImmediate(imm) => write!(f, "immediate {} is misaligned", imm),
+            EncodeError::ShamtOutOfRange(shamt) => {
+                write!(f, "shift amount {} out of range", shamt)
+            }
+            EncodeError::CsrOutOfRange(csr) => write!(f, "CSR address {:#x} out of range", csr),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Privilege modes, numbered as in mstatus.MPP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    pub fn from_u64(bits: u64) -> Option<Privilege> {
+        match bits {
+            0 => Some(Privilege::User),
+            1 => Some(Privilege::Supervisor),
+            3 => Some(Privilege::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// Synchronous exceptions raised while executing an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    /// Control transfer to a target that is not 4-byte aligned (target address)
+    InstructionAddressMisaligned(u64),
+    /// Instruction fetch from an address the bus cannot service (fetch address)
+    InstructionAccessFault(u64),
+    /// Word that does not decode to a supported instruction (raw word)
+    IllegalInstruction(u32),
+    /// Load from an address the bus cannot service (load address)
+    LoadAccessFault(u64),
+    /// Store to an address the bus cannot service (store address)
+    StoreAccessFault(u64),
+    /// ECALL executed
+    EnvironmentCall,
+    /// EBREAK executed (address of the EBREAK)
+    Breakpoint(u64),
+    /// Instruction fetch through a missing or forbidden mapping (virtual address)
+    InstructionPageFault(u64),
+    /// Load through a missing or forbidden mapping (virtual address)
+    LoadPageFault(u64),
+    /// Store through a missing or forbidden mapping (virtual address)
+    StorePageFault(u64),
+}
+
+impl Exception {
+    /// Exception code reported in mcause for an exception raised in
+    /// `privilege` mode
+    pub fn code(&self, privilege: Privilege) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAccessFault(_) => 7,
+            // ECALL from U-, S- or M-mode: 8, 9 or 11
+            Exception::EnvironmentCall => 8 + privilege as u64,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    /// Value reported in mtval: the faulting address or instruction word
+    pub fn tval(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(addr)
+            | Exception::InstructionAccessFault(addr)
+            | Exception::LoadAccessFault(addr)
+            | Exception::StoreAccessFault(addr)
+            | Exception::Breakpoint(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StorePageFault(addr) => *addr,
+            Exception::IllegalInstruction(word) => *word as u64,
+            Exception::EnvironmentCall => 0,
+        }
+    }
 }
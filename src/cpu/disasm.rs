@@ -0,0 +1,411 @@
+use std::fmt;
+
+use crate::cpu::csr;
+use crate::cpu::defs::*;
+
+/// How registers are named in disassembly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegNames {
+    /// `x0`-`x31`, as `objdump -M numeric`
+    Numeric,
+    /// `zero`, `ra`, `sp`, ..., the objdump default
+    Abi,
+}
+
+/// Disassembly options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Syntax {
+    pub names: RegNames,
+    /// Print pseudo-instructions such as `li`, `mv` and `ret` where an
+    /// instruction matches one, as objdump does by default; when false every
+    /// instruction is printed under its base mnemonic, as `objdump -M
+    /// no-aliases`
+    pub aliases: bool,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax {
+            names: RegNames::Abi,
+            aliases: true,
+        }
+    }
+}
+
+impl Syntax {
+    fn reg(&self, reg: &Option<REG>) -> String {
+        let n = reg.as_ref().map_or(0, |reg| reg.to_usize());
+        match self.names {
+            RegNames::Numeric => format!("x{}", n),
+            RegNames::Abi => format!("{:?}", ABI::from_u64(n as u64).unwrap()),
+        }
+    }
+}
+
+/// Disassembled instruction, split the way objdump tabulates it
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disassembly {
+    pub mnemonic: String,
+    pub operands: String,
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+/// Absolute target of a JAL or branch located at `pc`
+pub fn target(instr: &DecodedInstr, pc: u64) -> Option<u64> {
+    match instr.format {
+        FORMAT::J | FORMAT::B => Some(pc.wrapping_add(instr.imm? as u64)),
+        _ => None,
+    }
+}
+
+/// Predecessor or successor set of a FENCE
+fn fence_set(set: i64) -> String {
+    let set: String = [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')]
+        .iter()
+        .filter(|(bit, _)| set & bit != 0)
+        .map(|(_, c)| c)
+        .collect();
+    if set.is_empty() {
+        String::from("0")
+    } else {
+        set
+    }
+}
+
+/// Whether `reg` is the register `xn`
+fn is(reg: &Option<REG>, n: usize) -> bool {
+    reg.as_ref().is_some_and(|reg| reg.to_usize() == n)
+}
+
+/// Name of the CSR at `addr`, or its address in hex if it has none
+fn csr_text(addr: u32) -> String {
+    csr::name(addr).unwrap_or_else(|| format!("{:#x}", addr))
+}
+
+/// Jump target of an instruction at `pc` that is `offset` bytes away:
+/// absolute when `pc` is known, `.+offset` otherwise
+fn target_text(pc: Option<u64>, offset: i64) -> String {
+    match pc {
+        Some(pc) => format!("{:x}", pc.wrapping_add(offset as u64)),
+        None => format!(".{:+}", offset),
+    }
+}
+
+/// Pseudo-instruction objdump prints in place of `instr`, if any
+fn alias(instr: &DecodedInstr, syntax: &Syntax, target: &str) -> Option<Disassembly> {
+    let rd = syntax.reg(&instr.rd);
+    let rs1 = syntax.reg(&instr.rs1);
+    let rs2 = syntax.reg(&instr.rs2);
+    let imm = instr.imm.unwrap_or(0);
+    let csr = instr.csr.map_or(String::new(), csr_text);
+
+    let (mnemonic, operands) = match instr.mnemonic {
+        MNEMONIC::ADDI if is(&instr.rd, 0) && is(&instr.rs1, 0) && imm == 0 => {
+            ("nop", String::new())
+        }
+        MNEMONIC::ADDI if is(&instr.rs1, 0) => ("li", format!("{},{}", rd, imm)),
+        MNEMONIC::ADDI if imm == 0 => ("mv", format!("{},{}", rd, rs1)),
+        MNEMONIC::ADDIW if imm == 0 => ("sext.w", format!("{},{}", rd, rs1)),
+        MNEMONIC::ANDI if imm == 0xff => ("zext.b", format!("{},{}", rd, rs1)),
+        MNEMONIC::XORI if imm == -1 => ("not", format!("{},{}", rd, rs1)),
+        MNEMONIC::SLTIU if imm == 1 => ("seqz", format!("{},{}", rd, rs1)),
+        MNEMONIC::SUB if is(&instr.rs1, 0) => ("neg", format!("{},{}", rd, rs2)),
+        MNEMONIC::SUBW if is(&instr.rs1, 0) => ("negw", format!("{},{}", rd, rs2)),
+        MNEMONIC::SLTU if is(&instr.rs1, 0) => ("snez", format!("{},{}", rd, rs2)),
+        MNEMONIC::SLT if is(&instr.rs2, 0) => ("sltz", format!("{},{}", rd, rs1)),
+        MNEMONIC::SLT if is(&instr.rs1, 0) => ("sgtz", format!("{},{}", rd, rs2)),
+
+        MNEMONIC::JAL if is(&instr.rd, 0) => ("j", String::from(target)),
+        MNEMONIC::JAL if is(&instr.rd, 1) => ("jal", String::from(target)),
+        MNEMONIC::JALR if is(&instr.rd, 0) && is(&instr.rs1, 1) && imm == 0 => {
+            ("ret", String::new())
+        }
+        MNEMONIC::JALR if is(&instr.rd, 0) && imm == 0 => ("jr", rs1),
+        MNEMONIC::JALR if is(&instr.rd, 1) && imm == 0 => ("jalr", rs1),
+        MNEMONIC::JALR if is(&instr.rd, 0) => ("jr", format!("{}({})", imm, rs1)),
+        MNEMONIC::JALR if is(&instr.rd, 1) => ("jalr", format!("{}({})", imm, rs1)),
+
+        MNEMONIC::BEQ if is(&instr.rs2, 0) => ("beqz", format!("{},{}", rs1, target)),
+        MNEMONIC::BNE if is(&instr.rs2, 0) => ("bnez", format!("{},{}", rs1, target)),
+        MNEMONIC::BLT if is(&instr.rs2, 0) => ("bltz", format!("{},{}", rs1, target)),
+        MNEMONIC::BGE if is(&instr.rs2, 0) => ("bgez", format!("{},{}", rs1, target)),
+        MNEMONIC::BLT if is(&instr.rs1, 0) => ("bgtz", format!("{},{}", rs2, target)),
+        MNEMONIC::BGE if is(&instr.rs1, 0) => ("blez", format!("{},{}", rs2, target)),
+
+        MNEMONIC::FENCE if imm == 0b1111_1111 => ("fence", String::new()),
+        MNEMONIC::SFENCE_VMA if is(&instr.rs2, 0) => match instr.rs1 {
+            Some(REG::x0) => ("sfence.vma", String::new()),
+            _ => ("sfence.vma", rs1),
+        },
+
+        MNEMONIC::CSRRS if is(&instr.rs1, 0) => match instr.csr {
+            Some(csr::CYCLE) => ("rdcycle", rd),
+            Some(csr::TIME) => ("rdtime", rd),
+            Some(csr::INSTRET) => ("rdinstret", rd),
+            _ => ("csrr", format!("{},{}", rd, csr)),
+        },
+        MNEMONIC::CSRRW if is(&instr.rd, 0) => ("csrw", format!("{},{}", csr, rs1)),
+        MNEMONIC::CSRRS if is(&instr.rd, 0) => ("csrs", format!("{},{}", csr, rs1)),
+        MNEMONIC::CSRRC if is(&instr.rd, 0) => ("csrc", format!("{},{}", csr, rs1)),
+        MNEMONIC::CSRRWI if is(&instr.rd, 0) => ("csrwi", format!("{},{}", csr, imm)),
+        MNEMONIC::CSRRSI if is(&instr.rd, 0) => ("csrsi", format!("{},{}", csr, imm)),
+        MNEMONIC::CSRRCI if is(&instr.rd, 0) => ("csrci", format!("{},{}", csr, imm)),
+        _ => return None,
+    };
+    Some(Disassembly {
+        mnemonic: String::from(mnemonic),
+        operands,
+    })
+}
+
+/// Disassemble `instr` in GNU objdump syntax
+///
+/// JAL and branch targets are printed as absolute hexadecimal addresses when
+/// the instruction's `pc` is known, and as `.+offset` otherwise.
+pub fn disassemble(instr: &DecodedInstr, pc: Option<u64>, syntax: &Syntax) -> Disassembly {
+    let imm = instr.imm.unwrap_or(0);
+    let target = target_text(pc, imm);
+    if syntax.aliases {
+        if let Some(alias) = alias(instr, syntax, &target) {
+            return alias;
+        }
+    }
+
+    let mut mnemonic = instr.mnemonic.name();
+    let rd = syntax.reg(&instr.rd);
+    let rs1 = syntax.reg(&instr.rs1);
+    let rs2 = syntax.reg(&instr.rs2);
+
+    let operands = match instr.opcode {
+        OPCODE::LUI | OPCODE::AUIPC => format!("{},{:#x}", rd, (imm >> 12) & 0xf_ffff),
+        OPCODE::JAL => format!("{},{}", rd, target),
+        OPCODE::JALR | OPCODE::LOAD => format!("{},{}({})", rd, imm, rs1),
+        OPCODE::BRANCH => format!("{},{},{}", rs1, rs2, target),
+        OPCODE::STORE => format!("{},{}({})", rs2, imm, rs1),
+        OPCODE::OP_IMM | OPCODE::OP_IMM_32 => match instr.shamt {
+            Some(shamt) => format!("{},{},{:#x}", rd, rs1, shamt),
+            None => format!("{},{},{}", rd, rs1, imm),
+        },
+        OPCODE::OP | OPCODE::OP_32 => format!("{},{},{}", rd, rs1, rs2),
+        OPCODE::MISC_MEM if instr.mnemonic == MNEMONIC::FENCE => {
+            if imm == 0b1000_0011_0011 {
+                mnemonic = String::from("fence.tso");
+                String::new()
+            } else {
+                format!("{},{}", fence_set(imm >> 4), fence_set(imm))
+            }
+        }
+        OPCODE::MISC_MEM => String::new(),
+        OPCODE::SYSTEM if instr.mnemonic == MNEMONIC::SFENCE_VMA => format!("{},{}", rs1, rs2),
+        OPCODE::SYSTEM => match instr.csr {
+            Some(addr) => {
+                let name = csr_text(addr);
+                match instr.rs1 {
+                    Some(_) => format!("{},{},{}", rd, name, rs1),
+                    None => format!("{},{},{}", rd, name, imm),
+                }
+            }
+            None => String::new(),
+        },
+    };
+    Disassembly { mnemonic, operands }
+}
+
+/// Offset from the AUIPC of a `call` (AUIPC ra + JALR ra) or `tail` (AUIPC
+/// t1 + JALR zero) pair to its target, and the pseudo-instruction's name
+fn call_pair(first: &DecodedInstr, second: &DecodedInstr) -> Option<(&'static str, i64)> {
+    if first.mnemonic != MNEMONIC::AUIPC
+        || second.mnemonic != MNEMONIC::JALR
+        || first.rd != second.rs1
+    {
+        return None;
+    }
+    let name = if is(&first.rd, 1) && is(&second.rd, 1) {
+        "call"
+    } else if is(&first.rd, 6) && is(&second.rd, 0) {
+        "tail"
+    } else {
+        return None;
+    };
+    Some((name, first.imm? + second.imm?))
+}
+
+/// Target of a `call` or `tail` pair whose AUIPC is at `pc`
+pub fn pair_target(first: &DecodedInstr, second: &DecodedInstr, pc: u64) -> Option<u64> {
+    call_pair(first, second).map(|(_, offset)| pc.wrapping_add(offset as u64))
+}
+
+/// Disassemble two consecutive instructions as one `call` or `tail`
+/// pseudo-instruction, if they form one and `syntax` enables aliases
+pub fn disassemble_pair(
+    first: &DecodedInstr,
+    second: &DecodedInstr,
+    pc: Option<u64>,
+    syntax: &Syntax,
+) -> Option<Disassembly> {
+    if !syntax.aliases {
+        return None;
+    }
+    let (name, offset) = call_pair(first, second)?;
+    Some(Disassembly {
+        mnemonic: String::from(name),
+        operands: target_text(pc, offset),
+    })
+}
+
+impl fmt::Display for DecodedInstr {
+    /// objdump syntax with ABI register names, pseudo-instructions and
+    /// pc-relative targets
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", disassemble(self, None, &Syntax::default()))
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use crate::cpu::decoder::decode;
+    use crate::cpu::disasm::*;
+
+    const NO_ALIASES: Syntax = Syntax {
+        names: RegNames::Abi,
+        aliases: false,
+    };
+
+    #[test]
+    fn test_objdump_syntax() {
+        let cases: [(u32, &str); 27] = [
+            (0xfff5_0513, "addi a0,a0,-1"),
+            (0x0081_2283, "lw t0,8(sp)"),
+            (0x0011_3c23, "sd ra,24(sp)"),
+            (0xffff_f537, "lui a0,0xfffff"),
+            (0x0000_2197, "auipc gp,0x2"),
+            (0x0100_00ef, "jal ra,1010"),
+            (0x0000_8067, "jalr zero,0(ra)"),
+            (0xfeb5_0ce3, "beq a0,a1,ff8"),
+            (0x0207_9793, "slli a5,a5,0x20"),
+            (0x4033_d31b, "sraiw t1,t2,0x3"),
+            (0x4124_8433, "sub s0,s1,s2"),
+            (0x02c5_a533, "mulhsu a0,a1,a2"),
+            (0x0ff0_000f, "fence iorw,iorw"),
+            (0x8330_000f, "fence.tso"),
+            (0x0210_000f, "fence r,w"),
+            (0x0000_100f, "fence.i"),
+            (0x0000_0073, "ecall"),
+            (0x0010_0073, "ebreak"),
+            (0x3020_0073, "mret"),
+            (0x1020_0073, "sret"),
+            (0x1050_0073, "wfi"),
+            (0x12b5_0073, "sfence.vma a0,a1"),
+            (0x3000_2573, "csrrs a0,mstatus,zero"),
+            (0x3052_d073, "csrrwi zero,mtvec,5"),
+            (0x7c03_12f3, "csrrw t0,0x7c0,t1"),
+            (0x02c5_f53b, "remuw a0,a1,a2"),
+            (0x0015_3513, "sltiu a0,a0,1"),
+        ];
+        for (word, text) in cases {
+            let instr = decode(word).expect("decode failed");
+            let disassembly = disassemble(&instr, Some(0x1000), &NO_ALIASES);
+            assert_eq!(disassembly.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_register_names_and_targets() {
+        let numeric = Syntax {
+            names: RegNames::Numeric,
+            aliases: false,
+        };
+        let addi = decode(0xfff5_0513).unwrap();
+        assert_eq!(
+            disassemble(&addi, None, &numeric).to_string(),
+            "addi x10,x10,-1"
+        );
+        assert_eq!(addi.to_string(), "addi a0,a0,-1");
+
+        let beq = decode(0xfeb5_0ce3).unwrap();
+        assert_eq!(beq.to_string(), "beq a0,a1,.-8");
+        assert_eq!(target(&beq, 0x8000_0000), Some(0x7fff_fff8));
+        assert_eq!(target(&addi, 0x8000_0000), None);
+
+        let jal = disassemble(&decode(0x0100_00ef).unwrap(), None, &numeric);
+        assert_eq!(jal.mnemonic, "jal");
+        assert_eq!(jal.operands, "x1,.+16");
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let cases: [(u32, &str, &str); 28] = [
+            (0x0000_0013, "nop", "addi zero,zero,0"),
+            (0xfff0_0513, "li a0,-1", "addi a0,zero,-1"),
+            (0x0005_8513, "mv a0,a1", "addi a0,a1,0"),
+            (0x0005_851b, "sext.w a0,a1", "addiw a0,a1,0"),
+            (0x0ff5_f513, "zext.b a0,a1", "andi a0,a1,255"),
+            (0xfff5_c513, "not a0,a1", "xori a0,a1,-1"),
+            (0x40b0_0533, "neg a0,a1", "sub a0,zero,a1"),
+            (0x40b0_053b, "negw a0,a1", "subw a0,zero,a1"),
+            (0x0015_b513, "seqz a0,a1", "sltiu a0,a1,1"),
+            (0x00b0_3533, "snez a0,a1", "sltu a0,zero,a1"),
+            (0x0005_a533, "sltz a0,a1", "slt a0,a1,zero"),
+            (0x00b0_2533, "sgtz a0,a1", "slt a0,zero,a1"),
+            (0x0100_006f, "j 1010", "jal zero,1010"),
+            (0x0100_00ef, "jal 1010", "jal ra,1010"),
+            (0x0000_8067, "ret", "jalr zero,0(ra)"),
+            (0x0005_0067, "jr a0", "jalr zero,0(a0)"),
+            (0x0005_00e7, "jalr a0", "jalr ra,0(a0)"),
+            (0x0083_0067, "jr 8(t1)", "jalr zero,8(t1)"),
+            (0xff00_80e7, "jalr -16(ra)", "jalr ra,-16(ra)"),
+            (0x0005_0463, "beqz a0,1008", "beq a0,zero,1008"),
+            (0x00a0_5463, "blez a0,1008", "bge zero,a0,1008"),
+            (0x0ff0_000f, "fence", "fence iorw,iorw"),
+            (0x1200_0073, "sfence.vma", "sfence.vma zero,zero"),
+            (0x1205_0073, "sfence.vma a0", "sfence.vma a0,zero"),
+            (0xc000_2573, "rdcycle a0", "csrrs a0,cycle,zero"),
+            (0x3000_2573, "csrr a0,mstatus", "csrrs a0,mstatus,zero"),
+            (0x3005_9073, "csrw mstatus,a1", "csrrw zero,mstatus,a1"),
+            (0x3004_6073, "csrsi mstatus,8", "csrrsi zero,mstatus,8"),
+        ];
+        for (word, alias, raw) in cases {
+            let instr = decode(word).expect("decode failed");
+            let aliased = disassemble(&instr, Some(0x1000), &Syntax::default());
+            assert_eq!(aliased.to_string(), alias);
+            assert_eq!(
+                disassemble(&instr, Some(0x1000), &NO_ALIASES).to_string(),
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_call_and_tail_pairs() {
+        // auipc ra,0x1; jalr ra,-16(ra)
+        let auipc = decode(0x0000_1097).unwrap();
+        let jalr = decode(0xff00_80e7).unwrap();
+        let call = disassemble_pair(&auipc, &jalr, Some(0x1000), &Syntax::default());
+        assert_eq!(call.unwrap().to_string(), "call 1ff0");
+        assert_eq!(pair_target(&auipc, &jalr, 0x1000), Some(0x1ff0));
+        assert_eq!(
+            disassemble_pair(&auipc, &jalr, Some(0x1000), &NO_ALIASES),
+            None
+        );
+
+        // auipc t1,0x0; jalr zero,8(t1)
+        let auipc = decode(0x0000_0317).unwrap();
+        let jalr = decode(0x0083_0067).unwrap();
+        let tail = disassemble_pair(&auipc, &jalr, None, &Syntax::default());
+        assert_eq!(tail.unwrap().to_string(), "tail .+8");
+
+        // jalr through a different register is not a pair
+        let jalr = decode(0x0000_80e7).unwrap();
+        assert_eq!(pair_target(&auipc, &jalr, 0x1000), None);
+    }
+}
@@ -0,0 +1,448 @@
+use crate::cpu::defs::*;
+
+/// Operands of an instruction, which fix how its fields are laid out
+enum Operands {
+    /// rd and imm[31:12]
+    U,
+    /// rd and imm[20:1]
+    J,
+    /// rd, rs1 and imm[11:0]
+    I,
+    /// rd, rs1 and a shift amount of the given width in bits
+    Shift(u32),
+    /// rs1, rs2 and imm[12:1]
+    B,
+    /// rs1, rs2 and imm[11:0]
+    S,
+    /// rd, rs1 and rs2
+    R,
+    /// rd, rs1 and the unsigned fm/pred/succ fields
+    Fence,
+    /// No operands at all
+    Nullary,
+    /// rd, rs1 and csr
+    Csr,
+    /// rd, the 5-bit uimm and csr
+    CsrImm,
+    /// rs1 and rs2 only
+    Sfence,
+}
+
+impl Operands {
+    /// Format `decode` reports for instructions with these operands
+    fn format(&self) -> FORMAT {
+        match self {
+            Operands::U => FORMAT::U,
+            Operands::J => FORMAT::J,
+            Operands::B => FORMAT::B,
+            Operands::S => FORMAT::S,
+            Operands::R | Operands::Sfence => FORMAT::R,
+            _ => FORMAT::I,
+        }
+    }
+}
+
+/// Opcode, funct3 and funct7 of `mnemonic` as `decode` reports them, and its
+/// operands
+fn layout(mnemonic: &MNEMONIC) -> (OPCODE, Option<u32>, Option<u32>, Operands) {
+    match mnemonic {
+        MNEMONIC::LUI => (OPCODE::LUI, None, None, Operands::U),
+        MNEMONIC::AUIPC => (OPCODE::AUIPC, None, None, Operands::U),
+        MNEMONIC::JAL => (OPCODE::JAL, None, None, Operands::J),
+        MNEMONIC::JALR => (OPCODE::JALR, Some(0b000), None, Operands::I),
+
+        MNEMONIC::BEQ => (OPCODE::BRANCH, Some(0b000), None, Operands::B),
+        MNEMONIC::BNE => (OPCODE::BRANCH, Some(0b001), None, Operands::B),
+        MNEMONIC::BLT => (OPCODE::BRANCH, Some(0b100), None, Operands::B),
+        MNEMONIC::BGE => (OPCODE::BRANCH, Some(0b101), None, Operands::B),
+        MNEMONIC::BLTU => (OPCODE::BRANCH, Some(0b110), None, Operands::B),
+        MNEMONIC::BGEU => (OPCODE::BRANCH, Some(0b111), None, Operands::B),
+
+        MNEMONIC::LB => (OPCODE::LOAD, Some(0b000), None, Operands::I),
+        MNEMONIC::LH => (OPCODE::LOAD, Some(0b001), None, Operands::I),
+        MNEMONIC::LW => (OPCODE::LOAD, Some(0b010), None, Operands::I),
+        MNEMONIC::LD => (OPCODE::LOAD, Some(0b011), None, Operands::I),
+        MNEMONIC::LBU => (OPCODE::LOAD, Some(0b100), None, Operands::I),
+        MNEMONIC::LHU => (OPCODE::LOAD, Some(0b101), None, Operands::I),
+        MNEMONIC::LWU => (OPCODE::LOAD, Some(0b110), None, Operands::I),
+
+        MNEMONIC::SB => (OPCODE::STORE, Some(0b000), None, Operands::S),
+        MNEMONIC::SH => (OPCODE::STORE, Some(0b001), None, Operands::S),
+        MNEMONIC::SW => (OPCODE::STORE, Some(0b010), None, Operands::S),
+        MNEMONIC::SD => (OPCODE::STORE, Some(0b011), None, Operands::S),
+
+        MNEMONIC::ADDI => (OPCODE::OP_IMM, Some(0b000), None, Operands::I),
+        MNEMONIC::SLLI => (OPCODE::OP_IMM, Some(0b001), None, Operands::Shift(6)),
+        MNEMONIC::SLTI => (OPCODE::OP_IMM, Some(0b010), None, Operands::I),
+        MNEMONIC::SLTIU => (OPCODE::OP_IMM, Some(0b011), None, Operands::I),
+        MNEMONIC::XORI => (OPCODE::OP_IMM, Some(0b100), None, Operands::I),
+        MNEMONIC::SRLI => (OPCODE::OP_IMM, Some(0b101), None, Operands::Shift(6)),
+        MNEMONIC::SRAI => (OPCODE::OP_IMM, Some(0b101), None, Operands::Shift(6)),
+        MNEMONIC::ORI => (OPCODE::OP_IMM, Some(0b110), None, Operands::I),
+        MNEMONIC::ANDI => (OPCODE::OP_IMM, Some(0b111), None, Operands::I),
+
+        MNEMONIC::ADD => (OPCODE::OP, Some(0b000), Some(0b000_0000), Operands::R),
+        MNEMONIC::SUB => (OPCODE::OP, Some(0b000), Some(0b010_0000), Operands::R),
+        MNEMONIC::SLL => (OPCODE::OP, Some(0b001), Some(0b000_0000), Operands::R),
+        MNEMONIC::SLT => (OPCODE::OP, Some(0b010), Some(0b000_0000), Operands::R),
+        MNEMONIC::SLTU => (OPCODE::OP, Some(0b011), Some(0b000_0000), Operands::R),
+        MNEMONIC::XOR => (OPCODE::OP, Some(0b100), Some(0b000_0000), Operands::R),
+        MNEMONIC::SRL => (OPCODE::OP, Some(0b101), Some(0b000_0000), Operands::R),
+        MNEMONIC::SRA => (OPCODE::OP, Some(0b101), Some(0b010_0000), Operands::R),
+        MNEMONIC::OR => (OPCODE::OP, Some(0b110), Some(0b000_0000), Operands::R),
+        MNEMONIC::AND => (OPCODE::OP, Some(0b111), Some(0b000_0000), Operands::R),
+
+        MNEMONIC::MUL => (OPCODE::OP, Some(0b000), Some(0b000_0001), Operands::R),
+        MNEMONIC::MULH => (OPCODE::OP, Some(0b001), Some(0b000_0001), Operands::R),
+        MNEMONIC::MULHSU => (OPCODE::OP, Some(0b010), Some(0b000_0001), Operands::R),
+        MNEMONIC::MULHU => (OPCODE::OP, Some(0b011), Some(0b000_0001), Operands::R),
+        MNEMONIC::DIV => (OPCODE::OP, Some(0b100), Some(0b000_0001), Operands::R),
+        MNEMONIC::DIVU => (OPCODE::OP, Some(0b101), Some(0b000_0001), Operands::R),
+        MNEMONIC::REM => (OPCODE::OP, Some(0b110), Some(0b000_0001), Operands::R),
+        MNEMONIC::REMU => (OPCODE::OP, Some(0b111), Some(0b000_0001), Operands::R),
+
+        MNEMONIC::ADDIW => (OPCODE::OP_IMM_32, Some(0b000), None, Operands::I),
+        MNEMONIC::SLLIW => (
+            OPCODE::OP_IMM_32,
+            Some(0b001),
+            Some(0b000_0000),
+            Operands::Shift(5),
+        ),
+        MNEMONIC::SRLIW => (
+            OPCODE::OP_IMM_32,
+            Some(0b101),
+            Some(0b000_0000),
+            Operands::Shift(5),
+        ),
+        MNEMONIC::SRAIW => (
+            OPCODE::OP_IMM_32,
+            Some(0b101),
+            Some(0b010_0000),
+            Operands::Shift(5),
+        ),
+
+        MNEMONIC::ADDW => (OPCODE::OP_32, Some(0b000), Some(0b000_0000), Operands::R),
+        MNEMONIC::SUBW => (OPCODE::OP_32, Some(0b000), Some(0b010_0000), Operands::R),
+        MNEMONIC::SLLW => (OPCODE::OP_32, Some(0b001), Some(0b000_0000), Operands::R),
+        MNEMONIC::SRLW => (OPCODE::OP_32, Some(0b101), Some(0b000_0000), Operands::R),
+        MNEMONIC::SRAW => (OPCODE::OP_32, Some(0b101), Some(0b010_0000), Operands::R),
+        MNEMONIC::MULW => (OPCODE::OP_32, Some(0b000), Some(0b000_0001), Operands::R),
+        MNEMONIC::DIVW => (OPCODE::OP_32, Some(0b100), Some(0b000_0001), Operands::R),
+        MNEMONIC::DIVUW => (OPCODE::OP_32, Some(0b101), Some(0b000_0001), Operands::R),
+        MNEMONIC::REMW => (OPCODE::OP_32, Some(0b110), Some(0b000_0001), Operands::R),
+        MNEMONIC::REMUW => (OPCODE::OP_32, Some(0b111), Some(0b000_0001), Operands::R),
+
+        MNEMONIC::FENCE => (OPCODE::MISC_MEM, Some(0b000), None, Operands::Fence),
+        MNEMONIC::FENCE_I => (OPCODE::MISC_MEM, Some(0b001), None, Operands::Fence),
+
+        MNEMONIC::ECALL => (OPCODE::SYSTEM, Some(0b000), None, Operands::Nullary),
+        MNEMONIC::EBREAK => (OPCODE::SYSTEM, Some(0b000), None, Operands::Nullary),
+        MNEMONIC::MRET => (OPCODE::SYSTEM, Some(0b000), None, Operands::Nullary),
+        MNEMONIC::SRET => (OPCODE::SYSTEM, Some(0b000), None, Operands::Nullary),
+        MNEMONIC::WFI => (OPCODE::SYSTEM, Some(0b000), None, Operands::Nullary),
+        MNEMONIC::SFENCE_VMA => (
+            OPCODE::SYSTEM,
+            Some(0b000),
+            Some(0b000_1001),
+            Operands::Sfence,
+        ),
+        MNEMONIC::CSRRW => (OPCODE::SYSTEM, Some(0b001), None, Operands::Csr),
+        MNEMONIC::CSRRS => (OPCODE::SYSTEM, Some(0b010), None, Operands::Csr),
+        MNEMONIC::CSRRC => (OPCODE::SYSTEM, Some(0b011), None, Operands::Csr),
+        MNEMONIC::CSRRWI => (OPCODE::SYSTEM, Some(0b101), None, Operands::CsrImm),
+        MNEMONIC::CSRRSI => (OPCODE::SYSTEM, Some(0b110), None, Operands::CsrImm),
+        MNEMONIC::CSRRCI => (OPCODE::SYSTEM, Some(0b111), None, Operands::CsrImm),
+    }
+}
+
+/// Format, opcode, funct3 and funct7 of `mnemonic` as `decode` reports
+/// them, for `DecodedInstr::new`
+pub(crate) fn fields(mnemonic: &MNEMONIC) -> (FORMAT, OPCODE, Option<u32>, Option<u32>) {
+    let (opcode, funct3, funct7, operands) = layout(mnemonic);
+    (operands.format(), opcode, funct3, funct7)
+}
+
+/// Check that `field` is set exactly when the mnemonic has that operand
+fn expect<T>(field: &Option<T>, expected: bool, name: &'static str) -> Result<(), EncodeError> {
+    match (field, expected) {
+        (None, true) => Err(EncodeError::MissingField(name)),
+        (Some(_), false) => Err(EncodeError::UnexpectedField(name)),
+        _ => Ok(()),
+    }
+}
+
+/// Check that `imm` fits a signed `bits`-wide field and has its low `align`
+/// bits clear, returning it truncated to 32 bits
+fn immediate(imm: i64, bits: u32, align: u32) -> Result<u32, EncodeError> {
+    if imm < -(1 << (bits - 1)) || imm >= 1 << (bits - 1) {
+        return Err(EncodeError::ImmediateOutOfRange(imm));
+    }
+    if imm & ((1 << align) - 1) != 0 {
+        return Err(EncodeError::MisalignedImmediate(imm));
+    }
+    Ok(imm as u32)
+}
+
+/// Encode an instruction into a 32-bit RISC-V (RV64IM) word
+///
+/// The inverse of `decode`: the fields of `instr` must be exactly those
+/// `decode` produces for its mnemonic, with the immediate in range and
+/// aligned for the format.
+pub fn encode(instr: &DecodedInstr) -> Result<u32, EncodeError> {
+    let (opcode, funct3, funct7, operands) = layout(&instr.mnemonic);
+    if instr.format != operands.format() {
+        return Err(EncodeError::FieldMismatch("format"));
+    }
+    if instr.opcode != opcode {
+        return Err(EncodeError::FieldMismatch("opcode"));
+    }
+    if instr.funct3 != funct3 {
+        return Err(EncodeError::FieldMismatch("funct3"));
+    }
+    if instr.funct7 != funct7 {
+        return Err(EncodeError::FieldMismatch("funct7"));
+    }
+
+    let (rd, rs1, rs2, imm, shamt, csr) = match operands {
+        Operands::U | Operands::J => (true, false, false, true, false, false),
+        Operands::I | Operands::Fence => (true, true, false, true, false, false),
+        Operands::Shift(_) => (true, true, false, false, true, false),
+        Operands::B | Operands::S => (false, true, true, true, false, false),
+        Operands::R => (true, true, true, false, false, false),
+        Operands::Nullary => (false, false, false, false, false, false),
+        Operands::Csr => (true, true, false, false, false, true),
+        Operands::CsrImm => (true, false, false, true, false, true),
+        Operands::Sfence => (false, true, true, false, false, false),
+    };
+    expect(&instr.rd, rd, "rd")?;
+    expect(&instr.rs1, rs1, "rs1")?;
+    expect(&instr.rs2, rs2, "rs2")?;
+    expect(&instr.imm, imm, "imm")?;
+    expect(&instr.shamt, shamt, "shamt")?;
+    expect(&instr.csr, csr, "csr")?;
+
+    let reg = |reg: &Option<REG>| reg.as_ref().map_or(0, |reg| reg.to_usize() as u32);
+    let word = opcode.to_u32()
+        | (reg(&instr.rd) << 7)
+        | (funct3.unwrap_or(0) << 12)
+        | (reg(&instr.rs1) << 15)
+        | (reg(&instr.rs2) << 20)
+        | (funct7.unwrap_or(0) << 25);
+
+    let imm = instr.imm.unwrap_or(0);
+    let fields = match operands {
+        Operands::U => immediate(imm, 32, 12)?,
+        Operands::J => {
+            let imm = immediate(imm, 21, 1)?;
+            ((imm & 0b1_0000_0000_0000_0000_0000) << 11) // imm[20]
+                | ((imm & 0b111_1111_1110) << 20) // imm[10:1]
+                | ((imm & 0b1000_0000_0000) << 9) // imm[11]
+                | (imm & 0b1111_1111_0000_0000_0000) // imm[19:12]
+        }
+        Operands::I => (immediate(imm, 12, 0)? & 0b1111_1111_1111) << 20,
+        Operands::Shift(width) => {
+            let shamt = instr.shamt.unwrap_or(0);
+            if shamt >= 1 << width {
+                return Err(EncodeError::ShamtOutOfRange(shamt));
+            }
+            // SRAI has funct6 = 0b01_0000, which `decode` does not report
+            let funct6 = if instr.mnemonic == MNEMONIC::SRAI {
+                0b01_0000 << 26
+            } else {
+                0
+            };
+            funct6 | (shamt << 20)
+        }
+        Operands::B => {
+            let imm = immediate(imm, 13, 1)?;
+            ((imm & 0b1_0000_0000_0000) << 19) // imm[12]
+                | ((imm & 0b111_1110_0000) << 20) // imm[10:5]
+                | ((imm & 0b1_1110) << 7) // imm[4:1]
+                | ((imm & 0b1000_0000_0000) >> 4) // imm[11]
+        }
+        Operands::S => {
+            let imm = immediate(imm, 12, 0)?;
+            ((imm & 0b1111_1110_0000) << 20) // imm[11:5]
+                | ((imm & 0b1_1111) << 7) // imm[4:0]
+        }
+        Operands::R | Operands::Sfence => 0,
+        Operands::Fence => {
+            if !(0..1 << 12).contains(&imm) {
+                return Err(EncodeError::ImmediateOutOfRange(imm));
+            }
+            (imm as u32) << 20
+        }
+        Operands::Nullary => match instr.mnemonic {
+            MNEMONIC::EBREAK => 1 << 20,
+            MNEMONIC::MRET => 0b0011_0000_0010 << 20,
+            MNEMONIC::SRET => 0b0001_0000_0010 << 20,
+            MNEMONIC::WFI => 0b0001_0000_0101 << 20,
+            _ => 0,
+        },
+        Operands::Csr | Operands::CsrImm => {
+            let csr = instr.csr.unwrap_or(0);
+            if csr >= 1 << 12 {
+                return Err(EncodeError::CsrOutOfRange(csr));
+            }
+            if !(0..1 << 5).contains(&imm) {
+                return Err(EncodeError::ImmediateOutOfRange(imm));
+            }
+            (csr << 20) | ((imm as u32) << 15)
+        }
+    };
+    Ok(word | fields)
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::Rng;
+
+    use crate::cpu::decoder::decode;
+    use crate::cpu::encoder::*;
+
+    const ITERS: u32 = 1000;
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = rand::thread_rng();
+        let opcodes = [
+            OPCODE::LUI,
+            OPCODE::AUIPC,
+            OPCODE::JAL,
+            OPCODE::JALR,
+            OPCODE::BRANCH,
+            OPCODE::LOAD,
+            OPCODE::STORE,
+            OPCODE::OP_IMM,
+            OPCODE::OP,
+            OPCODE::OP_IMM_32,
+            OPCODE::OP_32,
+            OPCODE::MISC_MEM,
+            OPCODE::SYSTEM,
+        ];
+        let mut seen = HashSet::new();
+        for opcode in opcodes {
+            for _ in 0..ITERS {
+                // bias funct7 towards the values that decode
+                let funct7: u32 = match rng.gen_range(0..4) {
+                    0 => 0b000_0000,
+                    1 => 0b000_0001,
+                    2 => 0b010_0000,
+                    _ => rng.gen_range(0..1 << 7),
+                };
+                let word: u32 = (funct7 << 25) | (rng.gen_range(0..1 << 18) << 7) | opcode.to_u32();
+                if let Ok(instr) = decode(word) {
+                    assert_eq!(encode(&instr), Ok(word), "{:?}", instr);
+                    seen.insert(format!("{:?}", instr.mnemonic));
+                }
+            }
+        }
+        // SYSTEM words with fixed fields are too rare to be drawn at random
+        for word in [
+            0x0000_0073,
+            0x0010_0073,
+            0x3020_0073,
+            0x1020_0073,
+            0x1050_0073,
+            0x12b5_0073,
+        ] {
+            let instr = decode(word).unwrap();
+            assert_eq!(encode(&instr), Ok(word), "{:?}", instr);
+            seen.insert(format!("{:?}", instr.mnemonic));
+        }
+        assert_eq!(seen.len(), MNEMONIC::ALL.len());
+    }
+
+    #[test]
+    fn test_known_words() {
+        // addi a0, a0, -1
+        let addi = DecodedInstr {
+            format: FORMAT::I,
+            mnemonic: MNEMONIC::ADDI,
+            opcode: OPCODE::OP_IMM,
+            funct3: Some(0b000),
+            funct7: None,
+            rd: Some(REG::x10),
+            rs1: Some(REG::x10),
+            rs2: None,
+            imm: Some(-1),
+            shamt: None,
+            csr: None,
+        };
+        assert_eq!(encode(&addi), Ok(0xfff5_0513));
+
+        // beq x1, x2, -4
+        let beq = DecodedInstr {
+            format: FORMAT::B,
+            mnemonic: MNEMONIC::BEQ,
+            opcode: OPCODE::BRANCH,
+            funct3: Some(0b000),
+            funct7: None,
+            rd: None,
+            rs1: Some(REG::x1),
+            rs2: Some(REG::x2),
+            imm: Some(-4),
+            shamt: None,
+            csr: None,
+        };
+        assert_eq!(encode(&beq), Ok(0xfe20_8ee3));
+    }
+
+    #[test]
+    fn test_invalid_instructions() {
+        let jal = |imm: i64| DecodedInstr {
+            format: FORMAT::J,
+            mnemonic: MNEMONIC::JAL,
+            opcode: OPCODE::JAL,
+            funct3: None,
+            funct7: None,
+            rd: Some(REG::x1),
+            rs1: None,
+            rs2: None,
+            imm: Some(imm),
+            shamt: None,
+            csr: None,
+        };
+        assert_eq!(encode(&jal(-(1 << 20))), Ok(0x8000_00ef));
+        assert_eq!(
+            encode(&jal(1 << 20)),
+            Err(EncodeError::ImmediateOutOfRange(1 << 20))
+        );
+        assert_eq!(encode(&jal(6)), Ok(0x0060_00ef));
+        assert_eq!(encode(&jal(3)), Err(EncodeError::MisalignedImmediate(3)));
+
+        let mut lui = jal(0x1000);
+        lui.mnemonic = MNEMONIC::LUI;
+        assert_eq!(encode(&lui), Err(EncodeError::FieldMismatch("format")));
+        lui.format = FORMAT::U;
+        assert_eq!(encode(&lui), Err(EncodeError::FieldMismatch("opcode")));
+        lui.opcode = OPCODE::LUI;
+        assert_eq!(encode(&lui), Ok(0x0000_10b7));
+        lui.imm = Some(0x1001);
+        assert_eq!(encode(&lui), Err(EncodeError::MisalignedImmediate(0x1001)));
+        lui.imm = None;
+        assert_eq!(encode(&lui), Err(EncodeError::MissingField("imm")));
+        lui.imm = Some(0x1000);
+        lui.rs2 = Some(REG::x2);
+        assert_eq!(encode(&lui), Err(EncodeError::UnexpectedField("rs2")));
+
+        let slliw = |shamt: u32| DecodedInstr {
+            format: FORMAT::I,
+            mnemonic: MNEMONIC::SLLIW,
+            opcode: OPCODE::OP_IMM_32,
+            funct3: Some(0b001),
+            funct7: Some(0b000_0000),
+            rd: Some(REG::x1),
+            rs1: Some(REG::x1),
+            rs2: None,
+            imm: None,
+            shamt: Some(shamt),
+            csr: None,
+        };
+        assert_eq!(encode(&slliw(31)), Ok(0x01f0_909b));
+        assert_eq!(encode(&slliw(32)), Err(EncodeError::ShamtOutOfRange(32)));
+    }
+}
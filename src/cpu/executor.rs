@@ -0,0 +1,286 @@
+use crate::cpu::csr;
+use crate::cpu::defs::*;
+use crate::cpu::CPU;
+
+/// Sign-extend the low `bits` bits of `value` to 64 bits
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+impl CPU {
+    /// Read a source register, with x0 (and an absent operand) reading as zero
+    fn read_reg(&self, reg: &Option<REG>) -> u64 {
+        match reg {
+            Some(reg) => self.registers[reg.to_usize()],
+            None => 0,
+        }
+    }
+
+    /// Write a destination register, discarding writes to x0
+    fn write_reg(&mut self, reg: &Option<REG>, value: u64) {
+        match reg {
+            Some(REG::x0) | None => {}
+            Some(reg) => self.registers[reg.to_usize()] = value,
+        }
+    }
+
+    fn status(&self) -> u64 {
+        self.csrs.read(csr::MSTATUS).unwrap_or(0)
+    }
+
+    /// Execute `instr`, decoded from the word `raw`, and advance the pc
+    pub(super) fn execute(&mut self, instr: &DecodedInstr, raw: u32) -> Result<(), Exception> {
+        let rs1 = self.read_reg(&instr.rs1);
+        let rs2 = self.read_reg(&instr.rs2);
+        let imm = instr.imm.unwrap_or(0) as u64;
+        let shamt = instr.shamt.unwrap_or(0);
+        let pc = self.pc;
+        let mut next_pc = pc.wrapping_add(4);
+
+        let result: Option<u64> = match instr.mnemonic {
+            MNEMONIC::LUI => Some(imm),
+            MNEMONIC::AUIPC => Some(pc.wrapping_add(imm)),
+
+            MNEMONIC::JAL => {
+                next_pc = pc.wrapping_add(imm);
+                Some(pc.wrapping_add(4))
+            }
+            MNEMONIC::JALR => {
+                next_pc = rs1.wrapping_add(imm) & !1;
+                Some(pc.wrapping_add(4))
+            }
+
+            MNEMONIC::BEQ
+            | MNEMONIC::BNE
+            | MNEMONIC::BLT
+            | MNEMONIC::BGE
+            | MNEMONIC::BLTU
+            | MNEMONIC::BGEU => {
+                let taken = match instr.mnemonic {
+                    MNEMONIC::BEQ => rs1 == rs2,
+                    MNEMONIC::BNE => rs1 != rs2,
+                    MNEMONIC::BLT => (rs1 as i64) < (rs2 as i64),
+                    MNEMONIC::BGE => (rs1 as i64) >= (rs2 as i64),
+                    MNEMONIC::BLTU => rs1 < rs2,
+                    _ => rs1 >= rs2,
+                };
+                if taken {
+                    next_pc = pc.wrapping_add(imm);
+                }
+                None
+            }
+
+            MNEMONIC::LB
+            | MNEMONIC::LH
+            | MNEMONIC::LW
+            | MNEMONIC::LD
+            | MNEMONIC::LBU
+            | MNEMONIC::LHU
+            | MNEMONIC::LWU => {
+                let addr = rs1.wrapping_add(imm);
+                let (size, signed) = match instr.mnemonic {
+                    MNEMONIC::LB => (1, true),
+                    MNEMONIC::LH => (2, true),
+                    MNEMONIC::LW => (4, true),
+                    MNEMONIC::LD => (8, false),
+                    MNEMONIC::LBU => (1, false),
+                    MNEMONIC::LHU => (2, false),
+                    _ => (4, false),
+                };
+                let value = self.read(addr, size)?;
+                Some(if signed {
+                    sign_extend(value, 8 * size as u32)
+                } else {
+                    value
+                })
+            }
+
+            MNEMONIC::SB | MNEMONIC::SH | MNEMONIC::SW | MNEMONIC::SD => {
+                let addr = rs1.wrapping_add(imm);
+                let size = match instr.mnemonic {
+                    MNEMONIC::SB => 1,
+                    MNEMONIC::SH => 2,
+                    MNEMONIC::SW => 4,
+                    _ => 8,
+                };
+                self.write(addr, size, rs2)?;
+                None
+            }
+
+            MNEMONIC::ADDI => Some(rs1.wrapping_add(imm)),
+            MNEMONIC::SLTI => Some(((rs1 as i64) < (imm as i64)) as u64),
+            MNEMONIC::SLTIU => Some((rs1 < imm) as u64),
+            MNEMONIC::XORI => Some(rs1 ^ imm),
+            MNEMONIC::ORI => Some(rs1 | imm),
+            MNEMONIC::ANDI => Some(rs1 & imm),
+            MNEMONIC::SLLI => Some(rs1 << shamt),
+            MNEMONIC::SRLI => Some(rs1 >> shamt),
+            MNEMONIC::SRAI => Some(((rs1 as i64) >> shamt) as u64),
+
+            MNEMONIC::ADD => Some(rs1.wrapping_add(rs2)),
+            MNEMONIC::SUB => Some(rs1.wrapping_sub(rs2)),
+            MNEMONIC::SLL => Some(rs1 << (rs2 & 0b11_1111)),
+            MNEMONIC::SLT => Some(((rs1 as i64) < (rs2 as i64)) as u64),
+            MNEMONIC::SLTU => Some((rs1 < rs2) as u64),
+            MNEMONIC::XOR => Some(rs1 ^ rs2),
+            MNEMONIC::SRL => Some(rs1 >> (rs2 & 0b11_1111)),
+            MNEMONIC::SRA => Some(((rs1 as i64) >> (rs2 & 0b11_1111)) as u64),
+            MNEMONIC::OR => Some(rs1 | rs2),
+            MNEMONIC::AND => Some(rs1 & rs2),
+
+            // a single hart with no caches observes its own memory accesses
+            // in order and always fetches through the bus, so fences need
+            // no work
+            MNEMONIC::FENCE | MNEMONIC::FENCE_I => None,
+            MNEMONIC::ECALL => return Err(Exception::EnvironmentCall),
+            MNEMONIC::EBREAK => return Err(Exception::Breakpoint(pc)),
+            MNEMONIC::MRET => {
+                if self.csrs.privilege() != Privilege::Machine {
+                    return Err(Exception::IllegalInstruction(raw));
+                }
+                next_pc = self.csrs.mret();
+                None
+            }
+            MNEMONIC::SRET => {
+                let trapped = match self.csrs.privilege() {
+                    Privilege::User => true,
+                    Privilege::Supervisor => self.status() & csr::MSTATUS_TSR != 0,
+                    Privilege::Machine => false,
+                };
+                if trapped {
+                    return Err(Exception::IllegalInstruction(raw));
+                }
+                next_pc = self.csrs.sret();
+                None
+            }
+            MNEMONIC::SFENCE_VMA => {
+                let trapped = match self.csrs.privilege() {
+                    Privilege::User => true,
+                    Privilege::Supervisor => self.status() & csr::MSTATUS_TVM != 0,
+                    Privilege::Machine => false,
+                };
+                if trapped {
+                    return Err(Exception::IllegalInstruction(raw));
+                }
+                let vaddr = instr.rs1.filter(|reg| *reg != REG::x0).map(|_| rs1);
+                let asid = instr.rs2.filter(|reg| *reg != REG::x0).map(|_| rs2 as u16);
+                self.mmu.fence(vaddr, asid);
+                None
+            }
+            // interrupts are checked before every instruction anyway, so
+            // WFI completes at once, unless TW makes it illegal below M-mode
+            MNEMONIC::WFI => {
+                if self.csrs.privilege() != Privilege::Machine
+                    && self.status() & csr::MSTATUS_TW != 0
+                {
+                    return Err(Exception::IllegalInstruction(raw));
+                }
+                None
+            }
+
+            MNEMONIC::CSRRW
+            | MNEMONIC::CSRRS
+            | MNEMONIC::CSRRC
+            | MNEMONIC::CSRRWI
+            | MNEMONIC::CSRRSI
+            | MNEMONIC::CSRRCI => {
+                let addr = instr.csr.unwrap_or(0);
+                let illegal = Exception::IllegalInstruction(raw);
+                if !self.csrs.accessible(addr) {
+                    return Err(illegal);
+                }
+                let (source, source_is_zero) = match instr.mnemonic {
+                    MNEMONIC::CSRRW | MNEMONIC::CSRRS | MNEMONIC::CSRRC => {
+                        (rs1, matches!(instr.rs1, Some(REG::x0) | None))
+                    }
+                    _ => (imm, imm == 0),
+                };
+                let (reads, writes) = match instr.mnemonic {
+                    MNEMONIC::CSRRW | MNEMONIC::CSRRWI => {
+                        (!matches!(instr.rd, Some(REG::x0) | None), true)
+                    }
+                    _ => (true, !source_is_zero),
+                };
+                let old = if reads {
+                    self.csrs.read(addr).ok_or(illegal)?
+                } else {
+                    0
+                };
+                if writes {
+                    let value = match instr.mnemonic {
+                        MNEMONIC::CSRRW | MNEMONIC::CSRRWI => source,
+                        MNEMONIC::CSRRS | MNEMONIC::CSRRSI => old | source,
+                        _ => old & !source,
+                    };
+                    self.csrs
+                        .write(addr, value)
+                        .ok_or(Exception::IllegalInstruction(raw))?;
+                    // cached translations may belong to the old mode
+                    if addr == csr::SATP {
+                        self.mmu.fence(None, None);
+                    }
+                }
+                Some(old)
+            }
+
+            MNEMONIC::ADDIW => Some(sign_extend(rs1.wrapping_add(imm), 32)),
+            MNEMONIC::SLLIW => Some(sign_extend(((rs1 as u32) << shamt) as u64, 32)),
+            MNEMONIC::SRLIW => Some(sign_extend(((rs1 as u32) >> shamt) as u64, 32)),
+            MNEMONIC::SRAIW => Some(((rs1 as i32) >> shamt) as u64),
+            MNEMONIC::ADDW => Some(sign_extend(rs1.wrapping_add(rs2), 32)),
+            MNEMONIC::SUBW => Some(sign_extend(rs1.wrapping_sub(rs2), 32)),
+            MNEMONIC::SLLW => Some(sign_extend(((rs1 as u32) << (rs2 & 0b1_1111)) as u64, 32)),
+            MNEMONIC::SRLW => Some(sign_extend(((rs1 as u32) >> (rs2 & 0b1_1111)) as u64, 32)),
+            MNEMONIC::SRAW => Some(((rs1 as i32) >> (rs2 & 0b1_1111)) as u64),
+
+            MNEMONIC::MUL => Some(rs1.wrapping_mul(rs2)),
+            MNEMONIC::MULH => {
+                Some(((rs1 as i64 as i128).wrapping_mul(rs2 as i64 as i128) >> 64) as u64)
+            }
+            MNEMONIC::MULHSU => Some(((rs1 as i64 as i128).wrapping_mul(rs2 as i128) >> 64) as u64),
+            MNEMONIC::MULHU => Some(((rs1 as u128 * rs2 as u128) >> 64) as u64),
+            MNEMONIC::DIV => Some(if rs2 == 0 {
+                u64::MAX
+            } else {
+                (rs1 as i64).wrapping_div(rs2 as i64) as u64
+            }),
+            MNEMONIC::DIVU => Some(rs1.checked_div(rs2).unwrap_or(u64::MAX)),
+            MNEMONIC::REM => Some(if rs2 == 0 {
+                rs1
+            } else {
+                (rs1 as i64).wrapping_rem(rs2 as i64) as u64
+            }),
+            MNEMONIC::REMU => Some(rs1.checked_rem(rs2).unwrap_or(rs1)),
+
+            MNEMONIC::MULW => Some(sign_extend(rs1.wrapping_mul(rs2), 32)),
+            MNEMONIC::DIVW => Some(if rs2 as u32 == 0 {
+                u64::MAX
+            } else {
+                (rs1 as i32).wrapping_div(rs2 as i32) as u64
+            }),
+            MNEMONIC::DIVUW => Some(
+                (rs1 as u32)
+                    .checked_div(rs2 as u32)
+                    .map_or(u64::MAX, |q| q as i32 as u64),
+            ),
+            MNEMONIC::REMW => Some(if rs2 as u32 == 0 {
+                rs1 as i32 as u64
+            } else {
+                (rs1 as i32).wrapping_rem(rs2 as i32) as u64
+            }),
+            MNEMONIC::REMUW => {
+                Some((rs1 as u32).checked_rem(rs2 as u32).unwrap_or(rs1 as u32) as i32 as u64)
+            }
+        };
+
+        if next_pc & 0b11 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(next_pc));
+        }
+        if let Some(value) = result {
+            self.write_reg(&instr.rd, value);
+        }
+        self.pc = next_pc;
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use crate::bus::{AddressMap, Bus};
+use crate::cpu::csr;
+use crate::cpu::defs::*;
+
+/// Kind of memory access being translated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    /// Page fault for this kind of access to `vaddr`
+    fn page_fault(self, vaddr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(vaddr),
+            Access::Load => Exception::LoadPageFault(vaddr),
+            Access::Store => Exception::StorePageFault(vaddr),
+        }
+    }
+
+    /// Access fault for this kind of access to `vaddr`
+    fn access_fault(self, vaddr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(vaddr),
+            Access::Load => Exception::LoadAccessFault(vaddr),
+            Access::Store => Exception::StoreAccessFault(vaddr),
+        }
+    }
+}
+
+/// PTE bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// PTE bits 63:54 (N, PBMT and reserved); Svnapot and Svpbmt are not
+/// implemented, so they must be zero
+const PTE_RESERVED: u64 = 0x3ff << 54;
+
+const PAGE_SHIFT: u32 = 12;
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+/// Number of TLB entries; must be a power of two
+const TLB_SIZE: usize = 256;
+
+/// A cached leaf translation of one 4 KiB virtual page
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    vpn: u64,
+    asid: u16,
+    /// Physical page number of the 4 KiB page, also for superpages
+    ppn: u64,
+    /// Low eight PTE bits, after the A/D update
+    flags: u64,
+}
+
+/// Sv39/Sv48 address translation with a direct-mapped software TLB
+///
+/// The TLB caches leaf PTEs per 4 KiB page, tagged with the ASID; global
+/// mappings match every ASID. Like hardware, it is only kept coherent with
+/// the page tables by SFENCE.VMA (`fence`), plus a full flush on every satp
+/// write.
+#[derive(Debug)]
+pub struct Mmu {
+    tlb: Vec<Option<TlbEntry>>,
+}
+
+impl Mmu {
+    pub fn new() -> Mmu {
+        Mmu {
+            tlb: vec![None; TLB_SIZE],
+        }
+    }
+
+    /// SFENCE.VMA: drop the cached translations for `vaddr` (all pages if
+    /// None) in address space `asid` (all address spaces if None); global
+    /// mappings are only dropped when no ASID is given
+    pub fn fence(&mut self, vaddr: Option<u64>, asid: Option<u16>) {
+        let vpn = vaddr.map(|vaddr| vaddr >> PAGE_SHIFT);
+        for slot in self.tlb.iter_mut() {
+            let matches = slot.is_some_and(|entry| {
+                vpn.is_none_or(|vpn| entry.vpn == vpn)
+                    && asid.is_none_or(|asid| entry.asid == asid && entry.flags & PTE_G == 0)
+            });
+            if matches {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Translate `vaddr` for an access at `privilege`, walking the page
+    /// tables on a TLB miss and updating their A/D bits
+    ///
+    /// `csrs` supplies satp and mstatus.SUM/MXR; `privilege` is the
+    /// effective mode, after MPRV for loads and stores. M-mode accesses and
+    /// Bare mode are not translated.
+    pub fn translate(
+        &mut self,
+        bus: &mut AddressMap,
+        csrs: &csr::CsrFile,
+        vaddr: u64,
+        access: Access,
+        privilege: Privilege,
+    ) -> Result<u64, Exception> {
+        let satp = csrs.read(csr::SATP).unwrap_or(0);
+        let levels = match satp >> 60 {
+            csr::SATP_MODE_SV39 => 3,
+            csr::SATP_MODE_SV48 => 4,
+            _ => return Ok(vaddr),
+        };
+        if privilege == Privilege::Machine {
+            return Ok(vaddr);
+        }
+        let status = csrs.read(csr::MSTATUS).unwrap_or(0);
+        let asid = (satp >> 44) as u16;
+        let vpn = vaddr >> PAGE_SHIFT;
+        let offset = vaddr & ((1 << PAGE_SHIFT) - 1);
+
+        let slot = vpn as usize & (TLB_SIZE - 1);
+        if let Some(entry) = self.tlb[slot] {
+            let hit = entry.vpn == vpn && (entry.flags & PTE_G != 0 || entry.asid == asid);
+            // a store to a clean page walks again to set D
+            if hit && (access != Access::Store || entry.flags & PTE_D != 0) {
+                if !permitted(entry.flags, access, privilege, status) {
+                    return Err(access.page_fault(vaddr));
+                }
+                return Ok(entry.ppn << PAGE_SHIFT | offset);
+            }
+        }
+
+        // virtual addresses must be sign-extended from the top VA bit
+        let va_bits = PAGE_SHIFT + 9 * levels;
+        if ((vaddr as i64) << (64 - va_bits) >> (64 - va_bits)) as u64 != vaddr {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let mut table = (satp & PPN_MASK) << PAGE_SHIFT;
+        for level in (0..levels).rev() {
+            let index = (vaddr >> (PAGE_SHIFT + 9 * level)) & 0x1ff;
+            let pte_addr = table + 8 * index;
+            let mut pte = bus
+                .read64(pte_addr)
+                .map_err(|_| access.access_fault(vaddr))?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault(vaddr));
+            }
+            if pte & PTE_RESERVED != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+            let ppn = (pte >> 10) & PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+
+            if !permitted(pte, access, privilege, status) {
+                return Err(access.page_fault(vaddr));
+            }
+            // superpages must be aligned to their size
+            let superpage_mask = (1 << (9 * level)) - 1;
+            if ppn & superpage_mask != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+            let updated = pte | PTE_A | if access == Access::Store { PTE_D } else { 0 };
+            if updated != pte {
+                bus.write64(pte_addr, updated)
+                    .map_err(|_| access.access_fault(vaddr))?;
+                pte = updated;
+            }
+
+            let ppn = ppn | (vpn & superpage_mask);
+            self.tlb[slot] = Some(TlbEntry {
+                vpn,
+                asid,
+                ppn,
+                flags: pte & 0xff,
+            });
+            return Ok(ppn << PAGE_SHIFT | offset);
+        }
+        // no leaf by level 0
+        Err(access.page_fault(vaddr))
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Mmu::new()
+    }
+}
+
+/// Whether a leaf PTE allows `access` at `privilege` under mstatus `status`
+fn permitted(pte: u64, access: Access, privilege: Privilege, status: u64) -> bool {
+    let user_page = pte & PTE_U != 0;
+    let mode_ok = match privilege {
+        Privilege::User => user_page,
+        // S-mode never executes user pages and only touches their data
+        // with SUM set
+        _ => !user_page || (access != Access::Fetch && status & csr::MSTATUS_SUM != 0),
+    };
+    let kind_ok = match access {
+        Access::Fetch => pte & PTE_X != 0,
+        Access::Load => pte & PTE_R != 0 || (pte & PTE_X != 0 && status & csr::MSTATUS_MXR != 0),
+        Access::Store => pte & PTE_W != 0,
+    };
+    mode_ok && kind_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::csr::*;
+    use crate::cpu::mmu::*;
+
+    const ROOT: u64 = 0x1000;
+
+    /// RAM with a three-level Sv39 table at ROOT mapping:
+    /// - 0x4000_0000 (1 GiB page) -> 0 with `giga` flags
+    /// - 0x1000 -> 0x5000, R|W|U, via tables at 0x2000 and 0x3000
+    /// - 0x2000 -> 0x6000, X only
+    fn setup(giga: u64) -> (AddressMap, CsrFile, Mmu) {
+        let mut bus = AddressMap::new();
+        bus.map_ram(0, 0x10_0000);
+        let pte = |ppn: u64, flags: u64| ppn << 10 | flags;
+        bus.write64(ROOT + 8, pte(0, giga)).unwrap();
+        bus.write64(ROOT, pte(2, PTE_V)).unwrap();
+        bus.write64(0x2000, pte(3, PTE_V)).unwrap();
+        bus.write64(0x3000 + 8, pte(5, PTE_V | PTE_R | PTE_W | PTE_U))
+            .unwrap();
+        bus.write64(0x3000 + 16, pte(6, PTE_V | PTE_X)).unwrap();
+        let mut csrs = CsrFile::new();
+        csrs.write(SATP, SATP_MODE_SV39 << 60 | ROOT >> 12).unwrap();
+        (bus, csrs, Mmu::new())
+    }
+
+    #[test]
+    fn test_walk_and_permissions() {
+        let (mut bus, mut csrs, mut mmu) = setup(PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D);
+        let mut translate = |bus: &mut AddressMap, csrs: &CsrFile, vaddr, access, privilege| {
+            mmu.translate(bus, csrs, vaddr, access, privilege)
+        };
+        use Access::*;
+        use Privilege::*;
+
+        assert_eq!(translate(&mut bus, &csrs, 0x1234, Load, User), Ok(0x5234));
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x2ffc, Fetch, Supervisor),
+            Ok(0x6ffc)
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x4012_3456, Store, Supervisor),
+            Ok(0x12_3456)
+        );
+        // M-mode is not translated
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x1234, Load, Machine),
+            Ok(0x1234)
+        );
+
+        // unmapped, non-canonical, wrong kind and wrong mode
+        let fault = Exception::LoadPageFault;
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x3000, Load, Supervisor),
+            Err(fault(0x3000))
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 1 << 40, Load, Supervisor),
+            Err(fault(1 << 40))
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x2000, Store, Supervisor),
+            Err(Exception::StorePageFault(0x2000))
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x2000, Load, Supervisor),
+            Err(fault(0x2000))
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x4000_0000, Load, User),
+            Err(fault(0x4000_0000))
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x1000, Load, Supervisor),
+            Err(fault(0x1000))
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x1000, Fetch, Supervisor),
+            Err(Exception::InstructionPageFault(0x1000))
+        );
+
+        // SUM lets S-mode touch user data; MXR makes executable pages readable
+        csrs.write(MSTATUS, MSTATUS_SUM | MSTATUS_MXR).unwrap();
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x1000, Load, Supervisor),
+            Ok(0x5000)
+        );
+        assert_eq!(
+            translate(&mut bus, &csrs, 0x2000, Load, Supervisor),
+            Ok(0x6000)
+        );
+        assert!(translate(&mut bus, &csrs, 0x1000, Fetch, Supervisor).is_err());
+    }
+
+    #[test]
+    fn test_accessed_and_dirty_bits() {
+        let (mut bus, csrs, mut mmu) = setup(PTE_V | PTE_R | PTE_W);
+        let pte = 0x3000 + 8;
+        mmu.translate(&mut bus, &csrs, 0x1000, Access::Load, Privilege::User)
+            .unwrap();
+        assert_eq!(bus.read64(pte).unwrap() & (PTE_A | PTE_D), PTE_A);
+        // the cached clean entry walks again for a store
+        mmu.translate(&mut bus, &csrs, 0x1008, Access::Store, Privilege::User)
+            .unwrap();
+        assert_eq!(bus.read64(pte).unwrap() & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+        mmu.translate(
+            &mut bus,
+            &csrs,
+            0x4000_1000,
+            Access::Store,
+            Privilege::Supervisor,
+        )
+        .unwrap();
+        assert_eq!(
+            bus.read64(ROOT + 8).unwrap() & (PTE_A | PTE_D),
+            PTE_A | PTE_D
+        );
+    }
+
+    #[test]
+    fn test_misaligned_superpage_and_reserved_bits() {
+        let (mut bus, csrs, mut mmu) = setup(PTE_V | PTE_R);
+        bus.write64(ROOT + 8, 1 << 10 | PTE_V | PTE_R).unwrap();
+        assert_eq!(
+            mmu.translate(
+                &mut bus,
+                &csrs,
+                0x4000_0000,
+                Access::Load,
+                Privilege::Supervisor
+            ),
+            Err(Exception::LoadPageFault(0x4000_0000))
+        );
+        bus.write64(ROOT + 8, 1 << 61 | PTE_V | PTE_R).unwrap();
+        assert_eq!(
+            mmu.translate(
+                &mut bus,
+                &csrs,
+                0x4000_0000,
+                Access::Load,
+                Privilege::Supervisor
+            ),
+            Err(Exception::LoadPageFault(0x4000_0000))
+        );
+    }
+
+    #[test]
+    fn test_tlb_and_fence() {
+        let (mut bus, csrs, mut mmu) = setup(PTE_V | PTE_R | PTE_G);
+        let load = |mmu: &mut Mmu, bus: &mut AddressMap, vaddr| {
+            mmu.translate(bus, &csrs, vaddr, Access::Load, Privilege::User)
+        };
+        assert_eq!(load(&mut mmu, &mut bus, 0x1000), Ok(0x5000));
+        // remap without a fence: the stale translation is still used
+        bus.write64(0x3000 + 8, 7 << 10 | PTE_V | PTE_R | PTE_U | PTE_A)
+            .unwrap();
+        assert_eq!(load(&mut mmu, &mut bus, 0x1000), Ok(0x5000));
+        mmu.fence(Some(0x2000), None);
+        assert_eq!(load(&mut mmu, &mut bus, 0x1000), Ok(0x5000));
+        mmu.fence(Some(0x1abc), Some(5));
+        assert_eq!(load(&mut mmu, &mut bus, 0x1000), Ok(0x5000));
+        mmu.fence(Some(0x1abc), Some(0));
+        assert_eq!(load(&mut mmu, &mut bus, 0x1000), Ok(0x7000));
+
+        // global mappings survive an ASID-specific fence
+        mmu.translate(
+            &mut bus,
+            &csrs,
+            0x4000_0000,
+            Access::Load,
+            Privilege::Supervisor,
+        )
+        .unwrap();
+        bus.write64(ROOT + 8, 0).unwrap();
+        mmu.fence(None, Some(0));
+        assert!(mmu
+            .translate(
+                &mut bus,
+                &csrs,
+                0x4000_0000,
+                Access::Load,
+                Privilege::Supervisor
+            )
+            .is_ok());
+        mmu.fence(None, None);
+        assert!(mmu
+            .translate(
+                &mut bus,
+                &csrs,
+                0x4000_0000,
+                Access::Load,
+                Privilege::Supervisor
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_sv48() {
+        let (mut bus, mut csrs, mut mmu) = setup(PTE_V | PTE_R);
+        // one more level above the Sv39 root, covering VA 512 GiB-1 TiB
+        bus.write64(0x8000 + 8, (ROOT >> 12) << 10 | PTE_V).unwrap();
+        csrs.write(SATP, SATP_MODE_SV48 << 60 | 0x8).unwrap();
+        assert_eq!(
+            mmu.translate(
+                &mut bus,
+                &csrs,
+                (1 << 39) | 0x1234,
+                Access::Load,
+                Privilege::User
+            ),
+            Ok(0x5234)
+        );
+        // 2^40 is canonical in Sv48 but unmapped
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 1 << 40, Access::Load, Privilege::User),
+            Err(Exception::LoadPageFault(1 << 40))
+        );
+    }
+}
@@ -0,0 +1,615 @@
+use std::fmt;
+
+use crate::bus::{Bus, BusFault};
+use crate::cpu::CPU;
+
+const ELFMAG: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+/// Segment flags marking executable, writable and readable segments
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const STB_GLOBAL: u8 = 1;
+const SHN_ABS: u16 = 0xfff1;
+/// Alignment of the loadable segments in written executables
+const PAGE_SIZE: u64 = 0x1000;
+/// Bytes of a segment's bss zero-filled per bus write
+const ZERO_CHUNK: usize = 0x1000;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+/// Reasons an ELF image cannot be loaded
+#[derive(Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file does not start with the ELF magic number
+    NotElf,
+    /// EI_CLASS is not ELFCLASS64 (carries the class found)
+    WrongClass(u8),
+    /// EI_DATA is not little-endian (carries the encoding found)
+    WrongEndianness(u8),
+    /// e_machine is not EM_RISCV (carries the machine found)
+    WrongMachine(u16),
+    /// e_type is not an executable (carries the type found)
+    NotExecutable(u16),
+    /// A header or segment extends past the end of the file
+    Truncated,
+    /// A segment is smaller in memory than in the file, or its memory
+    /// wraps past the end of the address space (carries its address)
+    BadSegment(u64),
+    /// A segment could not be written to the bus
+    Bus(BusFault),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file (bad magic number)"),
+            ElfError::WrongClass(1) => write!(f, "32-bit ELF file, expected ELF64"),
+            ElfError::WrongClass(class) => write!(f, "unknown ELF class {}, expected ELF64", class),
+            ElfError::WrongEndianness(2) => {
+                write!(f, "big-endian ELF file, expected little-endian")
+            }
+            ElfError::WrongEndianness(data) => {
+                write!(
+                    f,
+                    "unknown ELF data encoding {}, expected little-endian",
+                    data
+                )
+            }
+            ElfError::WrongMachine(machine) => write!(
+                f,
+                "ELF machine {} is not RISC-V (EM_RISCV = {})",
+                machine, EM_RISCV
+            ),
+            ElfError::NotExecutable(ET_REL) => {
+                write!(f, "relocatable object file, expected a linked executable")
+            }
+            ElfError::NotExecutable(kind) => {
+                write!(f, "ELF type {} is not an executable", kind)
+            }
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::BadSegment(addr) => {
+                write!(f, "segment at {:#x} has an invalid memory size", addr)
+            }
+            ElfError::Bus(BusFault::Unmapped(addr)) => {
+                write!(f, "segment address {:#x} is not mapped to memory", addr)
+            }
+            ElfError::Bus(BusFault::Denied(addr)) => {
+                write!(f, "segment address {:#x} is not writable", addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// A PT_LOAD program header
+#[derive(Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub offset: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub flags: u32,
+}
+
+/// An entry of the ELF symbol table
+#[derive(Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    pub info: u8,
+}
+
+/// A parsed RISC-V ELF64 executable
+#[derive(Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+}
+
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .map(|b| b.try_into().unwrap())
+        .ok_or(ElfError::Truncated)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    bytes(data, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    bytes(data, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    bytes(data, offset).map(u64::from_le_bytes)
+}
+
+/// Slice `len` bytes at `offset`, both taken from 64-bit header fields
+fn range(data: &[u8], offset: u64, len: u64) -> Result<&[u8], ElfError> {
+    let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+    let len = usize::try_from(len).map_err(|_| ElfError::Truncated)?;
+    start
+        .checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or(ElfError::Truncated)
+}
+
+/// NUL-terminated string at `offset` of a string table
+fn c_str(strtab: &[u8], offset: usize) -> String {
+    let tail = strtab.get(offset..).unwrap_or(&[]);
+    let len = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+    String::from_utf8_lossy(&tail[..len]).into_owned()
+}
+
+impl<'a> Elf<'a> {
+    /// Parse and validate the headers, program headers and symbol table
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < 4 || data[..4] != ELFMAG {
+            return Err(ElfError::NotElf);
+        }
+        let ident: [u8; 16] = bytes(data, 0)?;
+        if ident[4] != ELFCLASS64 {
+            return Err(ElfError::WrongClass(ident[4]));
+        }
+        if ident[5] != ELFDATA2LSB {
+            return Err(ElfError::WrongEndianness(ident[5]));
+        }
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        let machine = u16_at(data, 18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::WrongMachine(machine));
+        }
+        let kind = u16_at(data, 16)?;
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(ElfError::NotExecutable(kind));
+        }
+
+        let entry = u64_at(data, 24)?;
+        let phoff = u64_at(data, 32)?;
+        let shoff = u64_at(data, 40)?;
+        let phnum = u16_at(data, 56)? as u64;
+        let shnum = u16_at(data, 60)? as u64;
+        let phdrs = range(data, phoff, phnum * PHDR_SIZE as u64)?;
+        let shdrs = range(data, shoff, shnum * SHDR_SIZE as u64)?;
+
+        let mut segments = Vec::new();
+        for ph in phdrs.chunks_exact(PHDR_SIZE) {
+            if u32_at(ph, 0)? != PT_LOAD {
+                continue;
+            }
+            let segment = Segment {
+                flags: u32_at(ph, 4)?,
+                offset: u64_at(ph, 8)?,
+                vaddr: u64_at(ph, 16)?,
+                paddr: u64_at(ph, 24)?,
+                filesz: u64_at(ph, 32)?,
+                memsz: u64_at(ph, 40)?,
+            };
+            range(data, segment.offset, segment.filesz)?;
+            if segment.memsz < segment.filesz
+                || segment.paddr.checked_add(segment.memsz).is_none()
+                || segment.vaddr.checked_add(segment.memsz).is_none()
+            {
+                return Err(ElfError::BadSegment(segment.paddr));
+            }
+            segments.push(segment);
+        }
+
+        let mut symbols = Vec::new();
+        for sh in shdrs.chunks_exact(SHDR_SIZE) {
+            if u32_at(sh, 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let symtab = range(data, u64_at(sh, 24)?, u64_at(sh, 32)?)?;
+            let link = u32_at(sh, 40)? as usize;
+            let strsh = shdrs
+                .chunks_exact(SHDR_SIZE)
+                .nth(link)
+                .ok_or(ElfError::Truncated)?;
+            let strtab = range(data, u64_at(strsh, 24)?, u64_at(strsh, 32)?)?;
+            for sym in symtab.chunks_exact(SYM_SIZE) {
+                symbols.push(Symbol {
+                    name: c_str(strtab, u32_at(sym, 0)? as usize),
+                    info: sym[4],
+                    value: u64_at(sym, 8)?,
+                    size: u64_at(sym, 16)?,
+                });
+            }
+        }
+
+        Ok(Elf {
+            data,
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    /// The bytes of `segment` present in the file (its first `filesz` bytes)
+    pub fn contents(&self, segment: &Segment) -> &'a [u8] {
+        range(self.data, segment.offset, segment.filesz).unwrap_or_default()
+    }
+
+    /// Look up a named symbol
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    /// Copy every PT_LOAD segment to its physical address on `bus`,
+    /// zero-filling the part of each segment beyond its file contents
+    pub fn load_segments<B: Bus + ?Sized>(&self, bus: &mut B) -> Result<(), ElfError> {
+        for segment in &self.segments {
+            bus.load(segment.paddr, self.contents(segment))
+                .map_err(ElfError::Bus)?;
+            // parse checked that the segment does not wrap
+            let end = segment.paddr + segment.memsz;
+            let mut addr = segment.paddr + segment.filesz;
+            while addr < end {
+                let len = (end - addr).min(ZERO_CHUNK as u64);
+                bus.load(addr, &[0; ZERO_CHUNK][..len as usize])
+                    .map_err(ElfError::Bus)?;
+                addr += len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the segments into the CPU's memory and point its pc at the entry
+    pub fn load(&self, cpu: &mut CPU) -> Result<(), ElfError> {
+        self.load_segments(&mut cpu.bus)?;
+        cpu.set_pc(self.entry);
+        Ok(())
+    }
+}
+
+/// Parse `data` as a RISC-V executable and load it into `cpu`
+pub fn load_elf<'a>(cpu: &mut CPU, data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+    let elf = Elf::parse(data)?;
+    elf.load(cpu)?;
+    Ok(elf)
+}
+
+/// A loadable section of an executable written by `write_executable`
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub address: u64,
+    /// PF_R, PF_W and PF_X flags of its segment
+    pub flags: u32,
+    pub data: &'a [u8],
+}
+
+/// Append `bytes` to `out` at the next multiple of `align`, returning their
+/// offset
+fn append(out: &mut Vec<u8>, bytes: &[u8], align: u64) -> u64 {
+    let offset = (out.len() as u64).next_multiple_of(align);
+    out.resize(offset as usize, 0);
+    out.extend_from_slice(bytes);
+    offset
+}
+
+/// Append a NUL-terminated `name` to a string table, returning its offset
+fn add_name(strtab: &mut Vec<u8>, name: &str) -> u32 {
+    let offset = strtab.len() as u32;
+    strtab.extend_from_slice(name.as_bytes());
+    strtab.push(0);
+    offset
+}
+
+/// Write a minimal RISC-V ELF64 executable
+///
+/// Each section becomes one PT_LOAD segment with a matching section header,
+/// and `symbols` become global symbols in a symbol table, so that `Elf`
+/// (and binutils) can read the image back.
+pub fn write_executable(entry: u64, sections: &[Section], symbols: &[(&str, u64)]) -> Vec<u8> {
+    let phoff = EHDR_SIZE as u64;
+    let mut out = vec![0u8; EHDR_SIZE + sections.len() * PHDR_SIZE];
+
+    // section headers: null, the sections, .symtab, .strtab, .shstrtab
+    let mut shstrtab = vec![0u8];
+    let mut shdrs = vec![[0u8; SHDR_SIZE]];
+    let shdr = |name: u32, kind: u32, flags: u64, addr: u64, offset: u64, size: u64| {
+        let mut sh = [0u8; SHDR_SIZE];
+        sh[0..4].copy_from_slice(&name.to_le_bytes());
+        sh[4..8].copy_from_slice(&kind.to_le_bytes());
+        sh[8..16].copy_from_slice(&flags.to_le_bytes());
+        sh[16..24].copy_from_slice(&addr.to_le_bytes());
+        sh[24..32].copy_from_slice(&offset.to_le_bytes());
+        sh[32..40].copy_from_slice(&size.to_le_bytes());
+        sh
+    };
+
+    for (i, section) in sections.iter().enumerate() {
+        // keep file offsets congruent to addresses modulo the page size
+        let pad =
+            (section.address % PAGE_SIZE + PAGE_SIZE - out.len() as u64 % PAGE_SIZE) % PAGE_SIZE;
+        out.resize(out.len() + pad as usize, 0);
+        let offset = append(&mut out, section.data, 1);
+        let size = section.data.len() as u64;
+
+        let ph = &mut out[EHDR_SIZE + i * PHDR_SIZE..EHDR_SIZE + (i + 1) * PHDR_SIZE];
+        ph[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        ph[4..8].copy_from_slice(&section.flags.to_le_bytes());
+        ph[8..16].copy_from_slice(&offset.to_le_bytes());
+        ph[16..24].copy_from_slice(&section.address.to_le_bytes());
+        ph[24..32].copy_from_slice(&section.address.to_le_bytes());
+        ph[32..40].copy_from_slice(&size.to_le_bytes());
+        ph[40..48].copy_from_slice(&size.to_le_bytes());
+        ph[48..56].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+
+        let mut flags = SHF_ALLOC;
+        if section.flags & PF_W != 0 {
+            flags |= SHF_WRITE;
+        }
+        if section.flags & PF_X != 0 {
+            flags |= SHF_EXECINSTR;
+        }
+        let name = add_name(&mut shstrtab, section.name);
+        let mut sh = shdr(name, SHT_PROGBITS, flags, section.address, offset, size);
+        sh[48..56].copy_from_slice(&4u64.to_le_bytes());
+        shdrs.push(sh);
+    }
+
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; SYM_SIZE];
+    for &(name, value) in symbols {
+        // the index of the section holding the symbol, or absolute
+        let shndx = sections
+            .iter()
+            .position(|s| (s.address..s.address + s.data.len() as u64).contains(&value))
+            .map_or(SHN_ABS, |i| i as u16 + 1);
+        let mut sym = [0u8; SYM_SIZE];
+        sym[0..4].copy_from_slice(&add_name(&mut strtab, name).to_le_bytes());
+        sym[4] = STB_GLOBAL << 4;
+        sym[6..8].copy_from_slice(&shndx.to_le_bytes());
+        sym[8..16].copy_from_slice(&value.to_le_bytes());
+        symtab.extend_from_slice(&sym);
+    }
+    let symtab_index = shdrs.len() as u32;
+    let symtab_offset = append(&mut out, &symtab, 8);
+    let name = add_name(&mut shstrtab, ".symtab");
+    let mut sh = shdr(name, SHT_SYMTAB, 0, 0, symtab_offset, symtab.len() as u64);
+    // linked to .strtab, with every symbol after the null one global
+    sh[40..44].copy_from_slice(&(symtab_index + 1).to_le_bytes());
+    sh[44..48].copy_from_slice(&1u32.to_le_bytes());
+    sh[48..56].copy_from_slice(&8u64.to_le_bytes());
+    sh[56..64].copy_from_slice(&(SYM_SIZE as u64).to_le_bytes());
+    shdrs.push(sh);
+
+    let strtab_offset = append(&mut out, &strtab, 1);
+    let name = add_name(&mut shstrtab, ".strtab");
+    shdrs.push(shdr(
+        name,
+        SHT_STRTAB,
+        0,
+        0,
+        strtab_offset,
+        strtab.len() as u64,
+    ));
+    let name = add_name(&mut shstrtab, ".shstrtab");
+    let shstrtab_offset = append(&mut out, &shstrtab, 1);
+    let size = shstrtab.len() as u64;
+    shdrs.push(shdr(name, SHT_STRTAB, 0, 0, shstrtab_offset, size));
+
+    let shoff = append(&mut out, &shdrs.concat(), 8);
+    out[..4].copy_from_slice(&ELFMAG);
+    out[4] = ELFCLASS64;
+    out[5] = ELFDATA2LSB;
+    out[6] = 1; // EV_CURRENT
+    out[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    out[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+    out[20..24].copy_from_slice(&1u32.to_le_bytes());
+    out[24..32].copy_from_slice(&entry.to_le_bytes());
+    out[32..40].copy_from_slice(&phoff.to_le_bytes());
+    out[40..48].copy_from_slice(&shoff.to_le_bytes());
+    out[52..54].copy_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    out[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    out[56..58].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    out[58..60].copy_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+    out[60..62].copy_from_slice(&(shdrs.len() as u16).to_le_bytes());
+    out[62..64].copy_from_slice(&(shdrs.len() as u16 - 1).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::AddressMap;
+    use crate::elf::*;
+
+    /// Build an executable with one PT_LOAD segment at 0x1000 holding
+    /// `text` followed by `bss` zero bytes, and a symbol table with `start`
+    fn build(text: &[u8], bss: u64) -> Vec<u8> {
+        let phoff = EHDR_SIZE;
+        let text_off = phoff + PHDR_SIZE;
+        let strtab: &[u8] = b"\0start\0";
+        let strtab_off = text_off + text.len();
+        let symtab_off = strtab_off + strtab.len();
+        let shoff = symtab_off + 2 * SYM_SIZE;
+
+        let mut out = vec![0u8; shoff + 3 * SHDR_SIZE];
+        out[..4].copy_from_slice(&ELFMAG);
+        out[4] = ELFCLASS64;
+        out[5] = ELFDATA2LSB;
+        out[6] = 1;
+        out[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        out[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        out[24..32].copy_from_slice(&0x1004u64.to_le_bytes());
+        out[32..40].copy_from_slice(&(phoff as u64).to_le_bytes());
+        out[40..48].copy_from_slice(&(shoff as u64).to_le_bytes());
+        out[56..58].copy_from_slice(&1u16.to_le_bytes());
+        out[60..62].copy_from_slice(&3u16.to_le_bytes());
+
+        let ph = &mut out[phoff..phoff + PHDR_SIZE];
+        ph[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        ph[8..16].copy_from_slice(&(text_off as u64).to_le_bytes());
+        ph[16..24].copy_from_slice(&0x1000u64.to_le_bytes());
+        ph[24..32].copy_from_slice(&0x1000u64.to_le_bytes());
+        ph[32..40].copy_from_slice(&(text.len() as u64).to_le_bytes());
+        ph[40..48].copy_from_slice(&(text.len() as u64 + bss).to_le_bytes());
+
+        out[text_off..strtab_off].copy_from_slice(text);
+        out[strtab_off..symtab_off].copy_from_slice(strtab);
+        let sym = &mut out[symtab_off + SYM_SIZE..shoff];
+        sym[0..4].copy_from_slice(&1u32.to_le_bytes());
+        sym[8..16].copy_from_slice(&0x1004u64.to_le_bytes());
+
+        // section 1: strtab, section 2: symtab linked to it
+        let sh = &mut out[shoff + SHDR_SIZE..shoff + 2 * SHDR_SIZE];
+        sh[4..8].copy_from_slice(&3u32.to_le_bytes());
+        sh[24..32].copy_from_slice(&(strtab_off as u64).to_le_bytes());
+        sh[32..40].copy_from_slice(&(strtab.len() as u64).to_le_bytes());
+        let sh = &mut out[shoff + 2 * SHDR_SIZE..];
+        sh[4..8].copy_from_slice(&SHT_SYMTAB.to_le_bytes());
+        sh[24..32].copy_from_slice(&(symtab_off as u64).to_le_bytes());
+        sh[32..40].copy_from_slice(&(2 * SYM_SIZE as u64).to_le_bytes());
+        sh[40..44].copy_from_slice(&1u32.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn test_load_sets_pc_and_zero_fills_bss() {
+        let image = build(&[1, 2, 3, 4, 5, 6, 7, 8], 8);
+        let mut bus = AddressMap::new();
+        bus.map_ram(0x1000, 0x1000);
+        let mut cpu = CPU::with_bus(bus);
+        cpu.bus.load(0x1008, &[0xff; 8]).unwrap();
+
+        let elf = load_elf(&mut cpu, &image).unwrap();
+        assert_eq!(cpu.pc(), 0x1004);
+        assert_eq!(cpu.bus.read64(0x1000), Ok(0x0807_0605_0403_0201));
+        assert_eq!(cpu.bus.read64(0x1008), Ok(0));
+        assert_eq!(elf.symbol("start").map(|sym| sym.value), Some(0x1004));
+        assert_eq!(elf.symbol("missing"), None);
+    }
+
+    #[test]
+    fn test_rejects_wrong_files() {
+        let image = build(&[0; 4], 0);
+
+        assert_eq!(Elf::parse(b"#!/bin/sh\n").unwrap_err(), ElfError::NotElf);
+
+        let mut elf32 = image.clone();
+        elf32[4] = 1;
+        assert_eq!(Elf::parse(&elf32).unwrap_err(), ElfError::WrongClass(1));
+
+        let mut big_endian = image.clone();
+        big_endian[5] = 2;
+        assert_eq!(
+            Elf::parse(&big_endian).unwrap_err(),
+            ElfError::WrongEndianness(2)
+        );
+
+        let mut x86_64 = image.clone();
+        x86_64[18..20].copy_from_slice(&62u16.to_le_bytes());
+        assert_eq!(Elf::parse(&x86_64).unwrap_err(), ElfError::WrongMachine(62));
+
+        let mut object = image.clone();
+        object[16..18].copy_from_slice(&ET_REL.to_le_bytes());
+        assert_eq!(
+            Elf::parse(&object).unwrap_err(),
+            ElfError::NotExecutable(ET_REL)
+        );
+
+        assert_eq!(
+            Elf::parse(&image[..EHDR_SIZE + 8]).unwrap_err(),
+            ElfError::Truncated
+        );
+
+        // memsz below filesz, and memsz running past 2^64
+        let phoff = u64::from_le_bytes(image[32..40].try_into().unwrap()) as usize;
+        let mut short = image.clone();
+        short[phoff + 40..phoff + 48].copy_from_slice(&2u64.to_le_bytes());
+        assert_eq!(
+            Elf::parse(&short).unwrap_err(),
+            ElfError::BadSegment(0x1000)
+        );
+        let mut wrapping = image.clone();
+        wrapping[phoff + 40..phoff + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            Elf::parse(&wrapping).unwrap_err(),
+            ElfError::BadSegment(0x1000)
+        );
+    }
+
+    #[test]
+    fn test_huge_bss_is_not_allocated() {
+        // 1 TiB of bss fails at the first chunk that runs out of RAM
+        let image = build(&[0; 4], 1 << 40);
+        let mut bus = AddressMap::new();
+        bus.map_ram(0x1000, 0x2000);
+        let mut cpu = CPU::with_bus(bus);
+        assert_eq!(
+            load_elf(&mut cpu, &image).unwrap_err(),
+            ElfError::Bus(BusFault::Unmapped(0x2004))
+        );
+    }
+
+    #[test]
+    fn test_unmapped_segment() {
+        let image = build(&[0; 4], 0);
+        let mut cpu = CPU::with_bus(AddressMap::new());
+        assert_eq!(
+            load_elf(&mut cpu, &image).unwrap_err(),
+            ElfError::Bus(BusFault::Unmapped(0x1000))
+        );
+    }
+
+    #[test]
+    fn test_write_executable() {
+        let text = [0x13, 0x05, 0xa0, 0x02];
+        let data = [1, 2, 3];
+        let image = write_executable(
+            0x8000_0000,
+            &[
+                Section {
+                    name: ".text",
+                    address: 0x8000_0000,
+                    flags: PF_R | PF_X,
+                    data: &text,
+                },
+                Section {
+                    name: ".data",
+                    address: 0x8000_1000,
+                    flags: PF_R | PF_W,
+                    data: &data,
+                },
+            ],
+            &[("_start", 0x8000_0000), ("tohost", 0x8000_1001)],
+        );
+
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.entry, 0x8000_0000);
+        assert_eq!(elf.segments.len(), 2);
+        assert_eq!(elf.segments[0].flags, PF_R | PF_X);
+        assert_eq!(elf.contents(&elf.segments[0]), text);
+        assert_eq!(elf.segments[1].vaddr, 0x8000_1000);
+        assert_eq!(elf.contents(&elf.segments[1]), data);
+        assert_eq!(elf.segments[1].offset % 0x1000, 0);
+        assert_eq!(elf.symbol("tohost").map(|s| s.value), Some(0x8000_1001));
+        assert_eq!(elf.symbol("_start").map(|s| s.value), Some(0x8000_0000));
+    }
+}
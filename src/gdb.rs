@@ -0,0 +1,808 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::cpu::csr;
+use crate::cpu::decoder::decode;
+use crate::cpu::defs::{Exception, Privilege, ABI, MNEMONIC};
+use crate::cpu::CPU;
+
+/// GDB's register numbers for RISC-V: x0-x31, then pc, the FPRs (absent
+/// here), 4096 CSRs and the virtual privilege register
+const PC: usize = 32;
+const CSR_FIRST: usize = 65;
+const PRIV: usize = CSR_FIRST + 4096;
+
+/// Largest packet accepted or sent, advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+/// Steps between checks for a Ctrl-C from the debugger while running
+const INTERRUPT_POLL: u64 = 1024;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+/// Why the runner stopped the guest during a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Halt {
+    /// The guest exited with this status
+    Exit(u8),
+    /// The guest raised an exception it has no handler for
+    Fault(Exception),
+}
+
+/// How a debugging session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ended {
+    /// The guest exited with this status while the debugger watched
+    Exited(u8),
+    /// The debugger killed the guest
+    Killed,
+    /// The debugger detached or went away; the guest should keep running
+    Detached,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Watch {
+    Write,
+    Read,
+    Access,
+}
+
+impl Watch {
+    /// Stop reason reported to GDB
+    fn reason(self) -> &'static str {
+        match self {
+            Watch::Write => "watch",
+            Watch::Read => "rwatch",
+            Watch::Access => "awatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    kind: Watch,
+    addr: u64,
+    len: u64,
+}
+
+/// Where a resumed guest stopped
+enum Stop {
+    Reply(String),
+    Exited(u8),
+    Detached,
+}
+
+/// GDB remote serial protocol stub controlling one hart
+///
+/// Bytes from the debugger arrive on `input`, typically fed by
+/// `spawn_reader` from a socket, and replies go to `output`. Breakpoints,
+/// hardware or software, are kept by the stub and checked against the pc
+/// before each instruction, so guest memory is never patched. Watchpoints
+/// are checked against the effective address of each load and store.
+/// `target.xml` describes the GPRs, the pc, every implemented CSR and the
+/// privilege mode.
+pub struct GdbStub {
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+    /// Bytes received while the guest ran, kept for the next packet
+    pending: VecDeque<u8>,
+    closed: bool,
+    ack: bool,
+    breakpoints: Vec<(u64, &'static str)>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+/// Read `reader` on a background thread, sending each byte to the returned
+/// channel until it ends
+pub fn spawn_reader<R: Read + Send + 'static>(reader: R) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in BufReader::new(reader).bytes() {
+            match byte {
+                Ok(byte) if sender.send(byte).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+    receiver
+}
+
+/// Signal GDB is told of for an unhandled exception
+fn signal(exception: &Exception) -> u8 {
+    match exception {
+        Exception::IllegalInstruction(_) => SIGILL,
+        Exception::InstructionAddressMisaligned(_) => SIGBUS,
+        Exception::Breakpoint(_) | Exception::EnvironmentCall => SIGTRAP,
+        _ => SIGSEGV,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// `addr,len` as sent by m, M, X and Z packets
+fn range(text: &str) -> Option<(u64, u64)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((number(addr)?, number(len)?))
+}
+
+/// Register value as little-endian hex, or None if it does not exist
+fn read_register(cpu: &CPU, regno: usize) -> Option<u64> {
+    match regno {
+        0..PC => Some(cpu.registers[regno]),
+        PC => Some(cpu.pc()),
+        CSR_FIRST..PRIV => cpu.csrs.read((regno - CSR_FIRST) as u32),
+        PRIV => Some(cpu.csrs.privilege() as u64),
+        _ => None,
+    }
+}
+
+/// Write a register, returning false if it does not exist or is read-only
+fn write_register(cpu: &mut CPU, regno: usize, value: u64) -> bool {
+    match regno {
+        // x0 stays hardwired to zero
+        0 => true,
+        1..PC => {
+            cpu.registers[regno] = value;
+            true
+        }
+        PC => {
+            cpu.set_pc(value);
+            true
+        }
+        CSR_FIRST..PRIV => {
+            let addr = (regno - CSR_FIRST) as u32;
+            let written = cpu.csrs.write(addr, value).is_some();
+            if addr == csr::SATP {
+                cpu.flush_tlb();
+            }
+            written
+        }
+        PRIV => match Privilege::from_u64(value) {
+            Some(privilege) => {
+                cpu.csrs.set_privilege(privilege);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Target description for `cpu`
+pub fn target_xml(cpu: &CPU) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>riscv:rv64</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for regno in 0..PC {
+        let name = format!("{:?}", ABI::from_u64(regno as u64).unwrap());
+        let kind = match regno {
+            1 => "code_ptr",
+            2..=4 => "data_ptr",
+            _ => "int",
+        };
+        let _ = writeln!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, regno
+        );
+    }
+    let _ = writeln!(
+        xml,
+        "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>",
+        PC
+    );
+    xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.riscv.csr\">\n");
+    for addr in 0..4096 {
+        if let (Some(_), Some(name)) = (cpu.csrs.read(addr), csr::name(addr)) {
+            let _ = writeln!(
+                xml,
+                "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>",
+                name,
+                CSR_FIRST + addr as usize
+            );
+        }
+    }
+    let _ = write!(
+        xml,
+        "</feature>\n\
+         <feature name=\"org.gnu.gdb.riscv.virtual\">\n\
+         <reg name=\"priv\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>\n\
+         </feature>\n\
+         </target>\n",
+        PRIV
+    );
+    xml
+}
+
+impl GdbStub {
+    pub fn new(input: Receiver<u8>, output: Box<dyn Write>) -> GdbStub {
+        GdbStub {
+            input,
+            output,
+            pending: VecDeque::new(),
+            closed: false,
+            ack: true,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Serve the debugger until it detaches or kills the guest, or the
+    /// guest exits
+    ///
+    /// The guest starts stopped. `step` executes one instruction the way
+    /// the runner does, returning how the guest stopped if it did; an
+    /// unhandled exception is reported to the debugger as a signal.
+    pub fn serve<F>(&mut self, cpu: &mut CPU, mut step: F) -> io::Result<Ended>
+    where
+        F: FnMut(&mut CPU) -> Option<Halt>,
+    {
+        while let Some(packet) = self.packet()? {
+            // bytes from the wire were widened to chars, so a packet that
+            // starts with one of 0x80 and up has no one-byte command
+            let (command, args) = match (packet.get(..1), packet.get(1..)) {
+                (Some(command), Some(args)) => (command, args),
+                _ => ("", packet.as_str()),
+            };
+            let reply = match (command, args) {
+                ("?", _) => format!("S{:02x}", SIGTRAP),
+                ("g", _) => (0..=PC)
+                    .map(|regno| hex(&read_register(cpu, regno).unwrap().to_le_bytes()))
+                    .collect(),
+                ("G", values) => match unhex(values) {
+                    Some(bytes) if bytes.len() == 8 * (PC + 1) => {
+                        for (regno, value) in bytes.chunks_exact(8).enumerate() {
+                            let value = u64::from_le_bytes(value.try_into().unwrap());
+                            write_register(cpu, regno, value);
+                        }
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                },
+                ("p", regno) => match number(regno).and_then(|n| read_register(cpu, n as usize)) {
+                    Some(value) => hex(&value.to_le_bytes()),
+                    None => String::from("E01"),
+                },
+                ("P", assignment) => {
+                    let written = assignment.split_once('=').and_then(|(regno, value)| {
+                        let bytes: [u8; 8] = unhex(value)?.try_into().ok()?;
+                        let value = u64::from_le_bytes(bytes);
+                        Some(write_register(cpu, number(regno)? as usize, value))
+                    });
+                    match written {
+                        Some(true) => String::from("OK"),
+                        _ => String::from("E01"),
+                    }
+                }
+                ("m", args) => match range(args) {
+                    Some((addr, len)) => {
+                        let mut buf = vec![0; (len as usize).min(PACKET_SIZE / 2)];
+                        match cpu.debug_read(addr, &mut buf) {
+                            Ok(()) => hex(&buf),
+                            Err(_) => String::from("E14"),
+                        }
+                    }
+                    None => String::from("E01"),
+                },
+                ("M", args) => {
+                    let data = args
+                        .split_once(':')
+                        .and_then(|(range, data)| Some((self::range(range)?, unhex(data)?)));
+                    match data {
+                        Some(((addr, len), data)) if data.len() as u64 == len => {
+                            match cpu.debug_write(addr, &data) {
+                                Ok(()) => String::from("OK"),
+                                Err(_) => String::from("E14"),
+                            }
+                        }
+                        _ => String::from("E01"),
+                    }
+                }
+                ("X", args) => {
+                    // binary data follows the colon; the packet was
+                    // unescaped when it was read
+                    let data = args.split_once(':').and_then(|(range, data)| {
+                        Some((
+                            self::range(range)?,
+                            data.chars().map(|c| c as u8).collect::<Vec<u8>>(),
+                        ))
+                    });
+                    match data {
+                        Some(((addr, len), data)) if data.len() as u64 == len => {
+                            match cpu.debug_write(addr, &data) {
+                                Ok(()) => String::from("OK"),
+                                Err(_) => String::from("E14"),
+                            }
+                        }
+                        _ => String::from("E01"),
+                    }
+                }
+                (command @ ("c" | "s"), addr) => {
+                    if let Some(addr) = number(addr) {
+                        cpu.set_pc(addr);
+                    }
+                    match self.resume(cpu, &mut step, command == "s") {
+                        Stop::Reply(reply) => reply,
+                        Stop::Exited(status) => {
+                            self.send(&format!("W{:02x}", status))?;
+                            return Ok(Ended::Exited(status));
+                        }
+                        Stop::Detached => return Ok(Ended::Detached),
+                    }
+                }
+                ("Z" | "z", args) => self.point(command == "Z", args),
+                ("D", _) => {
+                    self.send("OK")?;
+                    return Ok(Ended::Detached);
+                }
+                ("k", _) => return Ok(Ended::Killed),
+                ("H" | "T", _) => String::from("OK"),
+                _ => self.query(cpu, &packet),
+            };
+            self.send(&reply)?;
+            if packet == "vKill;1" {
+                return Ok(Ended::Killed);
+            }
+        }
+        Ok(Ended::Detached)
+    }
+
+    /// Answer the general query and `v` packets that GDB sends while
+    /// connecting; anything unknown gets the empty "unsupported" reply
+    fn query(&mut self, cpu: &CPU, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = range(args) else {
+                return String::from("E01");
+            };
+            let xml = target_xml(cpu);
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &xml[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                String::from("OK")
+            }
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "qSymbol::" | "vKill;1" => String::from("OK"),
+            _ => String::new(),
+        }
+    }
+
+    /// Insert (`Z`) or remove (`z`) a breakpoint or watchpoint
+    fn point(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (
+            fields.next(),
+            fields.next().and_then(number),
+            fields.next().and_then(number),
+        ) else {
+            return String::from("E01");
+        };
+        let watch = match kind {
+            "0" | "1" => {
+                let reason = if kind == "0" { "swbreak" } else { "hwbreak" };
+                self.breakpoints.retain(|&(a, _)| a != addr);
+                if insert {
+                    self.breakpoints.push((addr, reason));
+                }
+                return String::from("OK");
+            }
+            "2" => Watch::Write,
+            "3" => Watch::Read,
+            "4" => Watch::Access,
+            _ => return String::new(),
+        };
+        let point = Watchpoint {
+            kind: watch,
+            addr,
+            len,
+        };
+        self.watchpoints.retain(|&w| w != point);
+        if insert {
+            self.watchpoints.push(point);
+        }
+        String::from("OK")
+    }
+
+    /// Run until a breakpoint, watchpoint, exception or Ctrl-C stops the
+    /// guest, or for one instruction when `single`
+    fn resume<F>(&mut self, cpu: &mut CPU, step: &mut F, single: bool) -> Stop
+    where
+        F: FnMut(&mut CPU) -> Option<Halt>,
+    {
+        let mut steps: u64 = 0;
+        let stop = loop {
+            // the instruction at a breakpoint that stopped the guest runs
+            // when it resumes
+            if steps > 0 {
+                if let Some((_, reason)) = self.breakpoints.iter().find(|(a, _)| *a == cpu.pc()) {
+                    break format!("T{:02x}{}:;", SIGTRAP, reason);
+                }
+            }
+            if steps.is_multiple_of(INTERRUPT_POLL) && self.interrupted() {
+                break format!("S{:02x}", SIGINT);
+            }
+            let watched = self.watched(cpu);
+            let retired = cpu.csrs.read(csr::MINSTRET);
+            match step(cpu) {
+                Some(Halt::Exit(status)) => return Stop::Exited(status),
+                Some(Halt::Fault(exception)) => break format!("S{:02x}", signal(&exception)),
+                None => {}
+            }
+            steps += 1;
+            // an instruction that trapped or was preempted by an interrupt
+            // made no access
+            if let Some(point) = watched.filter(|_| cpu.csrs.read(csr::MINSTRET) != retired) {
+                break format!("T{:02x}{}:{:x};", SIGTRAP, point.kind.reason(), point.addr);
+            }
+            if single {
+                break format!("S{:02x}", SIGTRAP);
+            }
+        };
+        if self.closed && self.pending.is_empty() {
+            Stop::Detached
+        } else {
+            Stop::Reply(stop)
+        }
+    }
+
+    /// Watchpoint hit by the load or store at the pc, if any
+    fn watched(&self, cpu: &mut CPU) -> Option<Watchpoint> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        let mut word = [0; 4];
+        cpu.debug_read(cpu.pc(), &mut word).ok()?;
+        let instr = decode(u32::from_le_bytes(word)).ok()?;
+        let (store, size) = match instr.mnemonic {
+            MNEMONIC::LB | MNEMONIC::LBU => (false, 1),
+            MNEMONIC::LH | MNEMONIC::LHU => (false, 2),
+            MNEMONIC::LW | MNEMONIC::LWU => (false, 4),
+            MNEMONIC::LD => (false, 8),
+            MNEMONIC::SB => (true, 1),
+            MNEMONIC::SH => (true, 2),
+            MNEMONIC::SW => (true, 4),
+            MNEMONIC::SD => (true, 8),
+            _ => return None,
+        };
+        let base = cpu.registers[instr.rs1?.to_usize()];
+        let addr = base.wrapping_add(instr.imm? as u64);
+        self.watchpoints.iter().copied().find(|w| {
+            let kind = match w.kind {
+                Watch::Write => store,
+                Watch::Read => !store,
+                Watch::Access => true,
+            };
+            kind && addr < w.addr.wrapping_add(w.len) && w.addr < addr.wrapping_add(size)
+        })
+    }
+
+    /// Whether the debugger sent Ctrl-C, keeping anything else it sent
+    fn interrupted(&mut self) -> bool {
+        loop {
+            match self.input.try_recv() {
+                Ok(0x03) => return true,
+                Ok(byte) => self.pending.push_back(byte),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.pending.pop_front().or_else(|| self.input.recv().ok())
+    }
+
+    /// Next packet with its checksum verified and escapes removed, or None
+    /// once the debugger has gone
+    fn packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // acknowledgements and stray Ctrl-Cs come between packets
+            let mut byte = self.byte();
+            while byte.is_some_and(|byte| byte != b'$') {
+                byte = self.byte();
+            }
+            if byte.is_none() {
+                return Ok(None);
+            }
+            let mut data = Vec::new();
+            let mut sum: u8 = 0;
+            loop {
+                let Some(byte) = self.byte() else {
+                    return Ok(None);
+                };
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                data.push(byte);
+            }
+            let checksum = [self.byte(), self.byte()];
+            let valid = match checksum {
+                [Some(high), Some(low)] => {
+                    std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|text| u8::from_str_radix(text, 16).ok())
+                        == Some(sum)
+                }
+                _ => return Ok(None),
+            };
+            if self.ack {
+                self.output.write_all(if valid { b"+" } else { b"-" })?;
+                self.output.flush()?;
+            }
+            if !valid && self.ack {
+                continue;
+            }
+            let mut unescaped = String::with_capacity(data.len());
+            let mut bytes = data.into_iter();
+            while let Some(byte) = bytes.next() {
+                let byte = match byte {
+                    b'}' => bytes.next().unwrap_or(0) ^ 0x20,
+                    _ => byte,
+                };
+                unescaped.push(byte as char);
+            }
+            return Ok(Some(unescaped));
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let sum = reply.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.output, "${}#{:02x}", reply, sum)?;
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::defs::REG;
+    use crate::gdb::*;
+    use crate::program::Program;
+    use crate::testing::Capture;
+
+    fn frame(packet: &str) -> String {
+        let sum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        format!("${}#{:02x}", packet, sum)
+    }
+
+    /// Feed `packets` to a stub and return its replies, without the
+    /// acknowledgements, and how the session ended
+    fn session(cpu: &mut CPU, packets: &[&str]) -> (Vec<String>, Ended) {
+        let (sender, receiver) = mpsc::channel();
+        for packet in packets {
+            for byte in frame(packet).bytes() {
+                sender.send(byte).unwrap();
+            }
+        }
+        drop(sender);
+        let output = Capture::default();
+        let mut stub = GdbStub::new(receiver, Box::new(output.clone()));
+        let ended = stub
+            .serve(cpu, |cpu| match cpu.step() {
+                Err(Exception::EnvironmentCall) => Some(Halt::Exit(cpu.registers[10] as u8)),
+                Err(exception) => Some(Halt::Fault(exception)),
+                Ok(()) => None,
+            })
+            .unwrap();
+        let output = String::from_utf8(output.0.take()).unwrap();
+        let replies = output
+            .split('$')
+            .skip(1)
+            .map(|packet| {
+                let (data, checksum) = packet.rsplit_once('#').unwrap();
+                assert_eq!(
+                    checksum.trim_end_matches('+'),
+                    &frame(data)[data.len() + 2..]
+                );
+                String::from(data)
+            })
+            .collect();
+        (replies, ended)
+    }
+
+    /// a0 = 1, a1 = 2, store a1 to 0x100, load it back into a2, exit
+    fn program() -> CPU {
+        let mut cpu = Program::new()
+            .addi(REG::x10, REG::x0, 1)
+            .addi(REG::x11, REG::x0, 2)
+            .sd(REG::x11, 0x100, REG::x0)
+            .ld(REG::x12, 0x100, REG::x0)
+            .ecall()
+            .cpu()
+            .unwrap();
+        cpu.set_pc(0);
+        cpu
+    }
+
+    #[test]
+    fn test_framing_and_queries() {
+        let mut cpu = program();
+        let (replies, ended) = session(
+            &mut cpu,
+            &[
+                "qSupported:multiprocess+;swbreak+",
+                "?",
+                "qXfer:features:read:target.xml:0,15",
+                "qAttached",
+                "vMustReplyEmpty",
+                "D",
+            ],
+        );
+        assert_eq!(ended, Ended::Detached);
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], "m<?xml version=\"1.0\"?>");
+        assert_eq!(replies[3..], ["1", "", "OK"]);
+
+        let xml = target_xml(&cpu);
+        assert!(xml.contains("<reg name=\"sp\" bitsize=\"64\" type=\"data_ptr\" regnum=\"2\"/>"));
+        assert!(xml.contains("<reg name=\"mstatus\" bitsize=\"64\" type=\"int\" regnum=\"833\"/>"));
+        assert!(xml.contains("name=\"priv\""));
+
+        // a corrupt packet is refused and answered once resent
+        let (sender, receiver) = mpsc::channel();
+        for byte in "$?#00".bytes().chain(frame("?").bytes()) {
+            sender.send(byte).unwrap();
+        }
+        drop(sender);
+        let output = Capture::default();
+        let mut stub = GdbStub::new(receiver, Box::new(output.clone()));
+        stub.serve(&mut cpu, |_| None).unwrap();
+        assert_eq!(*output.0.borrow(), b"-+$S05#b8");
+    }
+
+    #[test]
+    fn test_non_ascii_packets() {
+        let mut cpu = program();
+        let (replies, ended) = session(&mut cpu, &["\u{e9}", "\u{e9}5", "m\u{e9},4", "p5"]);
+        assert_eq!(replies, ["", "", "E01", "0000000000000000"]);
+        assert_eq!(ended, Ended::Detached);
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = program();
+        cpu.registers[5] = 0x1122_3344_5566_7788;
+        let (replies, _) = session(
+            &mut cpu,
+            &[
+                "p5",
+                "P6=efbeadde00000000",
+                "p20",
+                "p1041",
+                "p1002",
+                "m0,4",
+                "M200,2:abcd",
+                "X202,2:}]\x01",
+                "m200,4",
+                "m40000000,4",
+                "g",
+            ],
+        );
+        assert_eq!(replies[0], "8877665544332211");
+        assert_eq!(replies[1], "OK");
+        assert_eq!(replies[2], "0000000000000000");
+        assert_eq!(cpu.registers[6], 0xdead_beef);
+        // priv reads as machine mode; register 0x1002 is CSR 0xfc1, which
+        // does not exist
+        assert_eq!(replies[3], "0300000000000000");
+        assert_eq!(replies[4], "E01");
+        assert_eq!(replies[5], "13051000");
+        assert_eq!(replies[6..8], ["OK", "OK"]);
+        assert_eq!(replies[8], "abcd7d01");
+        assert_eq!(replies[9], "E14");
+        assert_eq!(replies[10].len(), 33 * 16);
+        assert_eq!(&replies[10][5 * 16..6 * 16], "8877665544332211");
+    }
+
+    #[test]
+    fn test_csr_registers() {
+        let mut cpu = program();
+        let mtvec = CSR_FIRST + csr::MTVEC as usize;
+        let (replies, _) = session(
+            &mut cpu,
+            &[
+                &format!("P{:x}=0001000000000000", mtvec),
+                &format!("p{:x}", mtvec),
+            ],
+        );
+        assert_eq!(replies, ["OK", "0001000000000000"]);
+        assert_eq!(cpu.csrs.read(csr::MTVEC), Some(0x100));
+    }
+
+    #[test]
+    fn test_step_continue_and_breakpoints() {
+        let mut cpu = program();
+        let (replies, ended) = session(
+            &mut cpu,
+            &[
+                "s", "p20", "Z0,c,4", "c", "p20", "z0,c,4", "Z1,8,4", "c", "c",
+            ],
+        );
+        assert_eq!(replies[..2], ["S05", "0400000000000000"]);
+        assert_eq!(replies[2..5], ["OK", "T05swbreak:;", "0c00000000000000"]);
+        // the breakpoint at 0x8 is already behind the pc
+        assert_eq!(replies[5..8], ["OK", "OK", "W01"]);
+        assert_eq!(ended, Ended::Exited(1));
+        assert_eq!(cpu.registers[12], 2);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = program();
+        let (replies, _) = session(&mut cpu, &["Z2,104,4", "c", "Z3,100,8", "c", "p20", "?"]);
+        assert_eq!(replies[0], "OK");
+        // stops after the store, with the pc at the next instruction
+        assert_eq!(replies[1], "T05watch:104;");
+        assert_eq!(replies[2], "OK");
+        assert_eq!(replies[3], "T05rwatch:100;");
+        assert_eq!(replies[4], "1000000000000000");
+        assert_eq!(cpu.registers[12], 2);
+    }
+
+    #[test]
+    fn test_faults_and_interrupt() {
+        let mut cpu = Program::new().label("spin").j("spin").cpu().unwrap();
+        cpu.set_pc(0);
+
+        let (sender, receiver) = mpsc::channel();
+        for byte in frame("c").bytes().chain([0x03]).chain(frame("k").bytes()) {
+            sender.send(byte).unwrap();
+        }
+        let output = Capture::default();
+        let mut stub = GdbStub::new(receiver, Box::new(output.clone()));
+        let ended = stub
+            .serve(&mut cpu, |cpu| cpu.step().err().map(Halt::Fault))
+            .unwrap();
+        assert_eq!(ended, Ended::Killed);
+        assert_eq!(*output.0.borrow(), b"+$S02#b5+");
+        drop(sender);
+
+        cpu.set_pc(0x2);
+        let (replies, _) = session(&mut cpu, &["s", "?"]);
+        assert_eq!(replies[0], "S07");
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+
+use crate::bus::{Bus, BusFault};
+use crate::elf::Elf;
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const ENOSYS: i64 = 38;
+const EBADF: i64 = 9;
+
+/// Errors raised while servicing a `tohost` command
+#[derive(Debug, PartialEq, Eq)]
+pub enum HtifError {
+    /// The command named a device or command rast does not implement
+    /// (carries the `tohost` value)
+    Unsupported(u64),
+    /// A system call block or buffer could not be accessed
+    Bus(BusFault),
+}
+
+impl fmt::Display for HtifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HtifError::Unsupported(value) => {
+                write!(f, "unsupported HTIF command {:#018x}", value)
+            }
+            HtifError::Bus(BusFault::Unmapped(addr)) => {
+                write!(f, "HTIF access to unmapped address {:#x}", addr)
+            }
+            HtifError::Bus(BusFault::Denied(addr)) => {
+                write!(f, "HTIF access to {:#x} was denied", addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HtifError {}
+
+impl From<BusFault> for HtifError {
+    fn from(fault: BusFault) -> HtifError {
+        HtifError::Bus(fault)
+    }
+}
+
+/// Host side of the Host-Target Interface used by riscv-tests and Spike
+///
+/// The guest writes a command to the 64-bit `tohost` word and the host
+/// answers in `fromhost`. A command carries a device in bits 63:56, a
+/// command in bits 55:48 and a payload below. Device 0 command 0 is the
+/// system call proxy: a payload with bit 0 set exits with status
+/// `payload >> 1`, and any other payload points at eight words holding a
+/// system call number and its arguments, of which only `write` and `exit`
+/// are implemented. Device 1 is the console, with command 0 reading and
+/// command 1 writing a character.
+///
+/// `tohost` usually lies in guest RAM, so rather than being mapped on the
+/// bus the HTIF is polled by the runner between instructions.
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    output: Box<dyn Write>,
+    input: Option<Receiver<u8>>,
+    /// A console read waiting for input
+    reading: bool,
+}
+
+impl Htif {
+    /// HTIF with `tohost` (and `fromhost`, if the guest has one) at the given
+    /// physical addresses, writing console output to `output`
+    pub fn new(tohost: u64, fromhost: Option<u64>, output: Box<dyn Write>) -> Htif {
+        Htif {
+            tohost,
+            fromhost,
+            output,
+            input: None,
+            reading: false,
+        }
+    }
+
+    /// HTIF at the guest's `tohost` and `fromhost` symbols, or None if it
+    /// has no `tohost`
+    pub fn from_elf(elf: &Elf, output: Box<dyn Write>) -> Option<Htif> {
+        let tohost = elf.symbol("tohost")?.value;
+        let fromhost = elf.symbol("fromhost").map(|sym| sym.value);
+        Some(Htif::new(tohost, fromhost, output))
+    }
+
+    /// Answer console reads from `input`
+    pub fn set_input(&mut self, input: Receiver<u8>) {
+        self.input = Some(input);
+    }
+
+    /// Service a pending `tohost` command, returning the exit status once
+    /// the guest asks to exit
+    pub fn poll<B: Bus + ?Sized>(&mut self, bus: &mut B) -> Result<Option<u64>, HtifError> {
+        if self.reading {
+            if let Some(byte) = self.input.as_ref().and_then(|input| input.try_recv().ok()) {
+                self.reading = false;
+                self.respond(bus, DEVICE_CONSOLE << 56 | byte as u64)?;
+            }
+        }
+        let value = bus.read64(self.tohost)?;
+        if value == 0 {
+            return Ok(None);
+        }
+        // acknowledge the command so the guest may send the next one
+        bus.write64(self.tohost, 0)?;
+
+        let (device, command, payload) = (value >> 56, value >> 48 & 0xff, value << 16 >> 16);
+        match (device, command) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => Ok(Some(payload >> 1)),
+            (DEVICE_SYSCALL, 0) => self.syscall(bus, payload),
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                // putchar has no result, so a failed write is ignored
+                let _ = self
+                    .output
+                    .write_all(&[payload as u8])
+                    .and_then(|()| self.output.flush());
+                self.respond(bus, value & !0xff)?;
+                Ok(None)
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => {
+                self.reading = true;
+                Ok(None)
+            }
+            _ => Err(HtifError::Unsupported(value)),
+        }
+    }
+
+    /// Run the proxied system call described at `block`, storing its result
+    /// in the block's first word
+    fn syscall<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        block: u64,
+    ) -> Result<Option<u64>, HtifError> {
+        let mut args = [0; 8];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = bus.read64(block.wrapping_add(8 * i as u64))?;
+        }
+        let result = match args[0] {
+            SYS_EXIT => return Ok(Some(args[1])),
+            SYS_WRITE => {
+                let (fd, buf, len) = (args[1], args[2], args[3]);
+                let bytes = (0..len)
+                    .map(|i| bus.read8(buf.wrapping_add(i)))
+                    .collect::<Result<Vec<u8>, BusFault>>()?;
+                let written = match fd {
+                    1 => self
+                        .output
+                        .write_all(&bytes)
+                        .and_then(|()| self.output.flush()),
+                    2 => io::stderr().write_all(&bytes),
+                    _ => Err(io::ErrorKind::InvalidInput.into()),
+                };
+                match written {
+                    Ok(()) => len as i64,
+                    Err(_) => -EBADF,
+                }
+            }
+            _ => -ENOSYS,
+        };
+        bus.write64(block, result as u64)?;
+        self.respond(bus, 1)?;
+        Ok(None)
+    }
+
+    fn respond<B: Bus + ?Sized>(&mut self, bus: &mut B, value: u64) -> Result<(), HtifError> {
+        if let Some(fromhost) = self.fromhost {
+            bus.write64(fromhost, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crate::bus::AddressMap;
+    use crate::htif::*;
+    use crate::testing::Capture;
+
+    const TOHOST: u64 = 0x1000;
+    const FROMHOST: u64 = 0x1008;
+
+    fn setup() -> (Htif, AddressMap, Capture) {
+        let output = Capture::default();
+        let htif = Htif::new(TOHOST, Some(FROMHOST), Box::new(output.clone()));
+        let mut bus = AddressMap::new();
+        bus.map_ram(0, 0x2000);
+        (htif, bus, output)
+    }
+
+    #[test]
+    fn test_exit() {
+        let (mut htif, mut bus, _) = setup();
+        assert_eq!(htif.poll(&mut bus), Ok(None));
+        bus.write64(TOHOST, 1).unwrap();
+        assert_eq!(htif.poll(&mut bus), Ok(Some(0)));
+        assert_eq!(bus.read64(TOHOST), Ok(0));
+        // riscv-tests report a failing test number n as (n << 1) | 1
+        bus.write64(TOHOST, 7 << 1 | 1).unwrap();
+        assert_eq!(htif.poll(&mut bus), Ok(Some(7)));
+
+        bus.write64(TOHOST, 0x0200_0000_0000_0001).unwrap();
+        assert_eq!(
+            htif.poll(&mut bus),
+            Err(HtifError::Unsupported(0x0200_0000_0000_0001))
+        );
+    }
+
+    #[test]
+    fn test_console() {
+        let (mut htif, mut bus, output) = setup();
+        for byte in b"ok" {
+            bus.write64(TOHOST, 0x0101_0000_0000_0000 | *byte as u64)
+                .unwrap();
+            assert_eq!(htif.poll(&mut bus), Ok(None));
+            assert_eq!(bus.read64(FROMHOST), Ok(0x0101_0000_0000_0000));
+            bus.write64(FROMHOST, 0).unwrap();
+        }
+        assert_eq!(*output.0.borrow(), b"ok");
+
+        // a read is answered once input arrives
+        let (sender, receiver) = mpsc::channel();
+        htif.set_input(receiver);
+        bus.write64(TOHOST, 0x0100_0000_0000_0000).unwrap();
+        assert_eq!(htif.poll(&mut bus), Ok(None));
+        assert_eq!(bus.read64(FROMHOST), Ok(0));
+        sender.send(b'k').unwrap();
+        assert_eq!(htif.poll(&mut bus), Ok(None));
+        assert_eq!(
+            bus.read64(FROMHOST),
+            Ok(0x0100_0000_0000_0000 | b'k' as u64)
+        );
+    }
+
+    #[test]
+    fn test_syscall_proxy() {
+        let (mut htif, mut bus, output) = setup();
+        bus.load(0x200, b"hello\n").unwrap();
+        for (i, arg) in [SYS_WRITE, 1, 0x200, 6].iter().enumerate() {
+            bus.write64(0x100 + 8 * i as u64, *arg).unwrap();
+        }
+        bus.write64(TOHOST, 0x100).unwrap();
+        assert_eq!(htif.poll(&mut bus), Ok(None));
+        assert_eq!(*output.0.borrow(), b"hello\n");
+        assert_eq!(bus.read64(0x100), Ok(6));
+        assert_eq!(bus.read64(FROMHOST), Ok(1));
+
+        bus.write64(0x100, 1234).unwrap();
+        bus.write64(TOHOST, 0x100).unwrap();
+        assert_eq!(htif.poll(&mut bus), Ok(None));
+        assert_eq!(bus.read64(0x100), Ok(-ENOSYS as u64));
+
+        bus.write64(0x100, SYS_EXIT).unwrap();
+        bus.write64(0x108, 3).unwrap();
+        bus.write64(TOHOST, 0x100).unwrap();
+        assert_eq!(htif.poll(&mut bus), Ok(Some(3)));
+
+        bus.write64(TOHOST, 0x8000).unwrap();
+        assert_eq!(
+            htif.poll(&mut bus),
+            Err(HtifError::Bus(BusFault::Unmapped(0x8000)))
+        );
+    }
+}
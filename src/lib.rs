@@ -0,0 +1,13 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod asm;
+pub mod bus;
+pub mod conformance;
+pub mod cpu;
+pub mod elf;
+pub mod gdb;
+pub mod htif;
+pub mod program;
+
+#[cfg(test)]
+mod testing;
@@ -1,9 +1,767 @@
-mod cpu;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
 
-use crate::cpu::*;
-use crate::cpu::defs::*;
+use rast::asm::{assemble, Layout};
+use rast::bus::clint::{self, Clint, Timebase, CLINT_BASE, CLINT_SIZE};
+use rast::bus::plic::{self, Plic, PLIC_BASE, PLIC_SIZE};
+use rast::bus::uart::{Uart, UART_BASE, UART_IRQ, UART_SIZE};
+use rast::bus::{AddressMap, Bus, InterruptSource};
+use rast::conformance::{self, Harness};
+use rast::cpu::csr;
+use rast::cpu::decoder::decode;
+use rast::cpu::defs::*;
+use rast::cpu::disasm::{self, disassemble, Disassembly, RegNames, Syntax};
+use rast::cpu::CPU;
+use rast::elf::{Elf, PF_X};
+use rast::gdb::{self, Ended, GdbStub, Halt};
+use rast::htif::Htif;
 
-fn main() {
-    let cpu: CPU = CPU::new();
-    println!("CPU: {:#?}", cpu);
+const USAGE: &str = "\
+usage: rast <command> [options]
+
+commands:
+    run <elf|bin>       load and execute a guest program
+    disasm <elf|bin>    disassemble a guest program
+    decode <hex-word>   decode a single instruction word
+    asm <source>        assemble a guest program
+    test <dir|elf>      run riscv-tests or riscv-arch-test ELFs and report
+                        results per extension
+
+options:
+    --memory-base <addr>      base address of guest RAM
+                              (default: lowest ELF segment address, or 0)
+    --memory-size <bytes>     size of guest RAM, K/M/G suffixes allowed (default: 16M)
+    --load-address <addr>     where to place a raw binary (default: memory base)
+    --entry <addr>            initial pc (default: ELF entry, or load address)
+    --max-instructions <n>    stop after executing n instructions
+    --trace                   print every executed instruction to stderr
+    --realtime                drive mtime from the host clock instead of
+                              advancing it one tick per instruction
+    --timebase <hz>           mtime frequency; requires --realtime
+                              (default: 10M)
+    --serial-output <path>    write the guest's serial console to a file
+                              instead of stdout
+    --stdin                   feed stdin to the guest's serial console, in raw
+                              mode if it is a terminal; Ctrl-A x quits
+    --gdb <port|path>         wait for GDB on a local TCP port (or host:port),
+                              or on a Unix socket if given a path with a '/'
+    --signature-dir <dir>     write riscv-arch-test signatures under dir
+    --groups <names>          comma-separated groups to test, such as
+                              rv64ui,rv64um or I,M (default: all)
+    -M <options>              comma-separated disassembler options:
+                              numeric     print registers as x0-x31, not ABI names
+                              no-aliases  print base instructions, not pseudo-instructions
+    -o, --output <path>       assembler output file (default: a.out)
+    --binary                  assemble to a flat binary instead of an ELF executable
+    --text-address <addr>     address of the assembled .text section (default: 0)
+    --data-address <addr>     address of the assembled .data section
+                              (default: first page boundary after .text)
+    -h, --help                print this message
+
+The guest exits by executing `ecall` with a7 = 93 (exit) and the exit code in
+a0; rast then exits with that code. Guest faults exit with status 1, hitting
+the instruction limit with 124, and usage errors with 2. Once the guest sets
+mtvec to a non-zero handler address, ecalls and faults trap to that handler
+instead. Guests with a `tohost` symbol, such as riscv-tests, can also exit
+and print through the HTIF, as under Spike: writing (status << 1) | 1 to
+tohost exits with that status.
+
+A CLINT (machine timer and software interrupts) is mapped at 0x2000000, a
+PLIC (external interrupts) at 0xc000000 and an NS16550A UART (serial
+console, PLIC source 10) at 0x10000000, as on the QEMU virt machine, unless
+guest RAM covers those addresses.";
+
+/// Linux `exit` system call number, used by guests to report their exit code
+const SYS_EXIT: u64 = 93;
+
+const DEFAULT_MEMORY_SIZE: u64 = 16 << 20;
+
+enum Command {
+    Run(String),
+    Disasm(String),
+    Decode(String),
+    Asm(String),
+    Test(String),
+    Help,
+}
+
+struct Options {
+    memory_base: Option<u64>,
+    memory_size: u64,
+    load_address: Option<u64>,
+    entry: Option<u64>,
+    max_instructions: Option<u64>,
+    trace: bool,
+    realtime: bool,
+    timebase: Option<u64>,
+    serial_output: Option<String>,
+    stdin: bool,
+    gdb: Option<String>,
+    signature_dir: Option<String>,
+    groups: Vec<String>,
+    syntax: Syntax,
+    output: String,
+    binary: bool,
+    layout: Layout,
+}
+
+/// Parse a decimal or 0x-prefixed hexadecimal number with an optional
+/// K/M/G binary suffix
+fn parse_number(text: &str) -> Result<u64, String> {
+    let (digits, scale) = match text.chars().last() {
+        Some('k' | 'K') => (&text[..text.len() - 1], 1 << 10),
+        Some('m' | 'M') => (&text[..text.len() - 1], 1 << 20),
+        Some('g' | 'G') => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1),
+    };
+    let value = match digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => digits.replace('_', "").parse(),
+    };
+    value
+        .ok()
+        .and_then(|value| value.checked_mul(scale))
+        .ok_or_else(|| format!("invalid number '{}'", text))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<(Command, Options), String> {
+    let mut options = Options {
+        memory_base: None,
+        memory_size: DEFAULT_MEMORY_SIZE,
+        load_address: None,
+        entry: None,
+        max_instructions: None,
+        trace: false,
+        realtime: false,
+        timebase: None,
+        serial_output: None,
+        stdin: false,
+        gdb: None,
+        signature_dir: None,
+        groups: Vec::new(),
+        syntax: Syntax::default(),
+        output: String::from("a.out"),
+        binary: false,
+        layout: Layout::default(),
+    };
+    let mut positional: Vec<String> = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", name))
+                .and_then(|v| parse_number(&v))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok((Command::Help, options)),
+            "--memory-base" => options.memory_base = Some(value(&arg)?),
+            "--memory-size" => options.memory_size = value(&arg)?,
+            "--load-address" => options.load_address = Some(value(&arg)?),
+            "--entry" => options.entry = Some(value(&arg)?),
+            "--max-instructions" => options.max_instructions = Some(value(&arg)?),
+            "--trace" => options.trace = true,
+            "--realtime" => options.realtime = true,
+            "--timebase" => options.timebase = Some(value(&arg)?),
+            "--serial-output" => {
+                options.serial_output = Some(
+                    args.next()
+                        .ok_or_else(|| format!("missing value for {}", arg))?,
+                )
+            }
+            "--stdin" => options.stdin = true,
+            "--gdb" => {
+                options.gdb = Some(
+                    args.next()
+                        .ok_or_else(|| format!("missing value for {}", arg))?,
+                )
+            }
+            "--signature-dir" => {
+                options.signature_dir = Some(
+                    args.next()
+                        .ok_or_else(|| format!("missing value for {}", arg))?,
+                )
+            }
+            "--groups" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", arg))?;
+                options.groups = value.split(',').map(String::from).collect();
+            }
+            "-o" | "--output" => {
+                options.output = args
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", arg))?
+            }
+            "--binary" => options.binary = true,
+            "--text-address" => options.layout.text = value(&arg)?,
+            "--data-address" => options.layout.data = Some(value(&arg)?),
+            "-M" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", arg))?;
+                for option in value.split(',') {
+                    match option {
+                        "numeric" => options.syntax.names = RegNames::Numeric,
+                        "no-aliases" => options.syntax.aliases = false,
+                        _ => return Err(format!("unknown disassembler option '{}'", option)),
+                    }
+                }
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("unknown option '{}'", arg))
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match (positional.next().as_deref(), positional.next()) {
+        (Some("run"), Some(path)) => Command::Run(path),
+        (Some("disasm"), Some(path)) => Command::Disasm(path),
+        (Some("decode"), Some(word)) => Command::Decode(word),
+        (Some("asm"), Some(path)) => Command::Asm(path),
+        (Some("test"), Some(path)) => Command::Test(path),
+        (None, _) => Command::Help,
+        (Some(command @ ("run" | "disasm" | "decode" | "asm" | "test")), None) => {
+            return Err(format!("missing argument for '{}'", command))
+        }
+        (Some(command), _) => return Err(format!("unknown command '{}'", command)),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument '{}'", extra));
+    }
+    if options.timebase.is_some() && !options.realtime {
+        return Err(String::from("--timebase requires --realtime"));
+    }
+    if options.memory_size == 0 {
+        return Err(String::from("memory size must not be zero"));
+    }
+    Ok((command, options))
+}
+
+/// Disassemble the word at `pc`, or None if it does not decode
+fn disassemble_word(word: u32, pc: u64, syntax: &Syntax) -> Option<Disassembly> {
+    decode(word)
+        .ok()
+        .map(|instr| disassemble(&instr, Some(pc), syntax))
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// Whether `[base, base + size)` is clear of every mapping on `bus`
+fn is_free(bus: &AddressMap, base: u64, size: u64) -> bool {
+    bus.ranges()
+        .all(|(start, len)| base + size <= start || start + len <= base)
+}
+
+/// Map an interrupting device at `base` and connect it to the hart, unless
+/// guest RAM already covers the range
+fn attach<D>(cpu: &mut CPU, base: u64, size: u64, device: D)
+where
+    D: Bus + InterruptSource + Clone + 'static,
+{
+    if is_free(&cpu.bus, base, size) {
+        cpu.bus.map(base, size, Box::new(device.clone()));
+        cpu.connect(Box::new(device));
+    }
+}
+
+/// Host terminal switched to raw mode, restored on drop
+#[cfg(unix)]
+struct RawTerminal(libc::termios);
+
+#[cfg(unix)]
+impl RawTerminal {
+    /// Switch stdin to raw mode, or None if it is not a terminal
+    fn enter() -> Option<RawTerminal> {
+        // SAFETY: termios is plain data, and tcgetattr fills it in before
+        // it is used
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::isatty(0) == 0 || libc::tcgetattr(0, &mut original) != 0 {
+                return None;
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            // keep output processing so rast's own messages still start on a
+            // fresh line
+            raw.c_oflag |= libc::OPOST;
+            libc::tcsetattr(0, libc::TCSANOW, &raw);
+            Some(RawTerminal(original))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read by tcgetattr
+        unsafe {
+            libc::tcsetattr(0, libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct RawTerminal;
+
+#[cfg(not(unix))]
+impl RawTerminal {
+    fn enter() -> Option<RawTerminal> {
+        None
+    }
+}
+
+/// Host stdin feeding the serial console
+///
+/// A thread reads stdin and sends each byte to the UART. In raw mode the
+/// terminal no longer turns Ctrl-C into a signal, so Ctrl-A x asks the run
+/// loop to quit instead and Ctrl-A Ctrl-A sends a literal Ctrl-A, as in QEMU.
+struct Console {
+    quit: Arc<AtomicBool>,
+    _raw: Option<RawTerminal>,
+}
+
+impl Console {
+    const ESCAPE: u8 = 0x01;
+
+    fn start() -> (Console, Receiver<u8>) {
+        let raw = RawTerminal::enter();
+        let escapes = raw.is_some();
+        let quit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+        let flag = quit.clone();
+        thread::spawn(move || {
+            let mut escaped = false;
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else { break };
+                if escapes {
+                    match (escaped, byte) {
+                        (false, Console::ESCAPE) => {
+                            escaped = true;
+                            continue;
+                        }
+                        (true, b'x' | b'X') => {
+                            flag.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                        _ => escaped = false,
+                    }
+                }
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        (Console { quit, _raw: raw }, receiver)
+    }
+
+    fn quit(&self) -> bool {
+        self.quit.load(Ordering::Relaxed)
+    }
+}
+
+/// Build a CPU with RAM as configured and the program at `path` loaded, and
+/// the HTIF if the program has a `tohost` symbol. Console input from `input`
+/// goes to the HTIF if there is one, or else to the UART.
+fn load(
+    path: &str,
+    options: &Options,
+    mut input: Option<Receiver<u8>>,
+) -> Result<(CPU, Option<Htif>), String> {
+    let image = read_file(path)?;
+    let elf = match Elf::parse(&image) {
+        Ok(elf) => Some(elf),
+        Err(rast::elf::ElfError::NotElf) => None,
+        Err(err) => return Err(format!("{}: {}", path, err)),
+    };
+
+    let memory_base = options.memory_base.unwrap_or_else(|| {
+        elf.as_ref()
+            .and_then(|elf| elf.segments.iter().map(|s| s.paddr).min())
+            .map_or(0, |lowest| lowest & !0xfff)
+    });
+    if memory_base.checked_add(options.memory_size).is_none() {
+        return Err(format!(
+            "memory of size {:#x} at {:#x} runs past the end of the address space",
+            options.memory_size, memory_base
+        ));
+    }
+    let mut bus = AddressMap::new();
+    bus.map_ram(memory_base, options.memory_size);
+    let mut cpu = CPU::with_bus(bus);
+    let timebase = if options.realtime {
+        Timebase::HostClock(options.timebase.unwrap_or(clint::DEFAULT_FREQUENCY))
+    } else {
+        Timebase::Instructions(1)
+    };
+    attach(&mut cpu, CLINT_BASE, CLINT_SIZE, Clint::new(1, timebase));
+    let plic = Plic::new(1, plic::DEFAULT_SOURCES);
+    attach(&mut cpu, PLIC_BASE, PLIC_SIZE, plic.clone());
+    let serial = match &options.serial_output {
+        Some(path) => Some(File::create(path).map_err(|err| format!("{}: {}", path, err))?),
+        None => None,
+    };
+    // the UART and the HTIF share the console
+    let output = || -> Result<Box<dyn Write>, String> {
+        match &serial {
+            Some(file) => Ok(Box::new(file.try_clone().map_err(|err| err.to_string())?)),
+            None => Ok(Box::new(io::stdout())),
+        }
+    };
+    let mut htif = match &elf {
+        Some(elf) => Htif::from_elf(elf, output()?),
+        None => None,
+    };
+    if let Some(htif) = &mut htif {
+        if let Some(input) = input.take() {
+            htif.set_input(input);
+        }
+    }
+    let uart = Uart::new(output()?);
+    uart.set_interrupt(plic.line(UART_IRQ));
+    if let Some(input) = input {
+        uart.set_input(input);
+    }
+    attach(&mut cpu, UART_BASE, UART_SIZE, uart);
+
+    match &elf {
+        Some(elf) => elf
+            .load(&mut cpu)
+            .map_err(|err| format!("{}: {}", path, err))?,
+        None => {
+            let load_address = options.load_address.unwrap_or(memory_base);
+            cpu.bus
+                .load(load_address, &image)
+                .map_err(|_| format!("{}: does not fit in memory at {:#x}", path, load_address))?;
+            cpu.set_pc(load_address);
+        }
+    }
+    if let Some(entry) = options.entry {
+        cpu.set_pc(entry);
+    }
+    // start with the stack pointer at the top of RAM
+    cpu.registers[REG::x2.to_usize()] = memory_base.wrapping_add(options.memory_size) & !0xf;
+    Ok((cpu, htif))
+}
+
+fn run(path: &str, options: &Options) -> Result<ExitCode, String> {
+    let (console, input) = match options.stdin {
+        true => {
+            let (console, input) = Console::start();
+            (Some(console), Some(input))
+        }
+        false => (None, None),
+    };
+    let (mut cpu, mut htif) = load(path, options, input)?;
+    if let Some(address) = &options.gdb {
+        match debug(address, &mut cpu, &mut htif)? {
+            Ended::Exited(status) => return Ok(ExitCode::from(status)),
+            Ended::Killed => return Ok(ExitCode::SUCCESS),
+            Ended::Detached => {}
+        }
+    }
+    let mut executed: u64 = 0;
+    loop {
+        if console.as_ref().is_some_and(Console::quit) {
+            return Ok(ExitCode::SUCCESS);
+        }
+        if options.max_instructions.is_some_and(|max| executed >= max) {
+            eprintln!(
+                "rast: instruction limit of {} reached at pc {:#x}",
+                executed,
+                cpu.pc()
+            );
+            return Ok(ExitCode::from(124));
+        }
+        if options.trace {
+            let pc = cpu.pc();
+            let mut word = [0; 4];
+            if cpu.debug_read(pc, &mut word).is_ok() {
+                let word = u32::from_le_bytes(word);
+                let text = disassemble_word(word, pc, &options.syntax)
+                    .map_or(String::from("<illegal>"), |text| text.to_string());
+                eprintln!("{:#018x}: {:08x}  {}", pc, word, text);
+            }
+        }
+        match step(&mut cpu, &mut htif) {
+            None => executed += 1,
+            Some(Halt::Exit(status)) => return Ok(ExitCode::from(status)),
+            Some(Halt::Fault(exception)) => {
+                eprintln!(
+                    "rast: unhandled {:?} at pc {:#x} after {} instructions",
+                    exception,
+                    cpu.csrs.read(csr::MEPC).unwrap_or(0),
+                    executed
+                );
+                return Ok(ExitCode::from(1));
+            }
+        }
+    }
+}
+
+/// Execute one instruction and service the HTIF, returning how the guest
+/// stopped if it did
+fn step(cpu: &mut CPU, htif: &mut Option<Htif>) -> Option<Halt> {
+    // a guest that has installed a trap handler handles its own traps
+    let handled = cpu.csrs.read(csr::MTVEC) != Some(0);
+    match cpu.step() {
+        Ok(()) => {}
+        // the trap has been taken; the step still counts towards the limit
+        // so that a handler that keeps faulting still stops
+        Err(_) if handled => {}
+        Err(Exception::EnvironmentCall) if cpu.registers[REG::x17.to_usize()] == SYS_EXIT => {
+            return Some(Halt::Exit(cpu.registers[REG::x10.to_usize()] as u8));
+        }
+        Err(exception) => return Some(Halt::Fault(exception)),
+    }
+    match htif.as_mut().map(|htif| htif.poll(&mut cpu.bus)) {
+        // riscv-tests report the number of the failing test, which may not
+        // fit an exit status
+        Some(Ok(Some(status))) => Some(Halt::Exit(u8::try_from(status).unwrap_or(u8::MAX))),
+        Some(Err(err)) => {
+            eprintln!("rast: {} at pc {:#x}", err, cpu.pc());
+            Some(Halt::Exit(1))
+        }
+        _ => None,
+    }
+}
+
+/// Accept one connection on `address`: a TCP port, a host:port pair, or a
+/// Unix socket path if it contains a '/'
+fn connect(address: &str) -> Result<(Receiver<u8>, Box<dyn Write>), String> {
+    let error = |err: io::Error| format!("{}: {}", address, err);
+    if address.contains('/') {
+        #[cfg(unix)]
+        {
+            let listener = UnixListener::bind(address).map_err(error)?;
+            eprintln!("rast: waiting for gdb on {}", address);
+            let accepted = listener.accept();
+            // the socket was only needed to accept the debugger
+            let _ = fs::remove_file(address);
+            let (stream, _) = accepted.map_err(error)?;
+            let reader = stream.try_clone().map_err(error)?;
+            return Ok((gdb::spawn_reader(reader), Box::new(stream)));
+        }
+        #[cfg(not(unix))]
+        return Err(format!("{}: Unix sockets are not supported", address));
+    }
+    let address = match address.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => String::from(address),
+    };
+    let error = |err: io::Error| format!("{}: {}", address, err);
+    let listener = TcpListener::bind(&address).map_err(error)?;
+    eprintln!(
+        "rast: waiting for gdb on {}",
+        listener.local_addr().map_err(error)?
+    );
+    let (stream, _) = listener.accept().map_err(error)?;
+    // packets are small and latency-bound
+    let _ = stream.set_nodelay(true);
+    let reader = stream.try_clone().map_err(error)?;
+    Ok((gdb::spawn_reader(reader), Box::new(stream)))
+}
+
+/// Wait for GDB on `address` and let it control the guest
+fn debug(address: &str, cpu: &mut CPU, htif: &mut Option<Htif>) -> Result<Ended, String> {
+    let (input, output) = connect(address)?;
+    let mut stub = GdbStub::new(input, output);
+    stub.serve(cpu, |cpu| step(cpu, htif))
+        .map_err(|err| format!("gdb: {}", err))
+}
+
+/// `<symbol>` or `<symbol+0xoffset>` for the nearest symbol at or below
+/// `address`, as objdump annotates jump targets
+fn symbolize(address: u64, elf: &Elf) -> Option<String> {
+    let symbol = elf
+        .symbols
+        .iter()
+        .filter(|s| s.value <= address && !s.name.is_empty())
+        .max_by_key(|s| s.value)?;
+    match address - symbol.value {
+        0 => Some(format!("<{}>", symbol.name)),
+        offset => Some(format!("<{}+{:#x}>", symbol.name, offset)),
+    }
+}
+
+fn disasm_bytes(address: u64, bytes: &[u8], elf: Option<&Elf>, syntax: &Syntax) {
+    let labelled = |pc: u64| {
+        elf.into_iter()
+            .flat_map(|elf| elf.symbols.iter())
+            .filter(move |s| s.value == pc && !s.name.is_empty())
+    };
+    let words: Vec<u32> = bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    let instrs: Vec<_> = words.iter().map(|&word| decode(word).ok()).collect();
+
+    let mut i = 0;
+    while i < words.len() {
+        let pc = address.wrapping_add(4 * i as u64);
+        for symbol in labelled(pc) {
+            println!("\n{:016x} <{}>:", pc, symbol.name);
+        }
+        let word = words[i];
+        let Some(instr) = &instrs[i] else {
+            println!("{:8x}:\t{:08x}          \t.4byte\t{:#x}", pc, word, word);
+            i += 1;
+            continue;
+        };
+
+        // an AUIPC+JALR call/tail pair prints as one pseudo-instruction,
+        // unless a label splits it
+        let pair = instrs
+            .get(i + 1)
+            .and_then(|next| next.as_ref())
+            .filter(|_| labelled(pc.wrapping_add(4)).next().is_none())
+            .and_then(|next| {
+                let text = disasm::disassemble_pair(instr, next, Some(pc), syntax)?;
+                Some((text, disasm::pair_target(instr, next, pc)))
+            });
+        let (text, target, len) = match pair {
+            Some((text, target)) => (text, target, 2),
+            None => (
+                disassemble(instr, Some(pc), syntax),
+                disasm::target(instr, pc),
+                1,
+            ),
+        };
+        let annotation = target
+            .zip(elf)
+            .and_then(|(target, elf)| symbolize(target, elf))
+            .map_or(String::new(), |symbol| format!(" {}", symbol));
+        // objdump separates the operands with a tab, and omits it when
+        // there are none
+        let text = match text.operands.as_str() {
+            "" => text.mnemonic,
+            operands => format!("{}\t{}", text.mnemonic, operands),
+        };
+        println!("{:8x}:\t{:08x}          \t{}{}", pc, word, text, annotation);
+        if len == 2 {
+            println!("{:8x}:\t{:08x}", pc.wrapping_add(4), words[i + 1]);
+        }
+        i += len;
+    }
+}
+
+fn disasm(path: &str, options: &Options) -> Result<ExitCode, String> {
+    let image = read_file(path)?;
+    match Elf::parse(&image) {
+        Ok(elf) => {
+            for segment in elf.segments.iter().filter(|s| s.flags & PF_X != 0) {
+                disasm_bytes(
+                    segment.vaddr,
+                    elf.contents(segment),
+                    Some(&elf),
+                    &options.syntax,
+                );
+            }
+        }
+        Err(rast::elf::ElfError::NotElf) => {
+            let address = options.load_address.or(options.memory_base).unwrap_or(0);
+            disasm_bytes(address, &image, None, &options.syntax);
+        }
+        Err(err) => return Err(format!("{}: {}", path, err)),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn decode_word(word: &str, options: &Options) -> Result<ExitCode, String> {
+    let hex = word
+        .strip_prefix("0x")
+        .or(word.strip_prefix("0X"))
+        .unwrap_or(word);
+    let word = u32::from_str_radix(&hex.replace('_', ""), 16)
+        .map_err(|_| format!("invalid instruction word '{}'", word))?;
+    let instr = decode(word).map_err(|err| err.to_string())?;
+    println!("{}", disassemble(&instr, None, &options.syntax));
+    println!("{:#?}", instr);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn asm(path: &str, options: &Options) -> Result<ExitCode, String> {
+    let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let image = assemble(&source, &options.layout).map_err(|err| format!("{}: {}", path, err))?;
+    let bytes = if options.binary {
+        image.binary()
+    } else {
+        image.elf()
+    };
+    fs::write(&options.output, bytes).map_err(|err| format!("{}: {}", options.output, err))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn test(path: &str, options: &Options) -> Result<ExitCode, String> {
+    let harness = Harness {
+        max_instructions: options
+            .max_instructions
+            .unwrap_or(conformance::DEFAULT_MAX_INSTRUCTIONS),
+        signature_dir: options.signature_dir.as_ref().map(PathBuf::from),
+        groups: options.groups.clone(),
+    };
+    let results = harness
+        .run_suite(Path::new(path))
+        .map_err(|err| format!("{}: {}", path, err))?;
+    if results.is_empty() {
+        return Err(format!("{}: no tests found", path));
+    }
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    for result in &results {
+        println!("{:width$}  {}", result.name, result.outcome);
+    }
+    println!();
+    let summary = conformance::summarize(&results);
+    let width = summary
+        .keys()
+        .map(|group| group.len())
+        .fold("total".len(), usize::max);
+    for (group, (passed, total)) in &summary {
+        println!("{:width$}  {}/{} passed", group, passed, total);
+    }
+    let passed = summary.values().map(|(passed, _)| passed).sum::<usize>();
+    println!("{:width$}  {}/{} passed", "total", passed, results.len());
+    Ok(if passed == results.len() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    })
+}
+
+fn main() -> ExitCode {
+    let (command, options) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            eprintln!("rast: {}\n\n{}", msg, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let result = match command {
+        Command::Run(path) => run(&path, &options),
+        Command::Disasm(path) => disasm(&path, &options),
+        Command::Decode(word) => decode_word(&word, &options),
+        Command::Asm(path) => asm(&path, &options),
+        Command::Test(path) => test(&path, &options),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(ExitCode::SUCCESS)
+        }
+    };
+    result.unwrap_or_else(|msg| {
+        eprintln!("rast: {}", msg);
+        ExitCode::from(1)
+    })
 }
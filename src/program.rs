@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::asm;
+use crate::bus::{Bus, BusFault};
+use crate::cpu::defs::*;
+use crate::cpu::encoder::encode;
+use crate::cpu::CPU;
+
+/// Why a `Program` does not build
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProgramError {
+    /// A branch or jump refers to a label that is never placed (name)
+    UndefinedLabel(String),
+    /// A label is placed twice (name)
+    DuplicateLabel(String),
+    /// The instruction at `index` does not encode
+    Encode { index: usize, error: EncodeError },
+    /// The program does not fit the CPU's memory
+    Load(BusFault),
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::UndefinedLabel(name) => write!(f, "undefined label '{}'", name),
+            ProgramError::DuplicateLabel(name) => write!(f, "label '{}' placed twice", name),
+            ProgramError::Encode { index, error } => write!(f, "instruction {}: {}", index, error),
+            ProgramError::Load(fault) => write!(f, "load failed: {:?}", fault),
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+/// Destination of a branch or jump: a label, or a byte offset from the
+/// instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Label(String),
+    Offset(i64),
+}
+
+impl From<&str> for Target {
+    fn from(label: &str) -> Self {
+        Target::Label(String::from(label))
+    }
+}
+
+impl From<String> for Target {
+    fn from(label: String) -> Self {
+        Target::Label(label)
+    }
+}
+
+impl From<i64> for Target {
+    fn from(offset: i64) -> Self {
+        Target::Offset(offset)
+    }
+}
+
+/// Guest code built instruction by instruction, for tests
+///
+/// Each method appends one instruction (or a pseudo-instruction's sequence),
+/// with operands in assembly order: `lw(rd, offset, rs1)` is
+/// `lw rd, offset(rs1)`. Branches and jumps take a `Target`, so labels may be
+/// placed before or after their uses; they are resolved when the program is
+/// encoded.
+///
+/// ```
+/// use rast::cpu::defs::REG;
+/// use rast::program::Program;
+///
+/// let mut cpu = Program::new()
+///     .addi(REG::x1, REG::x0, 5)
+///     .label("loop")
+///     .addi(REG::x1, REG::x1, -1)
+///     .bne(REG::x1, REG::x0, "loop")
+///     .ecall()
+///     .cpu()
+///     .unwrap();
+/// cpu.run(Some(100)).unwrap_err();
+/// assert_eq!(cpu.registers[1], 0);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    base: u64,
+    instrs: Vec<(DecodedInstr, Option<String>)>,
+    labels: HashMap<String, usize>,
+    /// First label placed twice, reported when the program is encoded
+    duplicate: Option<String>,
+}
+
+/// Methods taking three registers
+macro_rules! r_type {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rd: REG, rs1: REG, rs2: REG) -> Self {
+                let mut instr = DecodedInstr::new(MNEMONIC::$mnemonic);
+                instr.rd = Some(rd);
+                instr.rs1 = Some(rs1);
+                instr.rs2 = Some(rs2);
+                self.push(instr)
+            }
+        )*
+    };
+}
+
+/// Methods taking a destination, a source and a 12-bit immediate
+macro_rules! i_type {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rd: REG, rs1: REG, imm: i64) -> Self {
+                self.i(MNEMONIC::$mnemonic, rd, rs1, imm)
+            }
+        )*
+    };
+}
+
+/// Loads, taking the offset before the base register as in
+/// `lw rd, offset(rs1)`
+macro_rules! load {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rd: REG, offset: i64, rs1: REG) -> Self {
+                self.i(MNEMONIC::$mnemonic, rd, rs1, offset)
+            }
+        )*
+    };
+}
+
+/// Stores, taking the source, then the offset and base register
+macro_rules! store {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rs2: REG, offset: i64, rs1: REG) -> Self {
+                let mut instr = DecodedInstr::new(MNEMONIC::$mnemonic);
+                instr.rs1 = Some(rs1);
+                instr.rs2 = Some(rs2);
+                instr.imm = Some(offset);
+                self.push(instr)
+            }
+        )*
+    };
+}
+
+/// Shifts by an immediate amount
+macro_rules! shift {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rd: REG, rs1: REG, shamt: u32) -> Self {
+                let mut instr = DecodedInstr::new(MNEMONIC::$mnemonic);
+                instr.rd = Some(rd);
+                instr.rs1 = Some(rs1);
+                instr.shamt = Some(shamt);
+                self.push(instr)
+            }
+        )*
+    };
+}
+
+/// Branches to a label or a byte offset
+macro_rules! branch {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rs1: REG, rs2: REG, target: impl Into<Target>) -> Self {
+                let mut instr = DecodedInstr::new(MNEMONIC::$mnemonic);
+                instr.rs1 = Some(rs1);
+                instr.rs2 = Some(rs2);
+                self.push_target(instr, target.into())
+            }
+        )*
+    };
+}
+
+/// Methods taking a 20-bit upper immediate, as written in assembly
+macro_rules! u_type {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rd: REG, imm: u32) -> Self {
+                let mut instr = DecodedInstr::new(MNEMONIC::$mnemonic);
+                instr.rd = Some(rd);
+                instr.imm = Some((imm << 12) as i32 as i64);
+                self.push(instr)
+            }
+        )*
+    };
+}
+
+/// CSR accesses with a register source
+macro_rules! csr {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rd: REG, csr: u32, rs1: REG) -> Self {
+                let mut instr = DecodedInstr::new(MNEMONIC::$mnemonic);
+                instr.rd = Some(rd);
+                instr.csr = Some(csr);
+                instr.rs1 = Some(rs1);
+                self.push(instr)
+            }
+        )*
+    };
+}
+
+/// CSR accesses with a 5-bit immediate source
+macro_rules! csr_imm {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(self, rd: REG, csr: u32, uimm: u32) -> Self {
+                let mut instr = DecodedInstr::new(MNEMONIC::$mnemonic);
+                instr.rd = Some(rd);
+                instr.csr = Some(csr);
+                instr.imm = Some(uimm as i64);
+                self.push(instr)
+            }
+        )*
+    };
+}
+
+impl Program {
+    /// An empty program placed at address 0
+    pub fn new() -> Program {
+        Program::default()
+    }
+
+    /// Place the program at `base` instead of address 0
+    pub fn at(mut self, base: u64) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Label the next instruction
+    pub fn label(mut self, name: &str) -> Self {
+        if self.labels.contains_key(name) {
+            self.duplicate.get_or_insert_with(|| String::from(name));
+        } else {
+            self.labels.insert(String::from(name), self.instrs.len());
+        }
+        self
+    }
+
+    /// Address of a placed label
+    pub fn address(&self, label: &str) -> Option<u64> {
+        self.labels
+            .get(label)
+            .map(|&index| self.base + 4 * index as u64)
+    }
+
+    /// Append an instruction built by hand
+    pub fn push(mut self, instr: DecodedInstr) -> Self {
+        self.instrs.push((instr, None));
+        self
+    }
+
+    fn push_target(mut self, mut instr: DecodedInstr, target: Target) -> Self {
+        match target {
+            Target::Offset(offset) => {
+                instr.imm = Some(offset);
+                self.instrs.push((instr, None));
+            }
+            Target::Label(label) => self.instrs.push((instr, Some(label))),
+        }
+        self
+    }
+
+    fn i(self, mnemonic: MNEMONIC, rd: REG, rs1: REG, imm: i64) -> Self {
+        let mut instr = DecodedInstr::new(mnemonic);
+        instr.rd = Some(rd);
+        instr.rs1 = Some(rs1);
+        instr.imm = Some(imm);
+        self.push(instr)
+    }
+
+    r_type! {
+        add => ADD, sub => SUB, sll => SLL, slt => SLT, sltu => SLTU,
+        xor => XOR, srl => SRL, sra => SRA, or => OR, and => AND,
+        addw => ADDW, subw => SUBW, sllw => SLLW, srlw => SRLW, sraw => SRAW,
+        mul => MUL, mulh => MULH, mulhsu => MULHSU, mulhu => MULHU,
+        div => DIV, divu => DIVU, rem => REM, remu => REMU,
+        mulw => MULW, divw => DIVW, divuw => DIVUW, remw => REMW, remuw => REMUW,
+    }
+
+    i_type! {
+        addi => ADDI, slti => SLTI, sltiu => SLTIU, xori => XORI, ori => ORI,
+        andi => ANDI, addiw => ADDIW,
+    }
+
+    load! {
+        lb => LB, lh => LH, lw => LW, ld => LD, lbu => LBU, lhu => LHU,
+        lwu => LWU, jalr => JALR,
+    }
+
+    store! { sb => SB, sh => SH, sw => SW, sd => SD }
+
+    shift! {
+        slli => SLLI, srli => SRLI, srai => SRAI,
+        slliw => SLLIW, srliw => SRLIW, sraiw => SRAIW,
+    }
+
+    branch! {
+        beq => BEQ, bne => BNE, blt => BLT, bge => BGE, bltu => BLTU, bgeu => BGEU,
+    }
+
+    u_type! { lui => LUI, auipc => AUIPC }
+
+    csr! { csrrw => CSRRW, csrrs => CSRRS, csrrc => CSRRC }
+
+    csr_imm! { csrrwi => CSRRWI, csrrsi => CSRRSI, csrrci => CSRRCI }
+
+    pub fn jal(self, rd: REG, target: impl Into<Target>) -> Self {
+        let mut instr = DecodedInstr::new(MNEMONIC::JAL);
+        instr.rd = Some(rd);
+        self.push_target(instr, target.into())
+    }
+
+    /// FENCE with predecessor and successor sets as 4-bit IORW masks
+    pub fn fence(self, pred: u32, succ: u32) -> Self {
+        self.i(MNEMONIC::FENCE, REG::x0, REG::x0, (pred << 4 | succ) as i64)
+    }
+
+    pub fn fence_i(self) -> Self {
+        self.i(MNEMONIC::FENCE_I, REG::x0, REG::x0, 0)
+    }
+
+    pub fn ecall(self) -> Self {
+        self.push(DecodedInstr::new(MNEMONIC::ECALL))
+    }
+
+    pub fn ebreak(self) -> Self {
+        self.push(DecodedInstr::new(MNEMONIC::EBREAK))
+    }
+
+    pub fn mret(self) -> Self {
+        self.push(DecodedInstr::new(MNEMONIC::MRET))
+    }
+
+    pub fn sret(self) -> Self {
+        self.push(DecodedInstr::new(MNEMONIC::SRET))
+    }
+
+    pub fn wfi(self) -> Self {
+        self.push(DecodedInstr::new(MNEMONIC::WFI))
+    }
+
+    /// `sfence.vma rs1, rs2`; x0 selects every address or address space
+    pub fn sfence_vma(self, rs1: REG, rs2: REG) -> Self {
+        let mut instr = DecodedInstr::new(MNEMONIC::SFENCE_VMA);
+        instr.rs1 = Some(rs1);
+        instr.rs2 = Some(rs2);
+        self.push(instr)
+    }
+
+    /// `li`: load any 64-bit constant, in up to eight instructions
+    pub fn li(mut self, rd: REG, value: i64) -> Self {
+        for instr in asm::li(rd, value) {
+            self = self.push(instr);
+        }
+        self
+    }
+
+    /// `mv`: ADDI rd, rs, 0
+    pub fn mv(self, rd: REG, rs: REG) -> Self {
+        self.addi(rd, rs, 0)
+    }
+
+    /// `nop`: ADDI x0, x0, 0
+    pub fn nop(self) -> Self {
+        self.addi(REG::x0, REG::x0, 0)
+    }
+
+    /// `j`: JAL x0, target
+    pub fn j(self, target: impl Into<Target>) -> Self {
+        self.jal(REG::x0, target)
+    }
+
+    /// `ret`: JALR x0, 0(ra)
+    pub fn ret(self) -> Self {
+        self.jalr(REG::x0, 0, REG::x1)
+    }
+
+    /// Encoded instruction words, with labels resolved
+    pub fn words(&self) -> Result<Vec<u32>, ProgramError> {
+        if let Some(name) = &self.duplicate {
+            return Err(ProgramError::DuplicateLabel(name.clone()));
+        }
+        let mut words = Vec::with_capacity(self.instrs.len());
+        for (index, (instr, label)) in self.instrs.iter().enumerate() {
+            let mut instr = instr.clone();
+            if let Some(label) = label {
+                let target = *self
+                    .labels
+                    .get(label)
+                    .ok_or_else(|| ProgramError::UndefinedLabel(label.clone()))?;
+                instr.imm = Some(4 * (target as i64 - index as i64));
+            }
+            words.push(encode(&instr).map_err(|error| ProgramError::Encode { index, error })?);
+        }
+        Ok(words)
+    }
+
+    /// The program as little-endian machine code
+    pub fn bytes(&self) -> Result<Vec<u8>, ProgramError> {
+        Ok(self.words()?.iter().flat_map(|w| w.to_le_bytes()).collect())
+    }
+
+    /// Copy the program into `cpu`'s memory at its base address and point
+    /// the pc at it
+    pub fn load(&self, cpu: &mut CPU) -> Result<(), ProgramError> {
+        cpu.bus
+            .load(self.base, &self.bytes()?)
+            .map_err(ProgramError::Load)?;
+        cpu.set_pc(self.base);
+        Ok(())
+    }
+
+    /// A CPU with default memory and the program loaded
+    pub fn cpu(&self) -> Result<CPU, ProgramError> {
+        let mut cpu = CPU::new();
+        self.load(&mut cpu)?;
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::csr;
+    use crate::program::*;
+
+    #[test]
+    fn test_forward_and_backward_labels() {
+        let program = Program::new()
+            .addi(REG::x1, REG::x0, 10)
+            .label("loop")
+            .addi(REG::x2, REG::x2, 3)
+            .addi(REG::x1, REG::x1, -1)
+            .bne(REG::x1, REG::x0, "loop")
+            .jal(REG::x0, "end")
+            .addi(REG::x2, REG::x0, -1)
+            .label("end")
+            .ecall();
+        // same words as the hand-assembled loop in the cpu tests
+        assert_eq!(
+            program.words().unwrap()[..4],
+            [0x00a0_0093, 0x0031_0113, 0xfff0_8093, 0xfe00_9ce3]
+        );
+        assert_eq!(program.address("end"), Some(24));
+
+        let mut cpu = program.cpu().unwrap();
+        assert_eq!(cpu.run(None), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.registers[2], 30);
+        assert_eq!(cpu.csrs.read(csr::MEPC), Some(24));
+    }
+
+    #[test]
+    fn test_calls_memory_and_csrs() {
+        let mut cpu = Program::new()
+            .at(0x1000)
+            .li(REG::x10, 0x1234_5678_9abc_def0)
+            .li(REG::x11, 0x8000)
+            .jal(REG::x1, "store")
+            .ld(REG::x12, 0, REG::x11)
+            .lwu(REG::x13, 4, REG::x11)
+            .csrrs(REG::x14, csr::MISA, REG::x0)
+            .ecall()
+            .label("store")
+            .sd(REG::x10, 0, REG::x11)
+            .ret()
+            .cpu()
+            .unwrap();
+        assert_eq!(cpu.pc(), 0x1000);
+        assert_eq!(cpu.run(None), Err(Exception::EnvironmentCall));
+        assert_eq!(cpu.registers[12], 0x1234_5678_9abc_def0);
+        assert_eq!(cpu.registers[13], 0x1234_5678);
+        assert_eq!(cpu.registers[14] >> 62, 2);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Program::new().j("nowhere").words(),
+            Err(ProgramError::UndefinedLabel(String::from("nowhere")))
+        );
+        assert_eq!(
+            Program::new().label("a").nop().label("a").words(),
+            Err(ProgramError::DuplicateLabel(String::from("a")))
+        );
+        assert_eq!(
+            Program::new().nop().addi(REG::x1, REG::x0, 2048).words(),
+            Err(ProgramError::Encode {
+                index: 1,
+                error: EncodeError::ImmediateOutOfRange(2048)
+            })
+        );
+        assert_eq!(
+            Program::new().at(1 << 40).nop().cpu().err(),
+            Some(ProgramError::Load(BusFault::Unmapped(1 << 40)))
+        );
+    }
+}